@@ -1,27 +1,28 @@
-use std::mem::{replace, size_of, size_of_val};
+use std::mem::size_of_val;
 use std::collections::{HashMap, VecDeque};
-use std::collections::vec_deque::Iter as VecDequeIter;
 
-use num::{Float};
 use serialize::json::{Json, ToJson};
 
 use values::Value as TipValue;
 use super::deltabuf::{DeltaBuf, DeltaIter, Delta, Int};
+use super::xorbuf::{XorBuf, XorIter};
+use limits::{Limits, Dropped, Usage, key_source};
+use index::Index;
 use Key;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Inner<T, U: ValueBuf<T>> {
     tip: T,
     age: u64,
     buf: U,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Value {
     // value, age, delta-buffer
     Counter(Inner<u64, DeltaBuf<u64>>),
     Integer(Inner<i64, DeltaBuf<i64>>),
-    Float(Inner<f64, VecDeque<f64>>),
+    Float(Inner<f64, XorBuf>),
 }
 
 probor_enum_encoder_decoder!(Value {
@@ -31,21 +32,27 @@ probor_enum_encoder_decoder!(Value {
 });
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Backlog {
     // Made pub for serializer, may be fix it?
     pub age: u64,
     pub timestamps: VecDeque<(u64, u32)>,
     pub values: HashMap<Key, Value>,
+    /// Limits for new keys, not serialized
+    pub limits: Limits,
+    /// Values refused because of limits, not serialized
+    pub dropped: Dropped,
+    /// Approximate number of bytes used by keys and values
+    bytes: usize,
+    /// Number of keys by source, not serialized
+    usage: Usage,
+    /// Index of keys in `values` by fields, not serialized
+    ///
+    /// Key sets are shared between copies of the backlog, only the ones
+    /// touched by added or removed keys are copied
+    index: Index,
 }
 
-// Named fields are ok since we don't store lots of History objects
-probor_struct_encoder_decoder!(Backlog {
-    age => (),
-    timestamps => (),
-    values => (),
-});
-
 #[derive(Clone, PartialEq, Eq, Copy, Debug)]
 enum HState {
     Skip(u64),
@@ -61,10 +68,10 @@ pub struct DeltaHistory<'a, T:Int> {
 }
 
 #[derive(Clone)]
-pub struct FloatHistory<'a, T:Float+Copy+'static> {
+pub struct FloatHistory<'a> {
     state: HState,
-    iter: VecDequeIter<'a, T>,
-    tip: T,
+    iter: XorIter<'a>,
+    tip: f64,
 }
 
 impl Value {
@@ -93,7 +100,7 @@ impl Value {
             &T::Float(v) => V::Float(Inner {
                 tip: v,
                 age: age,
-                buf: VecDeque::new(),
+                buf: XorBuf::new(),
             }),
             &T::State(_) => unreachable!(),
         }
@@ -234,9 +241,9 @@ impl<'a, T:Int> Iterator for DeltaHistory<'a, T> {
     }
 }
 
-impl<'a, T:Float> Iterator for FloatHistory<'a, T> {
-    type Item = Option<T>;
-    fn next(&mut self) -> Option<Option<T>> {
+impl<'a> Iterator for FloatHistory<'a> {
+    type Item = Option<f64>;
+    fn next(&mut self) -> Option<Option<f64>> {
         use self::HState::*;
         let (res, nstate) = match self.state {
             Skip(1) => (Some(None), Tip),
@@ -244,7 +251,9 @@ impl<'a, T:Float> Iterator for FloatHistory<'a, T> {
             Tip => (Some(Some(self.tip)), Next),
             Next => {
                 let val = self.iter.next()
-                    .map(|x| if x.is_nan() { None } else { Some(*x) });
+                    .map(|x| x.and_then(|x| {
+                        if x.is_nan() { None } else { Some(x) }
+                    }));
                 (val, Next)
             }
         };
@@ -278,35 +287,28 @@ impl <T:Int> Inner<T, DeltaBuf<T>> {
 }
 
 
-impl<T: Float> Inner<T, VecDeque<T>> {
-    pub fn history<'x>(&'x self, current_age: u64) -> FloatHistory<'x, T> {
+impl Inner<f64, XorBuf> {
+    pub fn history<'x>(&'x self, current_age: u64) -> FloatHistory<'x> {
         use self::HState::*;
         let age_diff = current_age.saturating_sub(self.age());
         return FloatHistory {
             state: if age_diff > 0 { Skip(age_diff) } else { Tip },
-            iter: self.buf.iter(),
+            iter: self.buf.values(self.tip),
             tip: self.tip,
         }
     }
 }
 
-impl<T:Float+Sized> ValueBuf<T> for VecDeque<T> {
-    fn push(&mut self, old: T, _new: T, age_diff: u64) {
-        self.push_front(old);
-        if age_diff > 1 {
-            for _ in 1..age_diff {
-                self.push_front(T::nan());
-            }
-        }
+impl ValueBuf<f64> for XorBuf {
+    fn push(&mut self, old: f64, new: f64, age_diff: u64) {
+        XorBuf::push(self, old, new, age_diff)
     }
     fn truncate(&mut self, limit: usize) {
-        // TODO(tailhook) use truncate
-        while self.len() > limit-1 {  // account self.tip as a value too
-            self.pop_back();
-        }
+        // account self.tip as a value too
+        XorBuf::truncate(self, limit.saturating_sub(1));
     }
     fn size(&self) -> usize {
-        self.len()*size_of::<T>()
+        self.byte_size()
     }
 }
 
@@ -316,8 +318,46 @@ impl Backlog {
             age: 0,
             timestamps: VecDeque::new(),
             values: HashMap::new(),
+            limits: Limits::default(),
+            dropped: Dropped::default(),
+            bytes: 0,
+            usage: Usage::default(),
+            index: Index::new(),
         }
     }
+    /// Creates backlog from decoded (or migrated) parts, limits are unset
+    pub fn unpack(age: u64, timestamps: VecDeque<(u64, u32)>,
+        values: HashMap<Key, Value>)
+        -> Backlog
+    {
+        let mut backlog = Backlog {
+            age: age,
+            timestamps: timestamps,
+            index: Index::from_keys(values.keys()),
+            values: values,
+            limits: Limits::default(),
+            dropped: Dropped::default(),
+            bytes: 0,
+            usage: Usage::default(),
+        };
+        backlog.recount();
+        return backlog;
+    }
+    /// Approximate number of bytes used by keys and values
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+    /// Index of keys by fields, it's up to date with `values` as long
+    /// as they are modified only by `push` and `truncate_*`
+    pub fn index(&self) -> &Index {
+        &self.index
+    }
+    fn recount(&mut self) {
+        self.bytes = self.values.iter()
+            .map(|(k, v)| k.size() + v.size())
+            .sum();
+        self.usage = Usage::from_keys(self.values.keys());
+    }
     pub fn info(&self) -> Json {
         let mut key_bytes = 0;
         let mut value_bytes = 0;
@@ -331,6 +371,7 @@ impl Backlog {
             ("values".to_string(), self.values.len().to_json()),
             ("key_bytes".to_string(), key_bytes.to_json()),
             ("value_bytes".to_string(), value_bytes.to_json()),
+            ("dropped".to_string(), self.dropped.total.to_json()),
             ].into_iter().collect());
     }
     pub fn push<'x, I>(&mut self, timestamp: (u64, u32), iter: I)
@@ -343,11 +384,39 @@ impl Backlog {
         let age = self.age;
         for (k, v) in iter {
             // fast path should be get_mut
-            if !self.values.get_mut(k).map(|x| x.push(v, age))
-                .unwrap_or(false)
-            {
+            let pushed = match self.values.get_mut(k) {
+                Some(val) => {
+                    let old_size = val.size();
+                    if val.push(v, age) {
+                        self.bytes = (self.bytes + val.size())
+                            .saturating_sub(old_size);
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => false,
+            };
+            if !pushed {
+                if !self.values.contains_key(k) {
+                    let source = key_source(k);
+                    if !self.limits.allow_new_key(self.values.len(),
+                        self.bytes, &self.usage, &source)
+                    {
+                        self.dropped.add(source);
+                        continue;
+                    }
+                    self.usage.insert(source);
+                }
                 // Only if no key or conflicting type clone the key
-                self.values.insert(k.clone(), Value::new(v, age));
+                let value = Value::new(v, age);
+                self.bytes += k.size() + value.size();
+                if let Some(old) = self.values.insert(k.clone(), value) {
+                    self.bytes = self.bytes
+                        .saturating_sub(k.size() + old.size());
+                } else {
+                    self.index.insert(k);
+                }
             }
         }
     }
@@ -360,29 +429,32 @@ impl Backlog {
     }
     pub fn truncate_by_num(&mut self, idx: usize) {
         let target_age = self.age.saturating_sub(idx as u64);
-        self.values = replace(&mut self.values, HashMap::new()).into_iter()
-            .filter_map(|(key, mut val)| {
+        {
+            let index = &mut self.index;
+            self.values.retain(|key, val| {
                 if val.truncate(target_age) {
-                    return Some((key, val));
+                    return true;
                 } else {
-                    return None;
+                    index.remove(key);
+                    return false;
                 }
-            }).collect();
+            });
+        }
         while self.timestamps.len() > idx {
             self.timestamps.pop_back();
         }
+        self.recount();
     }
 }
 
 mod serde {
-    use std::io::Cursor;
-    use std::collections::VecDeque;
     use probor::{Decodable, Decoder, DecodeError, Input};
     use probor::{Encodable, Encoder, EncodeError, Output};
-    use byteorder::{BigEndian, ReadBytesExt};
+    use byteorder::BigEndian;
     use cbor::types::Type;
-    use super::Inner;
+    use super::{Inner, Backlog};
     use super::super::deltabuf::{DeltaBuf, Int};
+    use super::super::xorbuf::XorBuf;
 
     fn type_len<W:Output>(w: &mut W, t: Type, x: u64) {
         match x {
@@ -420,6 +492,33 @@ mod serde {
         }
     }
 
+    // Named fields are ok since we don't store lots of History objects
+    impl Decodable for Backlog {
+        fn decode_opt<R:Input>(d: &mut Decoder<R>)
+            -> Result<Option<Self>, DecodeError>
+        {
+            probor_dec_struct!(d, {
+                age => (),
+                timestamps => (),
+                values => (),
+            });
+            Ok(Some(Backlog::unpack(age, timestamps, values)))
+        }
+    }
+
+    impl Encodable for Backlog {
+        fn encode<W:Output>(&self, e: &mut Encoder<W>)
+            -> Result<(), EncodeError>
+        {
+            probor_enc_struct!(e, self, {
+                age => (),
+                timestamps => (),
+                values => (),
+            });
+            Ok(())
+        }
+    }
+
     impl<T:Decodable+Int> Decodable for Inner<T, DeltaBuf<T>> {
         fn decode_opt<R:Input>(d: &mut Decoder<R>)
             -> Result<Option<Self>, DecodeError>
@@ -441,7 +540,7 @@ mod serde {
             try!(e.array(3));  // {tip, age, buf}
             try!(self.tip().encode(e));  // #0
             try!(self.age().encode(e));  // #1
-            write_bytes(e, self.buf().bytes().len(), |buf| {  // #2
+            write_bytes(e, self.buf().byte_size(), |buf| {  // #2
                 // I hope this crap will be optimized
                 for &i in self.buf().bytes() {
                     buf.write_all(&[i]).unwrap()
@@ -452,7 +551,7 @@ mod serde {
     }
 
 
-    impl Decodable for Inner<f64, VecDeque<f64>> {
+    impl Decodable for Inner<f64, XorBuf> {
         fn decode_opt<R:Input>(d: &mut Decoder<R>)
             -> Result<Option<Self>, DecodeError>
         {
@@ -460,35 +559,31 @@ mod serde {
                 tip => (#0),
                 age => (#1),
                 buf => (#2),
+                free_bits => (#3),
+                len => (#4),
             });
-            let mut deque = VecDeque::new();
             let Bytes(buf) = buf;
-            if buf.len() % 8 > 0 {
-                return Err(DecodeError::WrongValue("length of f64 buffer \
-                    should be multiple of 8"));
-            }
-            let num = buf.len() / 8;
-            let mut cur = Cursor::new(buf);
-            for _ in 0..num {
-                deque.push_back(
-                    cur.read_f64::<BigEndian>().unwrap());
-            }
-            Ok(Some(Inner::unpack(tip, age, deque)))
+            let len: u64 = len;
+            let buf = try!(XorBuf::from_parts(buf, free_bits, len as usize)
+                .map_err(DecodeError::WrongValue));
+            Ok(Some(Inner::unpack(tip, age, buf)))
         }
     }
 
-    impl Encodable for Inner<f64, VecDeque<f64>> {
+    impl Encodable for Inner<f64, XorBuf> {
         fn encode<W:Output>(&self, e: &mut Encoder<W>)
             -> Result<(), EncodeError>
         {
-            try!(e.array(3));  // {tip, age, buf}
+            try!(e.array(5));  // {tip, age, buf, free_bits, len}
             try!(self.tip().encode(e));  // #0
             try!(self.age().encode(e));  // #1
-            write_bytes(e, self.buf().len()*8, |buf| {  // #2
-                for val in self.buf().iter() {
-                    buf.write_f64::<BigEndian>(*val).unwrap();
+            write_bytes(e, self.buf().byte_size(), |buf| {  // #2
+                for &i in self.buf().bytes() {
+                    buf.write_all(&[i]).unwrap()
                 }
             });
+            try!(self.buf().free_bits().encode(e));  // #3
+            try!((self.buf().len() as u64).encode(e));  // #4
             Ok(())
         }
     }
@@ -500,7 +595,7 @@ mod test {
     use std::io::Cursor;
     use {Backlog, Key};
     use super::{Value, Inner};
-    use values::Value::Counter;
+    use values::Value::{Counter, Float};
     use std::collections::{HashMap, HashSet};
     use probor::{Encodable, Decodable, Encoder, Decoder, Config, decode};
 
@@ -520,6 +615,31 @@ mod test {
         assert_eq!(backlog.values.len(), 3);
     }
 
+    #[test]
+    fn test_index_shared() {
+        let mut backlog = Backlog::new();
+        backlog.push((1000, 10), vec![
+            (&Key::metric("test1"), &Counter(10)),
+            (&Key::metric("test2"), &Counter(20)),
+        ].into_iter());
+        let mut copy = backlog.clone();
+        copy.push((2000, 10), vec![
+            (&Key::metric("test1"), &Counter(30)),
+            (&Key::metric("test2"), &Counter(40)),
+        ].into_iter());
+        copy.truncate_by_num(2);
+        // same keys, so index is not copied
+        assert!(copy.index.is_shared_with(&backlog.index));
+        copy.push((3000, 10), vec![
+            (&Key::metric("test1"), &Counter(50)),
+        ].into_iter());
+        copy.truncate_by_num(1);
+        assert!(!copy.index.is_shared_with(&backlog.index));
+        assert_eq!(copy.index().get("metric", "test2"), None);
+        assert_eq!(backlog.index().get("metric", "test2").map(|x| x.len()),
+                   Some(1));
+    }
+
     #[test]
     fn test_truncate() {
         use Value::Counter as Cnt;
@@ -540,6 +660,9 @@ mod test {
         assert_eq!(backlog.age, 3);
         assert_eq!(backlog.values.len(), 2);
         assert_eq!(backlog.timestamps.len(), 2);
+        assert_eq!(backlog.index().get("metric", "test1"), None);
+        assert_eq!(backlog.index().values("metric").map(|x| x.len()),
+                   Some(2));
         for (key, val) in backlog.values {
             if let Cnt(log) = val {
                 if key.get_with("metric", |val| val == "test2")
@@ -557,6 +680,55 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_limits() {
+        let mut backlog = Backlog::new();
+        backlog.limits.max_keys = 2;
+        backlog.push((1000, 10), vec![
+            (&Key::metric("test1"), &Counter(10)),
+            (&Key::metric("test2"), &Counter(20)),
+            (&Key::metric("test3"), &Counter(30)),
+        ].into_iter());
+        backlog.push((2000, 10), vec![
+            (&Key::metric("test1"), &Counter(20)),
+            (&Key::metric("test3"), &Counter(40)),
+        ].into_iter());
+        assert_eq!(backlog.values.len(), 2);
+        assert!(!backlog.values.contains_key(&Key::metric("test3")));
+        assert_eq!(backlog.dropped.total, 2);
+        assert_eq!(backlog.dropped.offenders(), vec![("unknown", 2)]);
+        assert!(backlog.bytes() > 0);
+    }
+
+    #[test]
+    fn test_limits_share() {
+        let mut backlog = Backlog::new();
+        backlog.limits.max_keys = 2;
+        backlog.push((1000, 10), vec![
+            (&Key::pairs(&[("metric", "a"), ("pid", "1")]), &Counter(10)),
+            (&Key::pairs(&[("metric", "b"), ("pid", "1")]), &Counter(10)),
+        ].into_iter());
+        backlog.push((2000, 10), vec![
+            (&Key::pairs(&[("metric", "c"), ("pid", "1")]), &Counter(10)),
+            (&Key::pairs(&[("metric", "a"), ("pid", "2")]), &Counter(10)),
+        ].into_iter());
+        assert_eq!(backlog.values.len(), 3);
+        assert_eq!(backlog.dropped.offenders(), vec![("pid=1", 1)]);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut backlog = Backlog::new();
+        for i in 0..100 {
+            backlog.push((1000*(i+1), 10), vec![
+                (&Key::metric("test1"), &Counter(i*1000000)),
+            ].into_iter());
+        }
+        let bytes = backlog.bytes();
+        backlog.recount();
+        assert_eq!(bytes, backlog.bytes());
+    }
+
     #[test]
     fn test_truncate_counter() {
         use Value as V;
@@ -617,6 +789,20 @@ mod test {
         decode(&mut Decoder::new(Config::default(), Cursor::new(val))).unwrap()
     }
 
+    #[test]
+    fn test_serde_float() {
+        let mut value = Value::new(&Float(0.5), 1);
+        value.push(&Float(0.75), 2);
+        value.push(&Float(0.75), 5);
+        let nval: Value = roundtrip(&value);
+        if let Value::Float(inner) = nval {
+            assert_eq!(inner.history(5).collect::<Vec<_>>(),
+                vec![Some(0.75), None, None, Some(0.75), Some(0.5)]);
+        } else {
+            panic!("not a float");
+        }
+    }
+
     #[test]
     fn test_serde() {
         let mut value = Value::Counter(Inner::unpack(10, 1, vec![]));
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+
+use num::ToPrimitive;
+
+use values::Value as TipValue;
+use {Backlog, Value, Key};
+
+
+/// Interval of the coarse history (milliseconds)
+pub const COARSE_INTERVAL: u64 = 60_000;
+/// Maximum number of coarse keys made of a single fine key
+pub const ROLLUP_KEYS: usize = 4;
+
+struct Summary<T> {
+    min: T,
+    max: T,
+    last: T,
+    sum: f64,
+    count: u32,
+}
+
+impl<T: Copy + PartialOrd + ToPrimitive> Summary<T> {
+    fn new(value: T) -> Summary<T> {
+        Summary {
+            min: value,
+            max: value,
+            last: value,
+            sum: value.to_f64().unwrap_or(0.),
+            count: 1,
+        }
+    }
+    /// Note: values are visited from newest to oldest, so `last` is the
+    /// value summary was created with
+    fn add(&mut self, value: T) {
+        if value < self.min {
+            self.min = value;
+        }
+        if value > self.max {
+            self.max = value;
+        }
+        self.sum += value.to_f64().unwrap_or(0.);
+        self.count += 1;
+    }
+    fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+fn summarize<T, I>(fine: &Backlog, start: usize, iter: I)
+    -> BTreeMap<u64, Summary<T>>
+    where T: Copy + PartialOrd + ToPrimitive,
+          I: Iterator<Item=Option<T>>,
+{
+    let mut result = BTreeMap::new();
+    for (&(ts, _), value) in fine.timestamps.iter().zip(iter).skip(start) {
+        if let Some(value) = value {
+            match result.entry(ts / COARSE_INTERVAL) {
+                Entry::Occupied(mut e) => e.get_mut().add(value),
+                Entry::Vacant(e) => {
+                    e.insert(Summary::new(value));
+                }
+            }
+        }
+    }
+    return result;
+}
+
+fn rollup_keys(key: &Key) -> [Key; 4] {
+    [
+        key.add_pair("rollup", "min"),
+        key.add_pair("rollup", "max"),
+        key.add_pair("rollup", "avg"),
+        key.add_pair("rollup", "last"),
+    ]
+}
+
+/// Rolls up values older than `before` into the coarse backlog
+///
+/// Counters are stored as the last value in the interval, integers and
+/// floats are stored as four series: min, max, avg and last, which are
+/// distinguished by the `rollup` field of the key.
+///
+/// Only intervals newer than any interval in the coarse backlog are added.
+pub fn rollup(fine: &Backlog, before: u64, coarse: &mut Backlog) {
+    use values::Value as T;
+    let start = match fine.timestamps.iter()
+        .position(|&(ts, _)| ts < before)
+    {
+        Some(idx) => idx,
+        None => return,
+    };
+    let mut intervals = BTreeMap::new();
+    for (key, value) in fine.values.iter() {
+        match *value {
+            Value::Counter(ref x) => {
+                let last = key.add_pair("rollup", "last");
+                let summary = summarize(fine, start, x.history(fine.age));
+                for (bucket, s) in summary {
+                    intervals.entry(bucket).or_insert_with(Vec::new)
+                        .push((last.clone(), T::Counter(s.last)));
+                }
+            }
+            Value::Integer(ref x) => {
+                let keys = rollup_keys(key);
+                let summary = summarize(fine, start, x.history(fine.age));
+                for (bucket, s) in summary {
+                    let items = intervals.entry(bucket)
+                        .or_insert_with(Vec::new);
+                    items.push((keys[0].clone(), T::Integer(s.min)));
+                    items.push((keys[1].clone(), T::Integer(s.max)));
+                    items.push((keys[2].clone(), T::Float(s.avg())));
+                    items.push((keys[3].clone(), T::Integer(s.last)));
+                }
+            }
+            Value::Float(ref x) => {
+                let keys = rollup_keys(key);
+                let summary = summarize(fine, start, x.history(fine.age));
+                for (bucket, s) in summary {
+                    let items = intervals.entry(bucket)
+                        .or_insert_with(Vec::new);
+                    items.push((keys[0].clone(), T::Float(s.min)));
+                    items.push((keys[1].clone(), T::Float(s.max)));
+                    items.push((keys[2].clone(), T::Float(s.avg())));
+                    items.push((keys[3].clone(), T::Float(s.last)));
+                }
+            }
+        }
+    }
+    let newest = coarse.timestamps.front().map(|&(ts, _)| ts);
+    for (bucket, items) in intervals {
+        let ts = bucket * COARSE_INTERVAL;
+        if newest.map(|x| ts <= x).unwrap_or(false) {
+            continue;
+        }
+        coarse.push((ts, COARSE_INTERVAL as u32),
+            items.iter().map(|&(ref k, ref v): &(Key, TipValue)| (k, v)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {Backlog, Key};
+    use values::Value::{Counter, Integer};
+    use super::rollup;
+
+    fn tip(coarse: &Backlog, name: &str, rollup: &str) -> String {
+        format!("{:?}", coarse.values[&Key::metric(name)
+            .add_pair("rollup", rollup)].tip_value())
+    }
+
+    #[test]
+    fn test_rollup() {
+        let mut fine = Backlog::new();
+        for (idx, &ts) in [60000, 80000, 100000, 120000, 140000].iter()
+            .enumerate()
+        {
+            fine.push((ts, 10), vec![
+                (&Key::metric("cnt"), &Counter(idx as u64 * 10)),
+                (&Key::metric("int"), &Integer(idx as i64)),
+            ].into_iter());
+        }
+        let mut coarse = Backlog::new();
+        // only the first minute is complete
+        rollup(&fine, 120000, &mut coarse);
+        assert_eq!(coarse.timestamps.len(), 1);
+        assert_eq!(coarse.timestamps[0], (60000, 60000));
+        assert_eq!(coarse.values.len(), 5);
+        assert_eq!(tip(&coarse, "cnt", "last"), "Counter(20)");
+        assert_eq!(tip(&coarse, "int", "min"), "Integer(0)");
+        assert_eq!(tip(&coarse, "int", "max"), "Integer(2)");
+        assert_eq!(tip(&coarse, "int", "avg"), "Float(1.0)");
+        assert_eq!(tip(&coarse, "int", "last"), "Integer(2)");
+
+        // already rolled up intervals are skipped
+        rollup(&fine, 180000, &mut coarse);
+        assert_eq!(coarse.timestamps.len(), 2);
+        assert_eq!(coarse.timestamps[0], (120000, 60000));
+        assert_eq!(tip(&coarse, "cnt", "last"), "Counter(40)");
+    }
+}
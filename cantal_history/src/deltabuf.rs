@@ -1,11 +1,11 @@
 use std::cmp::min;
 use std::ops::{Shl, Shr, BitOr, BitAnd};
 use std::marker::PhantomData;
-use std::collections::VecDeque;
-use std::collections::vec_deque::Iter as DequeIter;
 
 use num::{Integer, FromPrimitive, ToPrimitive};
 
+use sharedbuf::{SharedBuf, Iter as BufIter};
+
 
 const SIGN_BIT: u8     = 0b00100000;
 const SPECIAL_BIT: u8  = 0b01000000;  // WARNING! check only with CONTINUATION
@@ -27,7 +27,7 @@ pub trait Int: Integer + FromPrimitive + ToPrimitive + Copy +
     BitOr<Self, Output=Self> + BitAnd<Self, Output=Self> {}
 
 #[derive(Debug, Clone)]
-pub struct DeltaBuf<T:Int>(VecDeque<u8>, PhantomData<T>);
+pub struct DeltaBuf<T:Int>(SharedBuf, PhantomData<T>);
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Delta<T:Int> {
@@ -47,7 +47,7 @@ enum DequeItem<T:Int> {
 
 #[derive(Clone)]
 pub struct DeltaIter<'a, T:Int> {
-    iter: DequeIter<'a, u8>,
+    iter: BufIter<'a>,
     queue: DequeItem<T>,
 }
 
@@ -135,7 +135,7 @@ impl<'a, T:Int> Iterator for DeltaIter<'a, T> {
 
 impl<T:Int> DeltaBuf<T> {
     pub fn new() -> DeltaBuf<T> {
-        return DeltaBuf(VecDeque::new(), PhantomData);
+        return DeltaBuf(SharedBuf::new(), PhantomData);
     }
     pub fn push(&mut self, old_value: T, new_value: T, mut age_diff: u64)
     {
@@ -198,11 +198,7 @@ impl<T:Int> DeltaBuf<T> {
                     deque[limit_bytes-1] = (b & SPECIAL_BITS as u8) |
                         ((b & SPECIAL_MASK as u8) - truncate_num as u8);
                 }
-                // TODO(tailhook) use truncate
-                while deque.len() > limit_bytes {
-                    deque.pop_back();
-                }
-                // deque.truncate(limit_bytes);
+                deque.truncate(limit_bytes);
                 limit
             }
             Err(num_current) => num_current,
@@ -234,7 +230,7 @@ impl<T:Int> DeltaBuf<T> {
         }
         return Err(counter);
     }
-    pub fn bytes<'x>(&'x self) -> DequeIter<'x, u8> {
+    pub fn bytes<'x>(&'x self) -> BufIter<'x> {
         self.0.iter()
     }
     pub fn byte_size(&self) -> usize {
@@ -244,7 +240,7 @@ impl<T:Int> DeltaBuf<T> {
 
 impl<T:Int> From<Vec<u8>> for DeltaBuf<T> {
     fn from(vec: Vec<u8>) -> DeltaBuf<T> {
-        DeltaBuf(vec.into_iter().collect(), PhantomData)
+        DeltaBuf(vec.into(), PhantomData)
     }
 }
 
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::sync::Arc;
+
+use Key;
+
+
+/// Inverted index: field name -> field value -> keys having the pair
+///
+/// Index is not serialized, it's rebuilt when backlog is loaded.
+///
+/// Values of a field and keys of a value are shared between copies of
+/// the index, so cloning it is cheap and updating it copies only the
+/// sets touched by the key inserted or removed.
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    fields: HashMap<String, Arc<BTreeMap<String, Arc<HashSet<Key>>>>>,
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index::default()
+    }
+    /// Builds an index for all the keys
+    pub fn from_keys<'x, I>(keys: I) -> Index
+        where I: Iterator<Item=&'x Key>
+    {
+        let mut index = Index::new();
+        for key in keys {
+            index.insert(key);
+        }
+        return index;
+    }
+    pub fn insert(&mut self, key: &Key) {
+        let fields = &mut self.fields;
+        key.with_pairs(|pairs| {
+            for &(name, value) in pairs {
+                if !fields.contains_key(name) {
+                    fields.insert(name.to_string(), Arc::new(BTreeMap::new()));
+                }
+                let values = Arc::make_mut(
+                    fields.get_mut(name).expect("field is inserted"));
+                if !values.contains_key(value) {
+                    values.insert(value.to_string(), Arc::new(HashSet::new()));
+                }
+                let keys = values.get_mut(value).expect("value is inserted");
+                Arc::make_mut(keys).insert(key.clone());
+            }
+        })
+    }
+    pub fn remove(&mut self, key: &Key) {
+        let fields = &mut self.fields;
+        key.with_pairs(|pairs| {
+            for &(name, value) in pairs {
+                // check first, so that nothing is copied if there is no key
+                let (last_value, last_key) = match fields.get(name) {
+                    Some(values) => match values.get(value) {
+                        Some(keys) if keys.contains(key) => {
+                            (values.len() == 1, keys.len() == 1)
+                        }
+                        _ => continue,
+                    },
+                    None => continue,
+                };
+                if last_value && last_key {
+                    fields.remove(name);
+                    continue;
+                }
+                let values = Arc::make_mut(
+                    fields.get_mut(name).expect("field exists"));
+                if last_key {
+                    values.remove(value);
+                } else {
+                    Arc::make_mut(values.get_mut(value).expect("value exists"))
+                        .remove(key);
+                }
+            }
+        })
+    }
+    /// Returns keys having `name=value` pair
+    pub fn get<'x>(&'x self, name: &str, value: &str)
+        -> Option<&'x HashSet<Key>>
+    {
+        self.fields.get(name).and_then(|values| values.get(value))
+            .map(|keys| &**keys)
+    }
+    /// Returns keys having field `name`, grouped by the value of the field
+    pub fn values<'x>(&'x self, name: &str)
+        -> Option<&'x BTreeMap<String, Arc<HashSet<Key>>>>
+    {
+        self.fields.get(name).map(|values| &**values)
+    }
+    /// Returns keys having field `name` with values starting with `prefix`
+    pub fn prefixed<'x>(&'x self, name: &str, prefix: &str)
+        -> Vec<&'x HashSet<Key>>
+    {
+        match self.fields.get(name) {
+            Some(values) => values.range(prefix.to_string()..)
+                .take_while(|&(value, _)| value.starts_with(prefix))
+                .map(|(_, keys)| &**keys)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Number of distinct fields in index
+    pub fn fields(&self) -> usize {
+        self.fields.len()
+    }
+    /// Returns true if all key sets are shared with the `other` index
+    #[cfg(test)]
+    pub(crate) fn is_shared_with(&self, other: &Index) -> bool {
+        self.fields.len() == other.fields.len() &&
+        self.fields.iter().all(|(name, values)| {
+            other.fields.get(name)
+                .map(|other| Arc::ptr_eq(values, other))
+                .unwrap_or(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Key;
+    use super::Index;
+
+    fn keys() -> Vec<Key> {
+        vec![
+            Key::pairs(&[("cgroup", "lithos.a"), ("metric", "rss")]),
+            Key::pairs(&[("cgroup", "lithos.b"), ("metric", "rss")]),
+            Key::pairs(&[("cgroup", "system"), ("metric", "rss")]),
+            Key::metric("cpu"),
+        ]
+    }
+
+    #[test]
+    fn test_get() {
+        let keys = keys();
+        let index = Index::from_keys(keys.iter());
+        assert_eq!(index.get("metric", "rss").map(|x| x.len()), Some(3));
+        assert_eq!(index.get("metric", "mem"), None);
+        assert_eq!(index.values("cgroup").map(|x| x.len()), Some(3));
+        assert_eq!(index.prefixed("cgroup", "lithos.").len(), 2);
+        assert_eq!(index.prefixed("cgroup", "zzz").len(), 0);
+        assert_eq!(index.fields(), 2);
+    }
+
+    #[test]
+    fn test_remove() {
+        let keys = keys();
+        let mut index = Index::from_keys(keys.iter());
+        index.remove(&keys[0]);
+        index.remove(&keys[1]);
+        index.remove(&keys[2]);
+        assert_eq!(index.get("metric", "rss"), None);
+        assert_eq!(index.values("cgroup"), None);
+        assert!(index.get("metric", "cpu").unwrap().contains(&keys[3]));
+        assert_eq!(index.fields(), 1);
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let keys = keys();
+        let index = Index::from_keys(keys.iter());
+        let mut copy = index.clone();
+        assert!(copy.is_shared_with(&index));
+        let new = Key::pairs(&[("cgroup", "lithos.c"), ("metric", "rss")]);
+        copy.insert(&new);
+        copy.remove(&keys[3]);
+        assert!(!copy.is_shared_with(&index));
+        assert_eq!(copy.get("metric", "rss").map(|x| x.len()), Some(4));
+        assert_eq!(copy.get("metric", "cpu"), None);
+        // the original is unchanged
+        assert_eq!(index.get("metric", "rss").map(|x| x.len()), Some(3));
+        assert_eq!(index.get("metric", "cpu").map(|x| x.len()), Some(1));
+        assert_eq!(index.get("cgroup", "lithos.c"), None);
+        // key sets which weren't touched are not copied
+        let a = index.get("cgroup", "lithos.a").unwrap();
+        let b = copy.get("cgroup", "lithos.a").unwrap();
+        assert!(a as *const _ == b as *const _);
+    }
+}
@@ -3,12 +3,24 @@ use std::io::Cursor;
 use std::collections::BTreeMap;
 use std::marker::PhantomData;
 use std::iter::Peekable;
+use std::str::from_utf8;
 
+use byteorder::{BigEndian, ByteOrder};
 use cbor::{Encoder, Decoder, Config};
 use serialize::json::Json;
 
 use Key;
 
+const CBOR_TEXT: u8 = 3;
+const CBOR_OBJECT: u8 = 5;
+
+/// Iterator over pairs of the key, strings are borrowed from the key
+pub struct Pairs<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    left: usize,
+}
+
 struct Merge<'a, A, B>(usize, Peekable<A>, Peekable<B>, PhantomData<&'a A>)
     where A: Iterator<Item=(&'a str, &'a str)> + 'a,
           B: Iterator<Item=(&'a str, &'a str)> + 'a;
@@ -33,6 +45,53 @@ impl<'a, A, B> Iterator for Merge<'a, A, B>
     }
 }
 
+/// Reads CBOR header of the `major` type at `pos`
+///
+/// Returns the length from the header and position of the data after it
+fn read_header(buf: &[u8], pos: usize, major: u8) -> Option<(usize, usize)> {
+    let first = *buf.get(pos)?;
+    if first >> 5 != major {
+        return None;
+    }
+    let (len, pos) = match first & 0x1f {
+        x @ 0...23 => (x as u64, pos + 1),
+        24 => (*buf.get(pos + 1)? as u64, pos + 2),
+        25 => (BigEndian::read_u16(buf.get(pos+1..pos+3)?) as u64, pos + 3),
+        26 => (BigEndian::read_u32(buf.get(pos+1..pos+5)?) as u64, pos + 5),
+        27 => (BigEndian::read_u64(buf.get(pos+1..pos+9)?), pos + 9),
+        _ => return None,
+    };
+    Some((len as usize, pos))
+}
+
+impl<'a> Pairs<'a> {
+    fn text(&mut self) -> Option<&'a str> {
+        let (len, start) = read_header(self.buf, self.pos, CBOR_TEXT)?;
+        let data = self.buf.get(start..start+len)?;
+        self.pos = start + len;
+        from_utf8(data).ok()
+    }
+}
+
+impl<'a> Iterator for Pairs<'a> {
+    type Item = (&'a str, &'a str);
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        // TODO(tailhook) other types may work in future
+        match (self.text(), self.text()) {
+            (Some(k), Some(v)) => Some((k, v)),
+            _ => {
+                error!("Invalid key data");
+                self.left = 0;
+                None
+            }
+        }
+    }
+}
+
 impl<'a, A, B> ExactSizeIterator for Merge<'a, A, B>
     where A: Iterator<Item=(&'a str, &'a str)>,
           B: Iterator<Item=(&'a str, &'a str)>
@@ -137,6 +196,82 @@ impl Key {
         })
     }
 
+    /// Iterates over pairs of the key without allocating
+    pub fn iter_pairs<'x>(&'x self) -> Pairs<'x> {
+        let buf = self.as_bytes();
+        match read_header(buf, 0, CBOR_OBJECT) {
+            Some((num, pos)) => Pairs { buf: buf, pos: pos, left: num },
+            None => Pairs { buf: buf, pos: 0, left: 0 },
+        }
+    }
+
+    /// Decodes all the pairs at once and passes them to the function
+    ///
+    /// This is faster than calling `get_with` for multiple fields
+    pub fn with_pairs<F, T>(&self, f: F) -> T
+        where F: FnOnce(&[(&str, &str)]) -> T
+    {
+        let pairs = self.iter_pairs().collect::<Vec<_>>();
+        f(&pairs[..])
+    }
+
+    /// Converts key to a json object, for dumping data out of cantal
+    pub fn to_json(&self) -> Json {
+        let mut obj = BTreeMap::new();
+        if let Some(ref b) = self.0 {
+            let mut d = Decoder::new(Config::default(), Cursor::new(&b[..]));
+            let num = d.object().unwrap();
+            for _ in 0..num {
+                let name = d.text_borrow().unwrap().to_string();
+                // TODO(tailhook) other types may work in future
+                let value = d.text_borrow().unwrap().to_string();
+                obj.insert(name, Json::String(value));
+            }
+        }
+        Json::Object(obj)
+    }
+
+    /// Returns a copy of the key with a pair added
+    ///
+    /// If there is a pair with the same name already, value is replaced
+    pub fn add_pair(&self, name: &str, value: &str) -> Key {
+        let mut pairs = Vec::new();
+        if let Some(ref b) = self.0 {
+            let mut d = Decoder::new(Config::default(), Cursor::new(&b[..]));
+            let num = d.object().unwrap();
+            for _ in 0..num {
+                let k = d.text_borrow().unwrap().to_string();
+                // TODO(tailhook) other types may work in future
+                let v = d.text_borrow().unwrap().to_string();
+                pairs.push((k, v));
+            }
+        }
+        let num = pairs.len() + 1 -
+            pairs.iter().filter(|&&(ref k, _)| k == name).count();
+        Key::from_iter(Merge(num,
+            pairs.iter().map(|&(ref k, ref v)| (&k[..], &v[..])).peekable(),
+            [(name, value)].iter().cloned().peekable(),
+            PhantomData))
+    }
+
+    /// Returns a copy of the key without the pair named `name`
+    pub fn remove_pair(&self, name: &str) -> Key {
+        let mut pairs = Vec::new();
+        if let Some(ref b) = self.0 {
+            let mut d = Decoder::new(Config::default(), Cursor::new(&b[..]));
+            let num = d.object().unwrap();
+            for _ in 0..num {
+                let k = d.text_borrow().unwrap().to_string();
+                // TODO(tailhook) other types may work in future
+                let v = d.text_borrow().unwrap().to_string();
+                if k != name {
+                    pairs.push((k, v));
+                }
+            }
+        }
+        Key::from_iter(pairs.iter().map(|&(ref k, ref v)| (&k[..], &v[..])))
+    }
+
     pub fn empty() -> Key {
         Key(None)
     }
@@ -235,4 +370,42 @@ mod test {
         assert_eq!(&key.0.unwrap()[..],
             &b"\xa3fmetricdtestcpidd1234czooebasic"[..]);
     }
+
+    #[test]
+    fn add_pair() {
+        let key = Key::pairs(&[("metric", "test"), ("zoo", "basic")]);
+        assert_eq!(key.add_pair("pid", "1234"),
+            Key::pairs(&[("metric", "test"), ("pid", "1234"),
+                         ("zoo", "basic")]));
+        assert_eq!(key.add_pair("zoo", "other"),
+            Key::pairs(&[("metric", "test"), ("zoo", "other")]));
+        assert_eq!(Key::empty().add_pair("metric", "test"),
+            Key::metric("test"));
+    }
+
+    #[test]
+    fn with_pairs() {
+        let key = Key::pairs(&[("metric", "test"), ("pid", "1234")]);
+        assert!(key.with_pairs(|p| {
+            p == &[("metric", "test"), ("pid", "1234")][..]
+        }));
+        assert_eq!(Key::empty().with_pairs(|p| p.len()), 0);
+    }
+
+    #[test]
+    fn iter_pairs() {
+        let long = "x".repeat(300);
+        let key = Key::pairs(&[("a", &long), ("metric", "test")]);
+        assert_eq!(key.iter_pairs().collect::<Vec<_>>(),
+            vec![("a", &long[..]), ("metric", "test")]);
+        assert_eq!(Key::empty().iter_pairs().count(), 0);
+    }
+
+    #[test]
+    fn remove_pair() {
+        let key = Key::pairs(&[("metric", "test"), ("pid", "1234")]);
+        assert_eq!(key.remove_pair("metric"), Key::from_pair("pid", "1234"));
+        assert_eq!(key.remove_pair("zoo"), key);
+        assert_eq!(Key::metric("test").remove_pair("metric"), Key::empty());
+    }
 }
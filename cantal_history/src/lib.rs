@@ -9,9 +9,14 @@ extern crate cantal_values as values;
 extern crate byteorder;
 
 mod key;
+mod sharedbuf;
 mod deltabuf;
+mod xorbuf;
 mod chunk;
 mod backlog;
+mod coarse;
+mod limits;
+mod index;
 mod tip;
 mod merge;
 mod serde;
@@ -20,10 +25,14 @@ mod tstamp;
 use std::sync::Arc;
 
 pub use backlog::{Backlog, Value};
+pub use key::Pairs;
 pub use tip::Tip;
+pub use coarse::COARSE_INTERVAL;
+pub use limits::{Limits, Dropped};
+pub use index::Index;
 pub use merge::{ChunkSet, ValueSet};
 pub use chunk::HistoryChunk as Chunk;
-pub use serde::VersionInfo;
+pub use serde::{VersionInfo, decode_history};
 pub use tstamp::compare_timestamps;
 use serialize::json::Json;
 
@@ -32,10 +41,12 @@ pub type TimeDelta = u32;  // Milliseconds
 pub type SnapTime = (TimeStamp, TimeDelta);
 pub type CounterHistory = backlog::Inner<u64, deltabuf::DeltaBuf<u64>>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct History {
     /// Values that are kept as fine-grained as possible (2-second interval)
     pub fine: Backlog,
+    /// Values rolled up from fine history into one-minute intervals
+    pub coarse: Backlog,
     /// Values that need only last value to be stored
     pub tip: Tip,
 }
@@ -43,6 +54,7 @@ pub struct History {
 // Named fields are ok since we don't store lots of History objects
 probor_struct_encoder_decoder!(History {
     fine => (),
+    coarse => (),
     tip => (),
 });
 
@@ -56,16 +68,45 @@ impl History {
         return History {
             tip: Tip::new(),
             fine: Backlog::new(),
+            coarse: Backlog::new(),
         }
     }
     pub fn truncate_by_time(&mut self, tstamp: u64) {
-        self.fine.truncate_by_time(tstamp);
+        self.truncate_fine(tstamp);
         self.tip.truncate_by_time(tstamp);
     }
+    /// Truncates fine history rolling up removed values into coarse history
+    ///
+    /// Timestamp is rounded down to the coarse interval, so that only
+    /// complete intervals are rolled up.
+    pub fn truncate_fine(&mut self, tstamp: u64) {
+        let tstamp = tstamp - tstamp % COARSE_INTERVAL;
+        coarse::rollup(&self.fine, tstamp, &mut self.coarse);
+        self.fine.truncate_by_time(tstamp);
+    }
+    /// Sets limits on the number of keys and bytes for every part of
+    /// the history (limits are applied to each of them separately)
+    ///
+    /// Coarse history gets `ROLLUP_KEYS` times larger limits, as every
+    /// integer and float key is rolled up into that many keys.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.fine.limits = limits;
+        self.coarse.limits = limits.scale(coarse::ROLLUP_KEYS);
+        self.tip.limits = limits;
+    }
+    /// Returns statistics of refused values summed over all parts of history
+    pub fn dropped(&self) -> Dropped {
+        let mut result = Dropped::default();
+        result.merge(&self.fine.dropped);
+        result.merge(&self.coarse.dropped);
+        result.merge(&self.tip.dropped);
+        return result;
+    }
     pub fn info(&self) -> Json {
         return Json::Object(vec![
             ("tip".to_string(), self.tip.info()),
             ("fine".to_string(), self.fine.info()),
+            ("coarse".to_string(), self.coarse.info()),
             ].into_iter().collect());
     }
 }
@@ -73,8 +114,9 @@ impl History {
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
-    use {History, Key, ValueSet};
-    use values::Value::{Counter, State};
+    use std::usize;
+    use {History, Key, ValueSet, Limits};
+    use values::Value::{Counter, Integer, Float, State};
     use std::collections::{HashMap, HashSet};
     use probor::{Encodable, Encoder, decode, Config, Decoder};
 
@@ -98,4 +140,20 @@ mod test {
         let h: History = decode(&mut Decoder::new(Config::default(),
             Cursor::new(&e.into_writer()[..]))).unwrap();
     }
+
+    #[test]
+    fn coarse_limits() {
+        let mut h = History::new();
+        h.set_limits(Limits { max_keys: 2, max_bytes: usize::MAX });
+        for (idx, &ts) in [60000, 80000, 100000, 120000].iter().enumerate() {
+            h.fine.push((ts, 10), vec![
+                (&Key::metric("int"), &Integer(idx as i64)),
+                (&Key::metric("float"), &Float(idx as f64)),
+            ].into_iter());
+        }
+        h.truncate_fine(120000);
+        // every fine key is rolled up into four coarse keys
+        assert_eq!(h.coarse.values.len(), 8);
+        assert_eq!(h.dropped().total, 0);
+    }
 }
@@ -0,0 +1,227 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::usize;
+
+use Key;
+
+
+/// Maximum number of distinct sources tracked in `Dropped`
+const MAX_SOURCES: usize = 100;
+
+
+/// Limits on the amount of data stored in a backlog or tip
+///
+/// Limits are not serialized, they should be set each time history is
+/// created or loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of keys (series)
+    pub max_keys: usize,
+    /// Maximum number of bytes used by keys and values (approximate)
+    pub max_bytes: usize,
+}
+
+/// Number of keys stored by each source, not serialized
+///
+/// Used to find sources that use more than their share of the limits.
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    keys: HashMap<String, usize>,
+}
+
+/// Statistics of values refused because of `Limits`
+#[derive(Debug, Clone, Default)]
+pub struct Dropped {
+    /// Total number of values refused (each scan counts separately)
+    pub total: u64,
+    /// Number of values refused by the source of the key,
+    /// e.g. `appname=something`
+    pub sources: HashMap<String, u64>,
+    /// Number of values refused from sources that don't fit into
+    /// `MAX_SOURCES`
+    pub untracked: u64,
+}
+
+impl Limits {
+    pub fn unlimited() -> Limits {
+        Limits {
+            max_keys: usize::MAX,
+            max_bytes: usize::MAX,
+        }
+    }
+    /// Returns limits multiplied by `factor`
+    pub fn scale(&self, factor: usize) -> Limits {
+        Limits {
+            max_keys: self.max_keys.saturating_mul(factor),
+            max_bytes: self.max_bytes.saturating_mul(factor),
+        }
+    }
+    /// Returns true if a new key from the `source` may be added
+    ///
+    /// When the limit is reached, only sources that have less than their
+    /// share of keys (limit divided by the number of sources) may add new
+    /// ones. So a single source that exhausts the limit can't prevent
+    /// others from adding keys. Such sources may exceed the limit, but
+    /// never more than twice.
+    pub fn allow_new_key(&self, keys: usize, bytes: usize,
+        usage: &Usage, source: &str)
+        -> bool
+    {
+        if keys < self.max_keys && bytes < self.max_bytes {
+            return true;
+        }
+        if keys >= self.max_keys.saturating_mul(2) ||
+           bytes >= self.max_bytes.saturating_mul(2)
+        {
+            return false;
+        }
+        let source_keys = usage.keys(source);
+        let sources = usage.sources() + if source_keys == 0 { 1 } else { 0 };
+        source_keys.saturating_mul(sources) < min(keys, self.max_keys)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::unlimited()
+    }
+}
+
+/// Returns the name of the source of the metric
+///
+/// Tries `appname`, `cgroup` and `pid` in this order, as these are the
+/// fields put into the key by the values scanner
+pub fn key_source(key: &Key) -> String {
+    key.get_with("appname", |x| format!("appname={}", x))
+    .or_else(|| key.get_with("cgroup", |x| format!("cgroup={}", x)))
+    .or_else(|| key.get_with("pid", |x| format!("pid={}", x)))
+    .unwrap_or_else(|| String::from("unknown"))
+}
+
+impl Usage {
+    pub fn from_keys<'x, I: Iterator<Item=&'x Key>>(keys: I) -> Usage {
+        let mut usage = Usage::default();
+        for key in keys {
+            usage.insert(key_source(key));
+        }
+        return usage;
+    }
+    pub fn insert(&mut self, source: String) {
+        *self.keys.entry(source).or_insert(0) += 1;
+    }
+    /// Number of keys stored by the source
+    pub fn keys(&self, source: &str) -> usize {
+        self.keys.get(source).map(|x| *x).unwrap_or(0)
+    }
+    /// Number of sources having at least one key
+    pub fn sources(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+impl Dropped {
+    pub fn add(&mut self, source: String) {
+        self.total += 1;
+        if let Some(cnt) = self.sources.get_mut(&source) {
+            *cnt += 1;
+            return;
+        }
+        if self.sources.len() < MAX_SOURCES {
+            warn!("History limit reached, refusing new keys from {}", source);
+            self.sources.insert(source, 1);
+        } else {
+            if self.untracked == 0 {
+                warn!("History limit reached for more than {} sources, \
+                    further sources are not reported", MAX_SOURCES);
+            }
+            self.untracked += 1;
+        }
+    }
+    /// Merge statistics from other backlog
+    pub fn merge(&mut self, other: &Dropped) {
+        self.total += other.total;
+        self.untracked += other.untracked;
+        for (source, cnt) in &other.sources {
+            *self.sources.entry(source.clone()).or_insert(0) += *cnt;
+        }
+    }
+    /// Returns sources sorted by number of values dropped, biggest first
+    pub fn offenders(&self) -> Vec<(&str, u64)> {
+        let mut result = self.sources.iter()
+            .map(|(s, &cnt)| (&s[..], cnt))
+            .collect::<Vec<_>>();
+        result.sort_by(|&(ref a, acnt), &(ref b, bcnt)| {
+            bcnt.cmp(&acnt).then(a.cmp(b))
+        });
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::usize;
+    use Key;
+    use super::{Dropped, Limits, Usage, key_source};
+
+    #[test]
+    fn source() {
+        assert_eq!(key_source(&Key::pairs(&[
+            ("appname", "app1"), ("metric", "x"), ("pid", "123")])),
+            "appname=app1");
+        assert_eq!(key_source(&Key::pairs(&[
+            ("metric", "x"), ("pid", "123")])),
+            "pid=123");
+        assert_eq!(key_source(&Key::metric("x")), "unknown");
+    }
+
+    #[test]
+    fn offenders() {
+        let mut dropped = Dropped::default();
+        dropped.add("pid=1".into());
+        dropped.add("pid=2".into());
+        dropped.add("pid=2".into());
+        assert_eq!(dropped.total, 3);
+        assert_eq!(dropped.offenders(), vec![("pid=2", 2), ("pid=1", 1)]);
+    }
+
+    #[test]
+    fn untracked() {
+        let mut dropped = Dropped::default();
+        for i in 0..105 {
+            dropped.add(format!("pid={}", i));
+        }
+        dropped.add("pid=1".into());
+        assert_eq!(dropped.total, 106);
+        assert_eq!(dropped.sources.len(), 100);
+        assert_eq!(dropped.untracked, 5);
+    }
+
+    #[test]
+    fn share() {
+        let limits = Limits { max_keys: 4, max_bytes: 1000 };
+        let usage = Usage::from_keys(vec![
+            Key::pairs(&[("metric", "a"), ("pid", "1")]),
+            Key::pairs(&[("metric", "b"), ("pid", "1")]),
+            Key::pairs(&[("metric", "c"), ("pid", "1")]),
+            Key::pairs(&[("metric", "a"), ("pid", "2")]),
+        ].iter());
+        assert!(limits.allow_new_key(3, 100, &usage, "pid=1"));
+        // limit is reached
+        assert!(!limits.allow_new_key(4, 100, &usage, "pid=1"));
+        assert!(limits.allow_new_key(4, 100, &usage, "pid=2"));
+        assert!(limits.allow_new_key(4, 100, &usage, "pid=3"));
+        // limit is reached by bytes
+        assert!(!limits.allow_new_key(4, 1000, &usage, "pid=1"));
+        assert!(limits.allow_new_key(4, 1000, &usage, "pid=2"));
+        // twice the limit
+        assert!(!limits.allow_new_key(8, 100, &usage, "pid=3"));
+        assert!(!limits.allow_new_key(4, 2000, &usage, "pid=3"));
+    }
+
+    #[test]
+    fn scale() {
+        let limits = Limits { max_keys: 4, max_bytes: usize::MAX }.scale(4);
+        assert_eq!(limits.max_keys, 16);
+        assert_eq!(limits.max_bytes, usize::MAX);
+    }
+}
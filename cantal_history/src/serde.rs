@@ -1,3 +1,21 @@
+//! Snapshot format versions and migration from older versions
+//!
+//! History of format versions:
+//!
+//! * 2 -- fine history and tip, float values stored as raw `f64`
+//! * 3 -- added coarse history
+//! * 4 -- float values are xor-compressed
+//!
+//! Older versions are decoded into the structures in the `legacy` module
+//! and then converted to the current ones. When bumping the version, add
+//! the previous format here rather than discarding old snapshots.
+use probor::{Decoder, DecodeError, Input, decode};
+
+use History;
+
+/// Oldest version of snapshots that can be migrated
+const OLDEST_VERSION: u8 = 2;
+
 probor_struct!(
 #[derive(PartialEq, Eq, Debug)]
 pub struct VersionInfo {
@@ -6,7 +24,287 @@ pub struct VersionInfo {
 
 impl VersionInfo {
     pub fn current() -> VersionInfo {
-        VersionInfo { version: 2 }
+        VersionInfo { version: 4 }
+    }
+    /// Returns true if history of this version can be decoded, either
+    /// directly or by migration
+    pub fn is_supported(&self) -> bool {
+        self.version >= OLDEST_VERSION &&
+            self.version <= VersionInfo::current().version
+    }
+}
+
+/// Decodes history written with the specified version of the format
+///
+/// The `version` is the value that precedes the history in the snapshot
+pub fn decode_history<R:Input>(version: &VersionInfo, d: &mut Decoder<R>)
+    -> Result<History, DecodeError>
+{
+    match version.version {
+        2 => decode(d).map(legacy::HistoryV2::migrate),
+        3 => decode(d).map(legacy::HistoryV3::migrate),
+        4 => decode(d),
+        _ => Err(DecodeError::WrongValue("unsupported history version")),
     }
 }
 
+mod legacy {
+    use std::io::Cursor;
+    use std::collections::{HashMap, VecDeque};
+
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use probor::{Decodable, Decoder, DecodeError, Input};
+    use probor::{Encodable, Encoder, EncodeError, Output};
+
+    use {History, Key, Tip};
+    use backlog::{Backlog as NewBacklog, Value as NewValue, Inner};
+    use deltabuf::DeltaBuf;
+    use xorbuf::XorBuf;
+
+    /// Float series stored as raw `f64` values newest first, skipped
+    /// values are stored as NaN (versions 2 and 3)
+    pub struct RawFloat {
+        tip: f64,
+        age: u64,
+        buf: RawBuf,
+    }
+
+    pub struct RawBuf(Vec<f64>);
+
+    pub enum Value {
+        Counter(Inner<u64, DeltaBuf<u64>>),
+        Integer(Inner<i64, DeltaBuf<i64>>),
+        Float(RawFloat),
+    }
+
+    probor_enum_encoder_decoder!(Value {
+        #0 Counter(inner #1),
+        #1 Integer(inner #1),
+        #2 Float(inner #1),
+    });
+
+    pub struct Backlog {
+        age: u64,
+        timestamps: VecDeque<(u64, u32)>,
+        values: HashMap<Key, Value>,
+    }
+
+    probor_struct_encoder_decoder!(Backlog {
+        age => (),
+        timestamps => (),
+        values => (),
+    });
+
+    /// Version 2
+    pub struct HistoryV2 {
+        pub fine: Backlog,
+        pub tip: Tip,
+    }
+
+    probor_struct_encoder_decoder!(HistoryV2 {
+        fine => (),
+        tip => (),
+    });
+
+    /// Version 3
+    pub struct HistoryV3 {
+        pub fine: Backlog,
+        pub coarse: Backlog,
+        pub tip: Tip,
+    }
+
+    probor_struct_encoder_decoder!(HistoryV3 {
+        fine => (),
+        coarse => (),
+        tip => (),
+    });
+
+    impl RawFloat {
+        pub fn migrate(self) -> Inner<f64, XorBuf> {
+            let RawBuf(values) = self.buf;
+            let mut buf = XorBuf::new();
+            let mut prev = None;
+            let mut age_diff = 1;
+            // leading NaNs (if any) are dropped, which only makes history
+            // shorter
+            for &value in values.iter().rev() {
+                if value.is_nan() {
+                    age_diff += 1;
+                    continue;
+                }
+                if let Some(prev) = prev {
+                    buf.push(prev, value, age_diff);
+                }
+                prev = Some(value);
+                age_diff = 1;
+            }
+            if let Some(prev) = prev {
+                buf.push(prev, self.tip, age_diff);
+            }
+            Inner::unpack(self.tip, self.age, buf)
+        }
+    }
+
+    impl Value {
+        pub fn migrate(self) -> NewValue {
+            match self {
+                Value::Counter(x) => NewValue::Counter(x),
+                Value::Integer(x) => NewValue::Integer(x),
+                Value::Float(x) => NewValue::Float(x.migrate()),
+            }
+        }
+    }
+
+    impl Backlog {
+        pub fn migrate(self) -> NewBacklog {
+            NewBacklog::unpack(self.age, self.timestamps,
+                self.values.into_iter()
+                    .map(|(k, v)| (k, v.migrate()))
+                    .collect())
+        }
+    }
+
+    impl HistoryV2 {
+        pub fn migrate(self) -> History {
+            History {
+                fine: self.fine.migrate(),
+                coarse: NewBacklog::new(),
+                tip: self.tip,
+            }
+        }
+    }
+
+    impl HistoryV3 {
+        pub fn migrate(self) -> History {
+            History {
+                fine: self.fine.migrate(),
+                coarse: self.coarse.migrate(),
+                tip: self.tip,
+            }
+        }
+    }
+
+    impl Decodable for RawFloat {
+        fn decode_opt<R:Input>(d: &mut Decoder<R>)
+            -> Result<Option<Self>, DecodeError>
+        {
+            probor_dec_struct!(d, {
+                tip => (#0),
+                age => (#1),
+                buf => (#2),
+            });
+            Ok(Some(RawFloat { tip: tip, age: age, buf: buf }))
+        }
+    }
+
+    impl Encodable for RawFloat {
+        fn encode<W:Output>(&self, e: &mut Encoder<W>)
+            -> Result<(), EncodeError>
+        {
+            try!(e.array(3));  // {tip, age, buf}
+            try!(self.tip.encode(e));  // #0
+            try!(self.age.encode(e));  // #1
+            try!(self.buf.encode(e));  // #2
+            Ok(())
+        }
+    }
+
+    impl Decodable for RawBuf {
+        fn decode_opt<R:Input>(d: &mut Decoder<R>)
+            -> Result<Option<Self>, DecodeError>
+        {
+            let buf = try!(d.bytes()
+                .map_err(|e| DecodeError::WrongType("expected bytes", e)));
+            if buf.len() % 8 > 0 {
+                return Err(DecodeError::WrongValue("length of f64 buffer \
+                    should be multiple of 8"));
+            }
+            let num = buf.len() / 8;
+            let mut cur = Cursor::new(buf);
+            let mut values = Vec::with_capacity(num);
+            for _ in 0..num {
+                values.push(cur.read_f64::<BigEndian>().unwrap());
+            }
+            Ok(Some(RawBuf(values)))
+        }
+    }
+
+    impl Encodable for RawBuf {
+        fn encode<W:Output>(&self, e: &mut Encoder<W>)
+            -> Result<(), EncodeError>
+        {
+            let mut buf = Vec::with_capacity(self.0.len()*8);
+            for &val in &self.0 {
+                buf.write_f64::<BigEndian>(val).unwrap();
+            }
+            e.bytes(&buf)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use probor::{Decoder, Config, decode};
+    use {History, Key};
+    use backlog::Value;
+    use super::{VersionInfo, decode_history};
+
+    fn read(data: &[u8]) -> History {
+        let mut d = Decoder::new(Config::default(), Cursor::new(data));
+        let version: VersionInfo = decode(&mut d).unwrap();
+        assert!(version.is_supported());
+        decode_history(&version, &mut d).unwrap()
+    }
+
+    fn check_fine(h: &History) {
+        assert_eq!(h.fine.age, 3);
+        assert_eq!(h.fine.timestamps.len(), 3);
+        assert_eq!(h.fine.timestamps[0], (3000, 10));
+        match h.fine.values[&Key::metric("cnt")] {
+            Value::Counter(ref x) => {
+                assert_eq!(x.history(3).collect::<Vec<_>>(),
+                           vec![Some(30), Some(20), Some(10)]);
+            }
+            _ => panic!("not a counter"),
+        }
+        match h.fine.values[&Key::metric("load")] {
+            Value::Float(ref x) => {
+                assert_eq!(x.history(3).collect::<Vec<_>>(),
+                           vec![Some(0.75), None, Some(0.25)]);
+            }
+            _ => panic!("not a float"),
+        }
+        assert_eq!(format!("{:?}",
+            h.tip.values[&Key::metric("state")]),
+            r#"(3000, State((1500, "hello")))"#);
+    }
+
+    #[test]
+    fn version_2() {
+        let h = read(include_bytes!("../test-data/history-v2.cbor"));
+        check_fine(&h);
+        assert_eq!(h.coarse.values.len(), 0);
+    }
+
+    #[test]
+    fn version_3() {
+        let h = read(include_bytes!("../test-data/history-v3.cbor"));
+        check_fine(&h);
+        assert_eq!(h.coarse.timestamps[0], (0, 60000));
+        let key = Key::metric("load").add_pair("rollup", "avg");
+        match h.coarse.values[&key] {
+            Value::Float(ref x) => {
+                assert_eq!(x.history(1).collect::<Vec<_>>(), vec![Some(0.5)]);
+            }
+            _ => panic!("not a float"),
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(!VersionInfo { version: 1 }.is_supported());
+        assert!(!VersionInfo { version: 100 }.is_supported());
+        assert!(VersionInfo::current().is_supported());
+    }
+}
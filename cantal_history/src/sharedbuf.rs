@@ -0,0 +1,191 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::collections::vec_deque::Iter as DequeIter;
+use std::ops::{Index, IndexMut};
+use std::slice::Iter as SliceIter;
+use std::sync::Arc;
+
+
+/// Number of bytes in a sealed segment
+const SEGMENT: usize = 256;
+
+
+/// Byte buffer which is cheap to clone
+///
+/// Bytes are prepended to the buffer and truncated at the end, the same
+/// as with `VecDeque`. Only the newest (at most `SEGMENT`) bytes are
+/// owned by the buffer, older ones are kept in immutable segments shared
+/// between copies. So cloning the history, where every value is appended
+/// to on each scan, doesn't copy all the data points.
+///
+/// All sealed segments except the last one contain exactly `SEGMENT`
+/// bytes.
+#[derive(Debug, Clone)]
+pub struct SharedBuf {
+    head: VecDeque<u8>,
+    sealed: VecDeque<Arc<Vec<u8>>>,
+    len: usize,
+}
+
+#[derive(Clone)]
+pub struct Iter<'a> {
+    head: DequeIter<'a, u8>,
+    sealed: DequeIter<'a, Arc<Vec<u8>>>,
+    segment: SliceIter<'a, u8>,
+}
+
+impl SharedBuf {
+    pub fn new() -> SharedBuf {
+        SharedBuf {
+            head: VecDeque::new(),
+            sealed: VecDeque::new(),
+            len: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn push_front(&mut self, byte: u8) {
+        if self.head.len() >= SEGMENT {
+            let segment = self.head.drain(..).collect::<Vec<_>>();
+            self.sealed.push_front(Arc::new(segment));
+        }
+        self.head.push_front(byte);
+        self.len += 1;
+    }
+    pub fn get(&self, idx: usize) -> Option<&u8> {
+        if idx >= self.len {
+            return None;
+        }
+        if idx < self.head.len() {
+            return self.head.get(idx);
+        }
+        let idx = idx - self.head.len();
+        self.sealed.get(idx / SEGMENT).and_then(|s| s.get(idx % SEGMENT))
+    }
+    /// Leaves at most `len` (oldest are dropped) bytes in the buffer
+    ///
+    /// Only the last segment is copied if it's truncated partially
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        if len <= self.head.len() {
+            self.head.truncate(len);
+            self.sealed.clear();
+        } else {
+            let rest = len - self.head.len();
+            self.sealed.truncate((rest + SEGMENT - 1) / SEGMENT);
+            let last = rest - (self.sealed.len() - 1) * SEGMENT;
+            if let Some(segment) = self.sealed.back_mut() {
+                if segment.len() > last {
+                    Arc::make_mut(segment).truncate(last);
+                }
+            }
+        }
+        self.len = len;
+    }
+    pub fn iter<'x>(&'x self) -> Iter<'x> {
+        Iter {
+            head: self.head.iter(),
+            sealed: self.sealed.iter(),
+            segment: [].iter(),
+        }
+    }
+}
+
+impl From<Vec<u8>> for SharedBuf {
+    fn from(vec: Vec<u8>) -> SharedBuf {
+        let len = vec.len();
+        let head = min(len, SEGMENT);
+        SharedBuf {
+            head: vec[..head].iter().cloned().collect(),
+            sealed: vec[head..].chunks(SEGMENT)
+                .map(|x| Arc::new(x.to_vec()))
+                .collect(),
+            len: len,
+        }
+    }
+}
+
+impl Index<usize> for SharedBuf {
+    type Output = u8;
+    fn index(&self, idx: usize) -> &u8 {
+        self.get(idx).expect("index out of range")
+    }
+}
+
+impl IndexMut<usize> for SharedBuf {
+    /// Copies the segment containing the byte if it's shared
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        assert!(idx < self.len, "index out of range");
+        if idx < self.head.len() {
+            return &mut self.head[idx];
+        }
+        let idx = idx - self.head.len();
+        &mut Arc::make_mut(&mut self.sealed[idx / SEGMENT])[idx % SEGMENT]
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a u8;
+    fn next(&mut self) -> Option<&'a u8> {
+        if let Some(byte) = self.head.next() {
+            return Some(byte);
+        }
+        loop {
+            if let Some(byte) = self.segment.next() {
+                return Some(byte);
+            }
+            match self.sealed.next() {
+                Some(segment) => self.segment = segment.iter(),
+                None => return None,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{SharedBuf, SEGMENT};
+
+    fn check(buf: &SharedBuf, expected: &[u8]) {
+        assert_eq!(buf.len(), expected.len());
+        assert_eq!(buf.iter().cloned().collect::<Vec<_>>(), expected);
+        for (idx, byte) in expected.iter().enumerate() {
+            assert_eq!(buf[idx], *byte);
+        }
+        assert_eq!(buf.get(expected.len()), None);
+    }
+
+    #[test]
+    fn push_and_truncate() {
+        let mut buf = SharedBuf::new();
+        let mut expected = Vec::new();
+        for i in 0..SEGMENT*3 + 10 {
+            buf.push_front(i as u8);
+            expected.insert(0, i as u8);
+        }
+        check(&buf, &expected);
+        for &len in &[SEGMENT*3, SEGMENT*2 + 5, SEGMENT, 7, 0] {
+            let copy = buf.clone();
+            let mut truncated = buf.clone();
+            truncated.truncate(len);
+            check(&truncated, &expected[..len]);
+            check(&copy, &expected);
+        }
+    }
+
+    #[test]
+    fn shared_write() {
+        let mut buf = SharedBuf::from((0..SEGMENT*2).map(|x| x as u8)
+            .collect::<Vec<_>>());
+        let copy = buf.clone();
+        buf[0] = 100;
+        buf[SEGMENT + 1] = 100;
+        assert_eq!(buf[SEGMENT + 1], 100);
+        assert_eq!(copy[0], 0);
+        assert_eq!(copy[SEGMENT + 1], (SEGMENT + 1) as u8);
+    }
+}
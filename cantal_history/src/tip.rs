@@ -2,30 +2,51 @@ use std::mem::{replace, size_of_val};
 use std::collections::HashMap;
 
 use Key;
+use limits::{Limits, Dropped, Usage, key_source};
 use values::Value as TipValue;
 use serialize::json::{Json, ToJson};
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Tip {
     // Made pub for serializer, may be fix it?
     pub latest_timestamp: (u64, u32),
     pub values: HashMap<Key, (u64, TipValue)>,
+    /// Limits for new keys, not serialized
+    pub limits: Limits,
+    /// Values refused because of limits, not serialized
+    pub dropped: Dropped,
+    /// Approximate number of bytes used by keys and values
+    bytes: usize,
+    /// Number of keys by source, not serialized
+    usage: Usage,
 }
 
-// Named fields are ok since we don't store lots of History objects
-probor_struct_encoder_decoder!(Tip {
-    latest_timestamp => (),
-    values => (),
-});
+fn item_size(key: &Key, value: &(u64, TipValue)) -> usize {
+    key.size() + size_of_val(value) + value.1.additional_bytes()
+}
 
 impl Tip {
     pub fn new() -> Tip {
         Tip {
             latest_timestamp: (0, 0),
             values: HashMap::new(),
+            limits: Limits::default(),
+            dropped: Dropped::default(),
+            bytes: 0,
+            usage: Usage::default(),
         }
     }
+    /// Approximate number of bytes used by keys and values
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+    fn recount(&mut self) {
+        self.bytes = self.values.iter()
+            .map(|(k, v)| item_size(k, v))
+            .sum();
+        self.usage = Usage::from_keys(self.values.keys());
+    }
     pub fn info(&self) -> Json {
         let mut key_bytes = 0;
         let mut value_bytes = 0;
@@ -37,6 +58,7 @@ impl Tip {
             ("values".to_string(), self.values.len().to_json()),
             ("key_bytes".to_string(), key_bytes.to_json()),
             ("value_bytes".to_string(), value_bytes.to_json()),
+            ("dropped".to_string(), self.dropped.total.to_json()),
             ].into_iter().collect());
     }
     pub fn push<'x, I>(&mut self, timestamp: (u64, u32), iter: I)
@@ -47,15 +69,70 @@ impl Tip {
             // fast path should be get_mut
             if let Some(ptr) = self.values.get_mut(k) {
                 // Only if no key or conflicting type clone the key
-                *ptr = (timestamp.0, v.clone());
+                let value = (timestamp.0, v.clone());
+                self.bytes = (self.bytes + item_size(k, &value))
+                    .saturating_sub(item_size(k, ptr));
+                *ptr = value;
                 continue;
             }
-            self.values.insert(k.clone(), (timestamp.0, v.clone()));
+            let source = key_source(k);
+            if !self.limits.allow_new_key(self.values.len(), self.bytes,
+                &self.usage, &source)
+            {
+                self.dropped.add(source);
+                continue;
+            }
+            self.usage.insert(source);
+            let value = (timestamp.0, v.clone());
+            self.bytes += item_size(k, &value);
+            self.values.insert(k.clone(), value);
         }
     }
     pub fn truncate_by_time(&mut self, timestamp: u64) {
         self.values = replace(&mut self.values, HashMap::new()).into_iter()
             .filter(|&(_, (ts, _))| ts >= timestamp)
             .collect();
+        self.recount();
+    }
+}
+
+mod serde {
+    use probor::{Decodable, Decoder, DecodeError, Input};
+    use probor::{Encodable, Encoder, EncodeError, Output};
+    use limits::{Limits, Dropped, Usage};
+    use super::Tip;
+
+    // Named fields are ok since we don't store lots of History objects
+    impl Decodable for Tip {
+        fn decode_opt<R:Input>(d: &mut Decoder<R>)
+            -> Result<Option<Self>, DecodeError>
+        {
+            probor_dec_struct!(d, {
+                latest_timestamp => (),
+                values => (),
+            });
+            let mut tip = Tip {
+                latest_timestamp: latest_timestamp,
+                values: values,
+                limits: Limits::default(),
+                dropped: Dropped::default(),
+                bytes: 0,
+                usage: Usage::default(),
+            };
+            tip.recount();
+            Ok(Some(tip))
+        }
+    }
+
+    impl Encodable for Tip {
+        fn encode<W:Output>(&self, e: &mut Encoder<W>)
+            -> Result<(), EncodeError>
+        {
+            probor_enc_struct!(e, self, {
+                latest_timestamp => (),
+                values => (),
+            });
+            Ok(())
+        }
     }
 }
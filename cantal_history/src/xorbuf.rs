@@ -0,0 +1,331 @@
+use std::cmp::min;
+
+use sharedbuf::{SharedBuf, Iter as BufIter};
+
+
+/// Maximum number of gaps stored in a single record
+const MAX_GAPS: u64 = 63;
+const GAPS_BITS: u32 = 6;
+const LEADING_BITS: u32 = 5;
+const MAX_LEADING: u32 = 31;
+const LENGTH_BITS: u32 = 6;
+
+
+/// Compressed buffer of floating point values
+///
+/// Values are encoded as XOR with the next newer value, much like in the
+/// Facebook's Gorilla paper, except records are prepended to the buffer.
+/// So buffer is decoded from the newest value to the oldest one, and
+/// truncated by dropping bytes at the end (same as `DeltaBuf`).
+///
+/// Records in reading order:
+///
+/// * `0` -- value is the same as the newer one
+/// * `10` + 5 bits of leading zeros + 6 bits of (length - 1) + meaningful
+///   bits of XOR
+/// * `11` + 6 bits -- number of skipped values (1..63)
+#[derive(Debug, Clone)]
+pub struct XorBuf {
+    bytes: SharedBuf,
+    /// Number of unused (most significant) bits of the first byte
+    free: u8,
+    /// Number of values (including skipped ones) stored
+    len: usize,
+}
+
+struct Bits<'a> {
+    bytes: &'a SharedBuf,
+    pos: usize,
+}
+
+#[derive(Clone)]
+pub struct XorIter<'a> {
+    bytes: &'a SharedBuf,
+    pos: usize,
+    left: usize,
+    gaps: u64,
+    value: f64,
+}
+
+impl<'a> Bits<'a> {
+    fn read(&mut self, num: u32) -> u64 {
+        let mut result = 0u64;
+        for _ in 0..num {
+            // out of range may only happen on corrupted data
+            let byte = self.bytes.get(self.pos / 8).map(|x| *x).unwrap_or(0);
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            result = (result << 1) | bit as u64;
+            self.pos += 1;
+        }
+        return result;
+    }
+}
+
+impl XorBuf {
+    pub fn new() -> XorBuf {
+        XorBuf {
+            bytes: SharedBuf::new(),
+            free: 0,
+            len: 0,
+        }
+    }
+    /// Restores the buffer from parts returned by `bytes()`, `free_bits()`
+    /// and `len()`
+    pub fn from_parts(bytes: Vec<u8>, free: u8, len: usize)
+        -> Result<XorBuf, &'static str>
+    {
+        if free >= 8 {
+            return Err("Too many free bits in xor buffer");
+        }
+        if bytes.len() == 0 && (free != 0 || len != 0) {
+            return Err("Non-empty xor buffer without data");
+        }
+        Ok(XorBuf {
+            bytes: bytes.into(),
+            free: free,
+            len: len,
+        })
+    }
+    /// Prepends `num` lowest bits of the value
+    ///
+    /// Bits are read most significant first, before any bits that are
+    /// already in the buffer.
+    fn prepend_bits(&mut self, mut value: u64, mut num: u32) {
+        while num > 0 {
+            if self.free == 0 {
+                self.bytes.push_front(0);
+                self.free = 8;
+            }
+            let n = min(num, self.free as u32);
+            let chunk = (value & ((1 << n) - 1)) as u8;
+            self.bytes[0] |= chunk << (8 - self.free);
+            self.free -= n as u8;
+            value >>= n;
+            num -= n;
+        }
+    }
+    fn write_at(&mut self, pos: usize, value: u64, num: u32) {
+        for i in 0..num as usize {
+            let bit = ((value >> (num as usize - 1 - i)) & 1) as u8;
+            let shift = 7 - (pos + i) % 8;
+            let byte = &mut self.bytes[(pos + i) / 8];
+            *byte = (*byte & !(1 << shift)) | (bit << shift);
+        }
+    }
+    pub fn push(&mut self, old_value: f64, new_value: f64, age_diff: u64) {
+        if age_diff == 0 {
+            warn!("Duplicate write at same age"); // Shouldn't we panic?
+            return;
+        }
+        // value is read after the gaps, so it's prepended first
+        let xor = old_value.to_bits() ^ new_value.to_bits();
+        if xor == 0 {
+            self.prepend_bits(0b0, 1);
+        } else {
+            let lead = min(xor.leading_zeros(), MAX_LEADING);
+            let trail = xor.trailing_zeros();
+            let len = 64 - lead - trail;
+            self.prepend_bits(xor >> trail, len);
+            self.prepend_bits((len - 1) as u64, LENGTH_BITS);
+            self.prepend_bits(lead as u64, LEADING_BITS);
+            self.prepend_bits(0b10, 2);
+        }
+        self.len += 1;
+        let mut gaps = age_diff - 1;
+        while gaps > 0 {
+            let num = min(gaps, MAX_GAPS);
+            self.prepend_bits(num, GAPS_BITS);
+            self.prepend_bits(0b11, 2);
+            self.len += num as usize;
+            gaps -= num;
+        }
+    }
+    /// Iterates over values starting from the one before `newest`
+    ///
+    /// Skipped values are returned as `None`
+    pub fn values<'a>(&'a self, newest: f64) -> XorIter<'a> {
+        XorIter {
+            bytes: &self.bytes,
+            pos: self.free as usize,
+            left: self.len,
+            gaps: 0,
+            value: newest,
+        }
+    }
+    /// Leaves at most `limit` values in the buffer
+    pub fn truncate(&mut self, limit: usize) {
+        if limit >= self.len {
+            return;
+        }
+        if limit == 0 {
+            *self = XorBuf::new();
+            return;
+        }
+        let mut count = 0;
+        let mut rewrite = None;
+        let end = {
+            let mut bits = Bits { bytes: &self.bytes, pos: self.free as usize };
+            while count < limit {
+                if bits.read(1) == 0 {
+                    count += 1;
+                } else if bits.read(1) == 0 {
+                    bits.read(LEADING_BITS);
+                    let len = bits.read(LENGTH_BITS) + 1;
+                    bits.pos += len as usize;
+                    count += 1;
+                } else {
+                    let pos = bits.pos;
+                    let num = bits.read(GAPS_BITS) as usize;
+                    if count + num > limit {
+                        rewrite = Some((pos, limit - count));
+                        count = limit;
+                    } else {
+                        count += num;
+                    }
+                }
+            }
+            bits.pos
+        };
+        if let Some((pos, num)) = rewrite {
+            self.write_at(pos, num as u64, GAPS_BITS);
+        }
+        self.bytes.truncate((end + 7) / 8);
+        self.len = limit;
+    }
+    /// Number of values (including skipped ones) in the buffer
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// Number of unused bits in the first byte of `bytes()`
+    pub fn free_bits(&self) -> u8 {
+        self.free
+    }
+    pub fn bytes<'x>(&'x self) -> BufIter<'x> {
+        self.bytes.iter()
+    }
+    pub fn byte_size(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl<'a> Iterator for XorIter<'a> {
+    type Item = Option<f64>;
+    fn next(&mut self) -> Option<Option<f64>> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        if self.gaps > 0 {
+            self.gaps -= 1;
+            return Some(None);
+        }
+        let mut bits = Bits { bytes: self.bytes, pos: self.pos };
+        let result = if bits.read(1) == 0 {
+            Some(self.value)
+        } else if bits.read(1) == 0 {
+            let lead = bits.read(LEADING_BITS);
+            let len = bits.read(LENGTH_BITS) + 1;
+            let xor = bits.read(len as u32) << 64u64.saturating_sub(lead + len);
+            self.value = f64::from_bits(self.value.to_bits() ^ xor);
+            Some(self.value)
+        } else {
+            self.gaps = bits.read(GAPS_BITS).saturating_sub(1);
+            None
+        };
+        self.pos = bits.pos;
+        return Some(result);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::XorBuf;
+
+    /// Makes a buffer from values, oldest first, returns the newest value
+    /// and the buffer
+    fn to_buf(values: &[Option<f64>]) -> (f64, XorBuf) {
+        let mut buf = XorBuf::new();
+        let mut off = 0;
+        let mut old = values[0].unwrap();
+        for idx in 0..(values.len()-1) {
+            off += 1;
+            values[idx+1].map(|v| {
+                buf.push(old, v, off);
+                old = v;
+                off = 0;
+            });
+        }
+        return (old, buf);
+    }
+
+    fn decode(values: &[Option<f64>]) -> Vec<Option<f64>> {
+        let (tip, buf) = to_buf(values);
+        return buf.values(tip).collect();
+    }
+
+    fn expected(values: &[Option<f64>]) -> Vec<Option<f64>> {
+        values.iter().rev().skip(1).cloned().collect()
+    }
+
+    #[test]
+    fn simple() {
+        let values = [Some(1.0), Some(1.5), Some(1.5), Some(-2.25),
+                      Some(0.1), Some(0.2), Some(1e100), Some(0.0),
+                      Some(0.0), Some(0.3)];
+        assert_eq!(decode(&values), expected(&values));
+    }
+
+    #[test]
+    fn skips() {
+        let mut values = vec![Some(0.5), None, Some(0.75), Some(0.75)];
+        for _ in 0..100 {
+            values.push(None);
+        }
+        values.push(Some(3.0));
+        values.push(None);
+        values.push(Some(3.0));
+        assert_eq!(decode(&values), expected(&values));
+    }
+
+    #[test]
+    fn truncate() {
+        let mut values = vec![Some(0.5), None, Some(0.75), Some(0.75)];
+        for _ in 0..70 {
+            values.push(None);
+        }
+        values.extend(&[Some(3.0), Some(0.1), None, Some(0.3), Some(0.3)]);
+        let (tip, buf) = to_buf(&values);
+        let exp = expected(&values);
+        for i in 0..exp.len()+2 {
+            let mut b = buf.clone();
+            b.truncate(i);
+            assert_eq!(b.values(tip).collect::<Vec<_>>(),
+                &exp[..::std::cmp::min(i, exp.len())]);
+            // buffer is still valid for pushing after truncation
+            b.push(tip, 7.0, 2);
+            let mut nexp = vec![None, Some(tip)];
+            nexp.extend(&exp[..::std::cmp::min(i, exp.len())]);
+            assert_eq!(b.values(7.0).collect::<Vec<_>>(), nexp);
+        }
+    }
+
+    #[test]
+    fn compression() {
+        let mut values = Vec::new();
+        for i in 0..1000 {
+            values.push(Some(((i / 10) as f64) * 0.5));
+        }
+        let (_, buf) = to_buf(&values);
+        assert!(buf.byte_size() < 999*8/4);
+    }
+
+    #[test]
+    fn parts() {
+        let values = [Some(1.0), Some(1.5), None, Some(-2.25)];
+        let (tip, buf) = to_buf(&values);
+        let copy = XorBuf::from_parts(buf.bytes().cloned().collect(),
+            buf.free_bits(), buf.len()).unwrap();
+        assert_eq!(copy.values(tip).collect::<Vec<_>>(), expected(&values));
+    }
+}
@@ -0,0 +1,285 @@
+extern crate argparse;
+extern crate probor;
+extern crate rustc_serialize;
+extern crate cantal_values;
+extern crate cantal_history;
+extern crate cantal_query;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{stderr, Write, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use argparse::{ArgumentParser, Parse, StoreOption, StoreConst, StoreTrue};
+use argparse::{Print};
+use rustc_serialize::json::{self, Json, ToJson};
+
+use cantal_values::Value;
+use cantal_history::{History, VersionInfo, Key, Chunk, TimeStamp};
+use cantal_history::decode_history;
+use cantal_query::{Rule, Filter, Source, Extract, Condition, Dataset};
+use cantal_query::{query_history, Budget};
+
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Keys,
+    Dump,
+    Query,
+}
+
+struct Row<'a> {
+    key: &'a Key,
+    timestamp: TimeStamp,
+    value: Value,
+}
+
+
+fn read_history(path: &Path) -> Result<History, Box<Error>> {
+    // Same limits as the agent uses when reading snapshots at startup
+    let cborcfg = probor::Config {
+        max_len_array: 100000,
+        max_len_bytes: 0x500000,
+        max_len_text: 0x500000,
+        max_size_map: 100000,
+        max_nesting: 16,
+        .. probor::Config::default()
+    };
+    let file = BufReader::new(try!(File::open(path)));
+    let mut dec = probor::Decoder::new(cborcfg, file);
+    let version: VersionInfo = try!(probor::decode(&mut dec)
+        .map_err(|e| format!("Can't decode version info: {}", e)));
+    if !version.is_supported() {
+        return Err(format!("Unsupported version of history data {:?}, \
+            expected {:?}", version, VersionInfo::current()).into());
+    }
+    let history = try!(decode_history(&version, &mut dec)
+        .map_err(|e| format!("Error parsing history: {}", e)));
+    Ok(history)
+}
+
+fn value_to_string(value: &Value) -> String {
+    match *value {
+        Value::Counter(x) => x.to_string(),
+        Value::Integer(x) => x.to_string(),
+        Value::Float(x) => x.to_string(),
+        Value::State((_, ref x)) => x.clone(),
+    }
+}
+
+fn value_to_json(value: &Value) -> Json {
+    match *value {
+        Value::Counter(x) => x.to_json(),
+        Value::Integer(x) => x.to_json(),
+        Value::Float(x) => x.to_json(),
+        Value::State((_, ref x)) => x.to_json(),
+    }
+}
+
+fn csv_field(val: &str) -> String {
+    if val.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", val.replace('"', "\"\""))
+    } else {
+        val.to_string()
+    }
+}
+
+fn series_rows<'a>(key: &'a Key, chunk: &Chunk, timestamps: &[TimeStamp],
+    rows: &mut Vec<Row<'a>>)
+{
+    for (&timestamp, value) in timestamps.iter().zip(chunk.iter()) {
+        if let Some(value) = value {
+            rows.push(Row { key: key, timestamp: timestamp, value: value });
+        }
+    }
+}
+
+fn dataset_rows<'a>(dataset: &'a Dataset, rows: &mut Vec<Row<'a>>)
+    -> Result<(), String>
+{
+    use cantal_query::Dataset::*;
+    match *dataset {
+        SingleSeries(ref key, ref chunk, ref ts) => {
+            series_rows(key, chunk, ts, rows);
+        }
+        MultiSeries(ref vec) => {
+            for &(ref key, ref chunk, ref ts) in vec {
+                series_rows(key, chunk, ts, rows);
+            }
+        }
+        SingleTip(ref key, ref value, (ts, _)) => {
+            rows.push(Row { key: key, timestamp: ts, value: value.clone() });
+        }
+        MultiTip(ref vec) => {
+            for &(ref key, ref value, (ts, _)) in vec {
+                rows.push(Row {
+                    key: key, timestamp: ts, value: value.clone() });
+            }
+        }
+        Chart(_) => {
+            return Err(format!("Charts can't be printed as rows: {:?}",
+                dataset));
+        }
+        Empty => {}
+        Incompatible(ref reason) => {
+            return Err(format!("Incompatible dataset: {:?}", reason));
+        }
+    }
+    Ok(())
+}
+
+fn print_rows(rows: &[Row], as_json: bool) {
+    if as_json {
+        let items = rows.iter().map(|row| {
+            Json::Object(vec![
+                ("key".to_string(), row.key.to_json()),
+                ("timestamp".to_string(), row.timestamp.to_json()),
+                ("value".to_string(), value_to_json(&row.value)),
+            ].into_iter().collect())
+        }).collect();
+        println!("{}", Json::Array(items).pretty());
+    } else {
+        println!("key,timestamp,value");
+        for row in rows {
+            println!("{},{},{}",
+                csv_field(&format!("{}", row.key.to_json())),
+                row.timestamp,
+                csv_field(&value_to_string(&row.value)));
+        }
+    }
+}
+
+fn print_keys(history: &History, as_json: bool) {
+    let mut keys = history.fine.values.keys().map(|k| ("fine", k))
+        .chain(history.coarse.values.keys().map(|k| ("coarse", k)))
+        .chain(history.tip.values.keys().map(|k| ("tip", k)))
+        .collect::<Vec<_>>();
+    keys.sort();
+    if as_json {
+        let items = keys.iter().map(|&(source, key)| {
+            Json::Object(vec![
+                ("source".to_string(), source.to_json()),
+                ("key".to_string(), key.to_json()),
+            ].into_iter().collect())
+        }).collect();
+        println!("{}", Json::Array(items).pretty());
+    } else {
+        println!("source,key");
+        for (source, key) in keys {
+            println!("{},{}", source,
+                csv_field(&format!("{}", key.to_json())));
+        }
+    }
+}
+
+fn run(path: &Path, action: Action, argument: Option<String>, as_json: bool)
+    -> Result<(), Box<Error>>
+{
+    let history = try!(read_history(path));
+    let rules = match action {
+        Action::Keys => {
+            print_keys(&history, as_json);
+            return Ok(());
+        }
+        Action::Dump => {
+            let condition: Condition = try!(json::decode(
+                &argument.expect("condition is required for dump")));
+            let num = history.fine.timestamps.len();
+            vec![
+                Rule {
+                    series: Filter {
+                        source: Source::Fine,
+                        condition: condition.clone(),
+                    },
+                    extract: Extract::HistoryByNum(num),
+                    functions: Vec::new(),
+                },
+                Rule {
+                    series: Filter {
+                        source: Source::Tip,
+                        condition: condition,
+                    },
+                    extract: Extract::Tip,
+                    functions: Vec::new(),
+                },
+            ]
+        }
+        Action::Query => {
+            vec![try!(json::decode(
+                &argument.expect("rule is required for query")))]
+        }
+    };
+    let datasets = rules.iter()
+        .map(|rule| {
+            query_history(rule, &history, &mut Budget::unlimited())
+        })
+        .collect::<Vec<_>>();
+    let mut rows = Vec::new();
+    for dataset in &datasets {
+        try!(dataset_rows(dataset, &mut rows));
+    }
+    print_rows(&rows, as_json);
+    Ok(())
+}
+
+fn main() {
+    let mut path = PathBuf::new();
+    let mut action = Action::Keys;
+    let mut condition = None::<String>;
+    let mut rule = None::<String>;
+    let mut as_json = false;
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("
+            Inspect history snapshots (`current.cbor` and `hourly-*.cbor`)
+            stored by cantal agent in its storage dir. By default lists all
+            keys in the snapshot.
+            ");
+        ap.refer(&mut path)
+            .add_argument("file", Parse, "Snapshot file to read")
+            .required();
+        ap.refer(&mut action)
+            .add_option(&["-k", "--keys"], StoreConst(Action::Keys),
+                "List keys stored in the snapshot (default)")
+            .add_option(&["-d", "--dump"], StoreConst(Action::Dump),
+                "Dump all values of series matching the condition")
+            .add_option(&["-q", "--query"], StoreConst(Action::Query),
+                "Execute a rule (same json as `/query` uses) against
+                 the snapshot");
+        ap.refer(&mut condition)
+            .add_option(&["-c", "--condition"], StoreOption,
+                r#"Condition for `--dump`, as json,
+                   e.g. `["Eq", "metric", "rss"]`"#);
+        ap.refer(&mut rule)
+            .add_option(&["-r", "--rule"], StoreOption,
+                "A rule for `--query` as json");
+        ap.refer(&mut as_json)
+            .add_option(&["--json"], StoreTrue,
+                "Print json instead of CSV");
+        ap.add_option(&["-V", "--version"],
+            Print(env!("CARGO_PKG_VERSION").to_string()),
+            "Show version and exit");
+        ap.parse_args_or_exit();
+    }
+    let argument = match action {
+        Action::Keys => None,
+        Action::Dump if condition.is_none() => {
+            writeln!(&mut stderr(), "Option --condition is required \
+                for --dump").ok();
+            exit(1);
+        }
+        Action::Dump => condition,
+        Action::Query if rule.is_none() => {
+            writeln!(&mut stderr(), "Option --rule is required \
+                for --query").ok();
+            exit(1);
+        }
+        Action::Query => rule,
+    };
+    if let Err(e) = run(&path, action, argument, as_json) {
+        writeln!(&mut stderr(), "Error reading history at {:?}: {}",
+            path, e).ok();
+        exit(1);
+    }
+}
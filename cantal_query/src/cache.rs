@@ -0,0 +1,408 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::mem::replace;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use history::{History, Backlog, Key, Chunk, TimeStamp};
+use query::{query_series, apply_functions};
+use query::{matching_values, extract_multi, time_window};
+use {Rule, Source, Extract, Dataset, Budget};
+
+
+/// Maximum number of rules kept in the cache
+const MAX_ENTRIES: usize = 1000;
+
+/// Maximum number of data points kept in the cache, both results and
+/// series kept for incremental updates are counted
+const MAX_POINTS: usize = 1_000_000;
+
+
+/// Identifies state of the history, changes on every scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Generation {
+    fine: (u64, usize),
+    coarse: (u64, usize),
+    tip: TimeStamp,
+}
+
+#[derive(Debug)]
+struct Entry {
+    generation: Generation,
+    /// Series before functions are applied, only kept for rules that
+    /// can be updated incrementally
+    series: Option<Dataset>,
+    result: Arc<Dataset>,
+    /// Number of data points in both the series and the result
+    points: usize,
+    /// Whether rule was queried since last `expire()`
+    used: bool,
+}
+
+/// Cache of query results keyed by rule
+///
+/// Results are reused until the history changes. Series extracted by
+/// `HistoryByNum` and `HistoryByTime` from fine or coarse history are
+/// updated by the new data points instead of being extracted from scratch.
+///
+/// The lock is only held to look up and to store the entries, so queries
+/// run in parallel and a slow query doesn't block others.
+#[derive(Debug)]
+pub struct Cache {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    entries: HashMap<Rule, Entry>,
+    /// Total number of data points in all the entries
+    points: usize,
+}
+
+enum Lookup {
+    Hit(Arc<Dataset>),
+    /// Series extracted at the specified age of the backlog
+    Update(Dataset, u64),
+    Miss,
+}
+
+impl Generation {
+    fn of(history: &History) -> Generation {
+        Generation {
+            fine: (history.fine.age, history.fine.timestamps.len()),
+            coarse: (history.coarse.age, history.coarse.timestamps.len()),
+            tip: history.tip.latest_timestamp.0,
+        }
+    }
+    fn age(&self, source: Source) -> Option<u64> {
+        match source {
+            Source::Tip => None,
+            Source::Fine => Some(self.fine.0),
+            Source::Coarse => Some(self.coarse.0),
+        }
+    }
+}
+
+fn incremental(rule: &Rule) -> bool {
+    match (rule.series.source, &rule.extract) {
+        (Source::Tip, _) => false,
+        (_, &Extract::HistoryByNum(_)) => true,
+        (_, &Extract::HistoryByTime(_)) => true,
+        (_, _) => false,
+    }
+}
+
+fn backlog<'x>(source: Source, history: &'x History) -> Option<&'x Backlog> {
+    match source {
+        Source::Tip => None,
+        Source::Fine => Some(&history.fine),
+        Source::Coarse => Some(&history.coarse),
+    }
+}
+
+/// Prepends `new` values to the `old` ones keeping at most `num` values
+fn join(new: Chunk, old: Chunk, diff: usize, num: usize) -> Chunk {
+    fn join_vec<T>(mut new: Vec<T>, old: Vec<T>, num: usize) -> Vec<T> {
+        new.extend(old);
+        new.truncate(num);
+        new
+    }
+    use history::Chunk::*;
+    // shorter chunk means value was replaced in the meantime
+    if chunk_len(&new) < diff {
+        return new;
+    }
+    match (new, old) {
+        (Counter(new), Counter(old)) => Counter(join_vec(new, old, num)),
+        (Integer(new), Integer(old)) => Integer(join_vec(new, old, num)),
+        (Float(new), Float(old)) => Float(join_vec(new, old, num)),
+        (new, _) => new,
+    }
+}
+
+fn chunk_len(chunk: &Chunk) -> usize {
+    match *chunk {
+        Chunk::State(_) => 1,
+        Chunk::Counter(ref vec) => vec.len(),
+        Chunk::Integer(ref vec) => vec.len(),
+        Chunk::Float(ref vec) => vec.len(),
+    }
+}
+
+fn dataset_points(dataset: &Dataset) -> usize {
+    use Dataset::*;
+    match *dataset {
+        SingleSeries(_, ref chunk, _) => chunk_len(chunk),
+        MultiSeries(ref vec) => {
+            vec.iter().map(|&(_, ref chunk, _)| chunk_len(chunk)).sum()
+        }
+        SingleTip(..) => 1,
+        MultiTip(ref vec) => vec.len(),
+        Chart(ref map) => map.len(),
+        Empty | Incompatible(_) => 0,
+    }
+}
+
+/// Updates series extracted at `age` of the backlog with new data points
+///
+/// Returns `None` if series can't be updated and should be extracted
+/// from scratch
+fn shift(rule: &Rule, series: Dataset, age: u64, bl: &Backlog,
+    budget: &mut Budget)
+    -> Option<Dataset>
+{
+    let num = match rule.extract {
+        Extract::HistoryByNum(n) => min(n, bl.timestamps.len()),
+        Extract::HistoryByTime(delta) => match time_window(bl, delta) {
+            Some(num) => num,
+            None => return None,
+        },
+        _ => return None,
+    };
+    if bl.age <= age || (bl.age - age) as usize >= num {
+        return None;
+    }
+    let diff = (bl.age - age) as usize;
+    let old = match series {
+        Dataset::MultiSeries(old) => old,
+        _ => return None,
+    };
+    let timestamps = bl.timestamps.iter()
+        .take(num).map(|&(x, _)| x).collect::<Vec<_>>();
+    if let Some(&(_, _, ref old_ts)) = old.first() {
+        // old data points must be the same data points shifted by `diff`
+        let len = min(old_ts.len(), num - diff);
+        if old_ts[..len] != timestamps[diff..diff+len] {
+            return None;
+        }
+    }
+    let mut old = old.into_iter()
+        .map(|(key, chunk, _)| (key, chunk))
+        .collect::<HashMap<Key, Chunk>>();
+    let mut result = Vec::new();
+    let matching = match matching_values(rule, bl, budget) {
+        Ok(matching) => matching,
+        Err(c) => return Some(Dataset::Incompatible(c)),
+    };
+    for (key, value) in matching {
+        let new = match extract_multi(value, bl, &Extract::HistoryByNum(diff))
+        {
+            Some((chunk, _)) => chunk,
+            None => continue,
+        };
+        if let Err(c) = budget.add_series(diff) {
+            return Some(Dataset::Incompatible(c));
+        }
+        let chunk = match old.remove(key) {
+            Some(old) => join(new, old, diff, num),
+            // key is new since previous update
+            None => new,
+        };
+        result.push((key.clone(), chunk, timestamps.clone()));
+    }
+    Some(Dataset::MultiSeries(result))
+}
+
+impl State {
+    /// Returns the cached result or the series to update
+    ///
+    /// Outdated entry is removed, it's stored again when updated.
+    fn lookup(&mut self, rule: &Rule, generation: Generation) -> Lookup {
+        let hit = match self.entries.get_mut(rule) {
+            Some(entry) => {
+                entry.used = true;
+                if entry.generation == generation {
+                    Some(entry.result.clone())
+                } else {
+                    None
+                }
+            }
+            None => return Lookup::Miss,
+        };
+        if let Some(result) = hit {
+            return Lookup::Hit(result);
+        }
+        let entry = self.remove(rule).expect("entry exists");
+        match (entry.series, entry.generation.age(rule.series.source)) {
+            (Some(series), Some(age)) => Lookup::Update(series, age),
+            _ => Lookup::Miss,
+        }
+    }
+    fn remove(&mut self, rule: &Rule) -> Option<Entry> {
+        let entry = self.entries.remove(rule);
+        if let Some(ref entry) = entry {
+            self.points -= entry.points;
+        }
+        return entry;
+    }
+    fn store(&mut self, rule: &Rule, entry: Entry) {
+        // entry might be stored by a concurrent query of the same rule
+        self.remove(rule);
+        if self.entries.len() >= MAX_ENTRIES ||
+            self.points + entry.points > MAX_POINTS
+        {
+            return;
+        }
+        self.points += entry.points;
+        self.entries.insert(rule.clone(), entry);
+    }
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                points: 0,
+            }),
+        }
+    }
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().expect("cache not poisoned")
+    }
+    /// Queries history reusing or updating the previous result of the rule
+    ///
+    /// Only the work actually done is accounted in the budget. Results
+    /// exceeding the limits are not cached.
+    pub fn query(&self, rule: &Rule, history: &History,
+        budget: &mut Budget)
+        -> Dataset
+    {
+        let generation = Generation::of(history);
+        let lookup = self.lock().lookup(rule, generation);
+        let series = match lookup {
+            Lookup::Hit(result) => return (*result).clone(),
+            Lookup::Update(old, age) => {
+                backlog(rule.series.source, history)
+                .and_then(|bl| shift(rule, old, age, bl, budget))
+            }
+            Lookup::Miss => None,
+        };
+        let series = series
+            .unwrap_or_else(|| query_series(rule, history, budget));
+        let (result, series) = if incremental(rule) {
+            let result = apply_functions(rule, series.clone(), budget);
+            (result, Some(series))
+        } else {
+            (apply_functions(rule, series, budget), None)
+        };
+        if budget.exceeded() {
+            self.lock().remove(rule);
+            return result;
+        }
+        let points = dataset_points(&result) +
+            series.as_ref().map(dataset_points).unwrap_or(0);
+        if points <= MAX_POINTS {
+            let entry = Entry {
+                generation: generation,
+                series: series,
+                result: Arc::new(result.clone()),
+                points: points,
+                used: true,
+            };
+            self.lock().store(rule, entry);
+        }
+        return result;
+    }
+    /// Drops results of the rules that weren't queried since the previous
+    /// call, should be called on every scan
+    pub fn expire(&self) {
+        let mut state = self.lock();
+        let mut points = 0;
+        state.entries.retain(|_, entry| {
+            if replace(&mut entry.used, false) {
+                points += entry.points;
+                true
+            } else {
+                false
+            }
+        });
+        state.points = points;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use history::{History, Key};
+    use values::Value::Counter;
+    use {Rule, Filter, Source, Extract, Condition, Dataset, Budget};
+    use query_history;
+    use super::Cache;
+
+    fn rule(extract: Extract) -> Rule {
+        Rule {
+            series: Filter {
+                source: Source::Fine,
+                condition: Condition::Has("metric".into()),
+            },
+            extract: extract,
+            functions: vec![],
+        }
+    }
+
+    fn push(history: &mut History, ts: u64, keys: &[(&str, u64)]) {
+        let keys = keys.iter()
+            .map(|&(name, value)| (Key::metric(name), Counter(value)))
+            .collect::<Vec<_>>();
+        history.fine.push((ts, 10),
+            keys.iter().map(|&(ref k, ref v)| (k, v)));
+    }
+
+    fn sorted(dataset: Dataset) -> String {
+        match dataset {
+            Dataset::MultiSeries(mut vec) => {
+                vec.sort_by(|a, b| a.0.cmp(&b.0));
+                format!("{:?}", vec)
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    fn check(cache: &Cache, rule: &Rule, history: &History) {
+        let cached = cache.query(rule, history, &mut Budget::unlimited());
+        let fresh = query_history(rule, history, &mut Budget::unlimited());
+        assert_eq!(sorted(cached), sorted(fresh));
+    }
+
+    #[test]
+    fn test_incremental() {
+        let rules = vec![
+            rule(Extract::HistoryByNum(3)),
+            rule(Extract::HistoryByTime(2500)),
+        ];
+        let cache = Cache::new();
+        let mut history = History::new();
+        push(&mut history, 1000, &[("a", 1), ("b", 10)]);
+        for (i, &(ts, ref keys)) in [
+            (2000, vec![("a", 2), ("b", 20)]),
+            (3000, vec![("a", 3)]),
+            (4000, vec![("a", 4), ("c", 40)]),
+            (5000, vec![("b", 50), ("c", 50)]),
+        ].iter().enumerate() {
+            for rule in &rules {
+                check(&cache, rule, &history);
+            }
+            push(&mut history, ts, keys);
+            if i == 2 {
+                history.fine.truncate_by_num(3);
+            }
+        }
+        for rule in &rules {
+            check(&cache, rule, &history);
+        }
+        let state = cache.lock();
+        assert_eq!(state.points, state.entries.values()
+            .map(|e| e.points).sum::<usize>());
+    }
+
+    #[test]
+    fn test_expire() {
+        let cache = Cache::new();
+        let history = History::new();
+        cache.query(&rule(Extract::HistoryByNum(3)), &history,
+                    &mut Budget::unlimited());
+        cache.expire();
+        assert_eq!(cache.lock().entries.len(), 1);
+        cache.expire();
+        assert_eq!(cache.lock().entries.len(), 0);
+    }
+}
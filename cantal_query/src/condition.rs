@@ -1,11 +1,15 @@
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
 use regex::Regex;
-use history::Key;
+use history::{Key, Index};
 
 /// A shim type to deserialize regex and hash it
 #[derive(Clone, Debug)]
 pub struct RegexWrap(Regex);
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Condition {
     Eq(String, String),
     NotEq(String, String),
@@ -14,6 +18,14 @@ pub enum Condition {
     Or(Box<Condition>, Box<Condition>),
     Not(Box<Condition>),
     Has(String),
+    /// Value of the field starts with the string
+    Prefix(String, String),
+    /// Value of the field is one of the strings
+    In(String, Vec<String>),
+    /// Value of the field is a number less than the one specified
+    Lt(String, f64),
+    /// Value of the field is a number greater than the one specified
+    Gt(String, f64),
 }
 
 probor_enum_encoder_decoder!(Condition {
@@ -24,6 +36,10 @@ probor_enum_encoder_decoder!(Condition {
     #4 Or(left #1, right #2),
     #5 Not(val #1),
     #6 Has(field #1),
+    #7 Prefix(left #1, right #2),
+    #8 In(left #1, right #2),
+    #9 Lt(left #1, right #2),
+    #10 Gt(left #1, right #2),
 });
 
 json_enum_decoder!(Condition {
@@ -34,25 +50,181 @@ json_enum_decoder!(Condition {
     Or(left, right),
     Not(val),
     Has(field),
+    Prefix(left, right),
+    In(left, right),
+    Lt(left, right),
+    Gt(left, right),
 });
 
+/// Number of key fields decoded on the stack, fields of larger keys
+/// are decoded into a vector
+const STACK_FIELDS: usize = 16;
+
+/// Fields of a key decoded once to evaluate all leaves of a condition
+struct Fields<'a> {
+    stack: [(&'a str, &'a str); STACK_FIELDS],
+    len: usize,
+    heap: Option<Vec<(&'a str, &'a str)>>,
+}
+
+impl<'a> Fields<'a> {
+    fn decode(key: &'a Key) -> Fields<'a> {
+        let mut fields = Fields {
+            stack: [("", ""); STACK_FIELDS],
+            len: 0,
+            heap: None,
+        };
+        for pair in key.iter_pairs() {
+            if let Some(ref mut heap) = fields.heap {
+                heap.push(pair);
+                continue;
+            }
+            if fields.len < STACK_FIELDS {
+                fields.stack[fields.len] = pair;
+                fields.len += 1;
+            } else {
+                let mut heap = fields.stack.to_vec();
+                heap.push(pair);
+                fields.heap = Some(heap);
+            }
+        }
+        fields
+    }
+    fn get(&self, name: &str) -> Option<&'a str> {
+        let pairs = match self.heap {
+            Some(ref heap) => &heap[..],
+            None => &self.stack[..self.len],
+        };
+        pairs.iter().find(|&&(k, _)| k == name).map(|&(_, v)| v)
+    }
+    fn number(&self, name: &str) -> Option<f64> {
+        self.get(name).and_then(|x| x.parse().ok())
+    }
+}
+
 impl Condition {
+    /// Checks whether key matches the condition
+    ///
+    /// The key is decoded once for all the fields used in the condition,
+    /// and unless the key is very large this doesn't allocate
     pub fn matches(&self, key: &Key) -> bool {
+        self.matches_fields(&Fields::decode(key))
+    }
+    fn matches_fields(&self, fields: &Fields) -> bool {
         use self::Condition::*;
         match self {
             &Eq(ref name, ref value) => {
-                key.get_with(name, |x| x == value).unwrap_or(false)
-            },
+                fields.get(name).map(|x| x == value).unwrap_or(false)
+            }
             &NotEq(ref name, ref value) => {
-                key.get_with(name, |x| x != value).unwrap_or(false)
+                fields.get(name).map(|x| x != value).unwrap_or(false)
             }
             &RegexLike(ref name, ref regex) => {
-                key.get_with(name, |x| regex.is_match(x)).unwrap_or(false)
+                fields.get(name).map(|x| regex.is_match(x)).unwrap_or(false)
+            }
+            &And(ref a, ref b) => {
+                a.matches_fields(fields) && b.matches_fields(fields)
+            }
+            &Or(ref a, ref b) => {
+                a.matches_fields(fields) || b.matches_fields(fields)
+            }
+            &Not(ref x) => !x.matches_fields(fields),
+            &Has(ref name) => fields.get(name).is_some(),
+            &Prefix(ref name, ref prefix) => {
+                fields.get(name).map(|x| x.starts_with(&prefix[..]))
+                .unwrap_or(false)
+            }
+            &In(ref name, ref values) => {
+                fields.get(name).map(|x| values.iter().any(|v| v == x))
+                .unwrap_or(false)
+            }
+            &Lt(ref name, value) => {
+                fields.number(name).map(|x| x < value).unwrap_or(false)
+            }
+            &Gt(ref name, value) => {
+                fields.number(name).map(|x| x > value).unwrap_or(false)
+            }
+        }
+    }
+    /// Returns keys that may match the condition according to the index
+    ///
+    /// Returned set is a superset of matching keys, so `matches` must
+    /// still be checked for every key. `None` means that index can't
+    /// be used for this condition and all the keys must be checked.
+    pub fn candidates<'x>(&self, index: &'x Index)
+        -> Option<HashSet<&'x Key>>
+    {
+        use self::Condition::*;
+        match self {
+            &Eq(ref name, ref value) => {
+                Some(index.get(name, value)
+                    .map(|keys| keys.iter().collect())
+                    .unwrap_or_else(HashSet::new))
+            }
+            &Has(ref name) => {
+                Some(index.values(name)
+                    .map(|values| values.values()
+                        .flat_map(|x| x.iter()).collect())
+                    .unwrap_or_else(HashSet::new))
+            }
+            &Prefix(ref name, ref prefix) => {
+                Some(index.prefixed(name, prefix).into_iter()
+                    .flat_map(|x| x).collect())
+            }
+            &And(ref a, ref b) => {
+                match (a.candidates(index), b.candidates(index)) {
+                    (Some(a), Some(b)) => Some(a.intersection(&b)
+                        .cloned().collect()),
+                    (Some(x), None) | (None, Some(x)) => Some(x),
+                    (None, None) => None,
+                }
+            }
+            &Or(ref a, ref b) => {
+                match (a.candidates(index), b.candidates(index)) {
+                    (Some(mut a), Some(b)) => {
+                        a.extend(b);
+                        Some(a)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+// NaN in numeric comparison never matches anything, the only effect is
+// that such condition is not equal to itself
+impl Eq for Condition {}
+
+impl Hash for Condition {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use self::Condition::*;
+        mem::discriminant(self).hash(state);
+        match *self {
+            Eq(ref name, ref value) | NotEq(ref name, ref value) |
+            Prefix(ref name, ref value) => {
+                name.hash(state);
+                value.hash(state);
+            }
+            RegexLike(ref name, ref regex) => {
+                name.hash(state);
+                regex.hash(state);
+            }
+            And(ref a, ref b) | Or(ref a, ref b) => {
+                a.hash(state);
+                b.hash(state);
+            }
+            Not(ref x) => x.hash(state),
+            Has(ref name) => name.hash(state),
+            In(ref name, ref values) => {
+                name.hash(state);
+                values.hash(state);
+            }
+            Lt(ref name, value) | Gt(ref name, value) => {
+                name.hash(state);
+                value.to_bits().hash(state);
             }
-            &And(ref a, ref b) => a.matches(key) && b.matches(key),
-            &Or(ref a, ref b) => a.matches(key) || b.matches(key),
-            &Not(ref x) => !x.matches(key),
-            &Has(ref name) => key.get_with(name, |_| ()).is_some(),
         }
     }
 }
@@ -86,6 +258,12 @@ mod regex_wrap {
 
     impl Eq for RegexWrap { }
 
+    impl From<Regex> for RegexWrap {
+        fn from(regex: Regex) -> RegexWrap {
+            RegexWrap(regex)
+        }
+    }
+
     impl probor::Encodable for RegexWrap {
         fn encode<W:probor::Output>(&self, e: &mut probor::Encoder<W>)
             -> Result<(), probor::EncodeError>
@@ -116,3 +294,80 @@ mod regex_wrap {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Index};
+    use Condition;
+    use Condition::*;
+
+    fn key() -> Key {
+        Key::pairs(&[("cgroup", "lithos.web"), ("pid", "123"),
+                     ("port", "8080")])
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert!(Prefix("cgroup".into(), "lithos.".into()).matches(&key()));
+        assert!(!Prefix("cgroup".into(), "web".into()).matches(&key()));
+        assert!(!Prefix("zoo".into(), "".into()).matches(&key()));
+    }
+
+    #[test]
+    fn test_in() {
+        let cond = In("pid".into(), vec!["1".into(), "123".into()]);
+        assert!(cond.matches(&key()));
+        assert!(!In("pid".into(), vec!["1".into()]).matches(&key()));
+        assert!(!In("pid".into(), vec![]).matches(&key()));
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert!(Gt("port".into(), 1023.).matches(&key()));
+        assert!(!Lt("port".into(), 1024.).matches(&key()));
+        assert!(!Lt("port".into(), 8080.).matches(&key()));
+        // non-numeric values never match
+        assert!(!Lt("cgroup".into(), 1e10).matches(&key()));
+        assert!(!Gt("cgroup".into(), -1e10).matches(&key()));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let keys = vec![key(), Key::from_pair("pid", "1"), Key::empty()];
+        let index = Index::from_keys(keys.iter());
+        let num = |cond: Condition| cond.candidates(&index).map(|x| x.len());
+        assert_eq!(num(Has("pid".into())), Some(2));
+        assert_eq!(num(Eq("pid".into(), "1".into())), Some(1));
+        assert_eq!(num(Prefix("cgroup".into(), "lithos".into())), Some(1));
+        assert_eq!(num(Lt("pid".into(), 10.)), None);
+        assert_eq!(num(And(Box::new(Has("pid".into())),
+                           Box::new(Lt("pid".into(), 10.)))), Some(2));
+        assert_eq!(num(Or(Box::new(Has("pid".into())),
+                          Box::new(Lt("pid".into(), 10.)))), None);
+        assert_eq!(num(Not(Box::new(Has("pid".into())))), None);
+    }
+
+    #[test]
+    fn test_combined() {
+        let cond = And(
+            Box::new(Prefix("cgroup".into(), "lithos.".into())),
+            Box::new(Not(Box::new(Eq("pid".into(), "1".into())))));
+        assert!(cond.matches(&key()));
+        let cond = Or(
+            Box::new(Has("zoo".into())),
+            Box::new(NotEq("port".into(), "8080".into())));
+        assert!(!cond.matches(&key()));
+    }
+
+    #[test]
+    fn test_large_key() {
+        let names = (0..40).map(|i| format!("f{:02}", i)).collect::<Vec<_>>();
+        let pairs = names.iter().map(|n| (&n[..], &n[1..]))
+            .collect::<Vec<_>>();
+        let key = Key::pairs(&pairs);
+        assert!(Eq("f00".into(), "00".into()).matches(&key));
+        assert!(Eq("f39".into(), "39".into()).matches(&key));
+        assert!(Gt("f20".into(), 19.).matches(&key));
+        assert!(!Has("f40".into()).matches(&key));
+    }
+}
@@ -6,13 +6,26 @@ use values::Value;
 pub type TimeSlice = (TimeStamp, TimeStamp);
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Conflict {
     CantSumChart,
     Dissimilar,
     CantSumTimestamps,
     CantSumStates,
     CantDerive,
+    BadPercentile,
+    /// Values are not states, so can't be charted
+    CantChartValues,
+    /// Dataset doesn't match `Function::Expect`
+    Unexpected,
+    /// Resample step is zero or too small for the time range
+    BadStep,
+    /// Query exceeds `Limits::max_series`
+    TooManySeries,
+    /// Query exceeds `Limits::max_points`
+    TooManyPoints,
+    /// Query exceeds `Limits::max_time`
+    Timeout,
 }
 
 probor_enum_encoder_decoder!(Conflict {
@@ -21,9 +34,16 @@ probor_enum_encoder_decoder!(Conflict {
     #102 CantSumTimestamps(),
     #103 CantSumStates(),
     #104 CantDerive(),
+    #105 BadPercentile(),
+    #106 CantChartValues(),
+    #107 Unexpected(),
+    #108 BadStep(),
+    #109 TooManySeries(),
+    #110 TooManyPoints(),
+    #111 Timeout(),
 });
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Dataset {
     SingleSeries(Key, Chunk, Vec<TimeStamp>),
     MultiSeries(Vec<(Key, Chunk, Vec<TimeStamp>)>),
@@ -46,3 +66,58 @@ probor_enum_encoder_decoder!(Dataset {
     #999 Incompatible(reason #1),
 });
 
+
+impl Dataset {
+    /// Appends data extracted from an older history to this dataset
+    ///
+    /// Series are matched by key, and the points of the older series are
+    /// put after the newer ones (timestamps go from newest to oldest).
+    /// Caller must ensure that time ranges of the datasets don't overlap.
+    pub fn append_older(self, older: Dataset) -> Dataset {
+        use self::Dataset::*;
+        match (self, older) {
+            (Incompatible(x), _) | (_, Incompatible(x)) => Incompatible(x),
+            (x, Empty) | (Empty, x) => x,
+            (MultiSeries(mut newer), MultiSeries(older)) => {
+                let mut index = newer.iter().enumerate()
+                    .map(|(idx, &(ref key, _, _))| (key.clone(), idx))
+                    .collect::<HashMap<_, _>>();
+                for (key, chunk, timestamps) in older {
+                    if let Some(&idx) = index.get(&key) {
+                        let (_, ref mut nchunk, ref mut nts) = newer[idx];
+                        match append_chunk(nchunk, chunk) {
+                            Ok(true) => nts.extend(timestamps),
+                            Ok(false) => {}
+                            Err(()) => {
+                                debug!("Type of {:?} changed, \
+                                    skipping old data", key);
+                            }
+                        }
+                        continue;
+                    }
+                    index.insert(key.clone(), newer.len());
+                    newer.push((key, chunk, timestamps));
+                }
+                MultiSeries(newer)
+            }
+            // Tips are always taken from the newest history
+            (x @ SingleTip(..), SingleTip(..)) => x,
+            (x @ MultiTip(..), MultiTip(..)) => x,
+            _ => Incompatible(Conflict::Dissimilar),
+        }
+    }
+}
+
+/// Returns `Ok(true)` if timestamps of the older chunk must be appended too
+fn append_chunk(newer: &mut Chunk, older: Chunk) -> Result<bool, ()> {
+    use history::Chunk as C;
+    match (newer, older) {
+        (&mut C::Counter(ref mut a), C::Counter(b)) => a.extend(b),
+        (&mut C::Integer(ref mut a), C::Integer(b)) => a.extend(b),
+        (&mut C::Float(ref mut a), C::Float(b)) => a.extend(b),
+        // state chunk contains only latest value
+        (&mut C::State(_), C::State(_)) => return Ok(false),
+        _ => return Err(()),
+    }
+    return Ok(true);
+}
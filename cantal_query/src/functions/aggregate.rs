@@ -0,0 +1,495 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use num::traits::ToPrimitive;
+
+use history::{Key, Chunk, ChunkSet, ValueSet, TimeStamp};
+use values::Value;
+use {Dataset, Conflict, TimeSlice, Aggregation};
+
+
+/// Result of aggregation of values of type `T`
+pub(super) enum Output<T> {
+    /// Min and max keep the type of values
+    Same(T),
+    Float(f64),
+    Count(i64),
+}
+
+pub fn check(agg: &Aggregation) -> Result<(), Conflict> {
+    match *agg {
+        Aggregation::Percentile(p) if !(p >= 0. && p <= 100.) => {
+            Err(Conflict::BadPercentile)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(super) fn calc<T>(agg: &Aggregation, mut values: Vec<T>) -> Option<Output<T>>
+    where T: Copy + PartialOrd + ToPrimitive
+{
+    use Aggregation as A;
+    if let A::Count = *agg {
+        return Some(Output::Count(values.len() as i64));
+    }
+    if values.len() == 0 {
+        return None;
+    }
+    match *agg {
+        A::Min => values.into_iter()
+            .fold(None, |m, x| match m {
+                Some(m) if m <= x => Some(m),
+                _ => Some(x),
+            }).map(Output::Same),
+        A::Max => values.into_iter()
+            .fold(None, |m, x| match m {
+                Some(m) if m >= x => Some(m),
+                _ => Some(x),
+            }).map(Output::Same),
+        A::Avg => {
+            let sum: f64 = values.iter()
+                .map(|x| x.to_f64().unwrap_or(0.)).sum();
+            Some(Output::Float(sum / values.len() as f64))
+        }
+        A::Count => unreachable!(),
+        A::Percentile(p) => {
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            let rank = p / 100. * (values.len() - 1) as f64;
+            let low = values[rank.floor() as usize].to_f64().unwrap_or(0.);
+            let high = values[rank.ceil() as usize].to_f64().unwrap_or(0.);
+            Some(Output::Float(low + (high - low) * rank.fract()))
+        }
+    }
+}
+
+fn to_value<T, W: FnOnce(T) -> Value>(out: Output<T>, wrap: W) -> Value {
+    match out {
+        Output::Same(x) => wrap(x),
+        Output::Float(x) => Value::Float(x),
+        Output::Count(x) => Value::Integer(x),
+    }
+}
+
+/// Converts per-timestamp results into a chunk, all the results have the
+/// same kind, which is defined by aggregation
+pub(super) fn to_chunk<T, W>(agg: &Aggregation,
+    results: Vec<Option<Output<T>>>, wrap: W)
+    -> Chunk
+    where W: FnOnce(Vec<Option<T>>) -> Chunk
+{
+    use Aggregation as A;
+    match *agg {
+        A::Min | A::Max => wrap(results.into_iter().map(|x| match x {
+            Some(Output::Same(x)) => Some(x),
+            _ => None,
+        }).collect()),
+        A::Count => Chunk::Integer(results.into_iter().map(|x| match x {
+            Some(Output::Count(x)) => Some(x),
+            _ => None,
+        }).collect()),
+        A::Avg | A::Percentile(_) => {
+            Chunk::Float(results.into_iter().map(|x| match x {
+                Some(Output::Float(x)) => Some(x),
+                _ => None,
+            }).collect())
+        }
+    }
+}
+
+fn aggregate_chunks<T, W>(agg: &Aggregation, lst: &[&Vec<Option<T>>],
+    data_points: usize, wrap: W)
+    -> Chunk
+    where T: Copy + PartialOrd + ToPrimitive,
+          W: FnOnce(Vec<Option<T>>) -> Chunk,
+{
+    let results = (0..data_points)
+        .map(|i| calc(agg, lst.iter()
+            .filter_map(|v| v.get(i).and_then(|x| *x))
+            .collect()))
+        .collect();
+    to_chunk(agg, results, wrap)
+}
+
+/// Aggregates values of all series at each timestamp
+///
+/// Same as for `Sum`, series must have equal timestamps
+pub fn across_series(agg: &Aggregation, src: Dataset) -> Dataset {
+    use Dataset::*;
+    if let Err(c) = check(agg) {
+        return Incompatible(c);
+    }
+    match src {
+        MultiSeries(vec) => {
+            if vec.len() == 0 {
+                Empty
+            } else {
+                match aggregate_series(agg, &vec) {
+                    Ok((k, v, t)) => SingleSeries(k, v, t),
+                    Err(c) => Incompatible(c),
+                }
+            }
+        }
+        SingleSeries(key, chunk, ts) => {
+            match aggregate_series(agg, &vec![(key, chunk, ts)]) {
+                Ok((k, v, t)) => SingleSeries(k, v, t),
+                Err(c) => Incompatible(c),
+            }
+        }
+        MultiTip(vec) => aggregate_tip(agg, vec),
+        SingleTip(key, value, tslice) => {
+            aggregate_tip(agg, vec![(key, value, tslice)])
+        }
+        src @ Incompatible(_) => src,
+        Chart(_) => Incompatible(Conflict::CantSumChart),
+        Empty => Empty,
+    }
+}
+
+fn aggregate_series(agg: &Aggregation, src: &Vec<(Key, Chunk, Vec<TimeStamp>)>)
+    -> Result<(Key, Chunk, Vec<TimeStamp>), Conflict>
+{
+    use history::ChunkSet as S;
+    use history::Chunk as C;
+    assert!(src.len() > 0);
+    let ts = src[0].2.clone();
+    for &(ref nkey, _, ref nts) in &src[1..] {
+        if &ts != nts {
+            error!("Incompatible timestamps: {:?} {:?} /// {:?} {:?}",
+                src[0].0, ts, nkey, nts);
+            return Err(Conflict::CantSumTimestamps);
+        }
+    }
+    let data_points = ts.len();
+    let chunk = match
+        ChunkSet::merge(src.iter().map(|&(_, ref chunk, _)| chunk))
+    {
+        S::Empty => unreachable!(),
+        S::Counters(lst) => aggregate_chunks(agg, &lst, data_points,
+                                             C::Counter),
+        S::Integers(lst) => aggregate_chunks(agg, &lst, data_points,
+                                             C::Integer),
+        S::Floats(lst) => aggregate_chunks(agg, &lst, data_points,
+                                           C::Float),
+        S::States(_) => return Err(Conflict::CantSumStates),
+        S::Conflict => return Err(Conflict::Dissimilar),
+    };
+    let key = if src.len() == 1 { src[0].0.clone() } else { Key::empty() };
+    Ok((key, chunk, ts))
+}
+
+fn aggregate_tip(agg: &Aggregation, src: Vec<(Key, Value, TimeSlice)>)
+    -> Dataset
+{
+    match aggregate_values(agg, &src) {
+        Ok(Some((value, tslice))) => {
+            let key = if src.len() == 1 {
+                src[0].0.clone()
+            } else {
+                Key::empty()
+            };
+            Dataset::SingleTip(key, value, tslice)
+        }
+        Ok(None) => Dataset::Empty,
+        Err(c) => Dataset::Incompatible(c),
+    }
+}
+
+fn aggregate_values(agg: &Aggregation, src: &[(Key, Value, TimeSlice)])
+    -> Result<Option<(Value, TimeSlice)>, Conflict>
+{
+    use history::ValueSet as S;
+    use values::Value as V;
+
+    if src.len() == 0 {
+        return Ok(None);
+    }
+    for &(_, _, ref nts) in &src[1..] {
+        if &src[0].2 != nts {
+            return Err(Conflict::CantSumTimestamps);
+        }
+    }
+    let value = match
+        ValueSet::merge(src.iter().map(|&(_, ref value, _)| value))
+    {
+        S::Empty => return Ok(None),
+        S::Counters(lst) => calc(agg, lst).map(|x| to_value(x, V::Counter)),
+        S::Integers(lst) => calc(agg, lst).map(|x| to_value(x, V::Integer)),
+        S::Floats(lst) => calc(agg, lst).map(|x| to_value(x, V::Float)),
+        S::States(_) => return Err(Conflict::CantSumStates),
+        S::Conflict => return Err(Conflict::Dissimilar),
+    };
+    Ok(value.map(|v| (v, src[0].2)))
+}
+
+/// Returns values of `fields` in the key, or `None` if any is missing
+fn group_of(key: &Key, fields: &[String]) -> Option<Vec<String>> {
+    fields.iter()
+        .map(|f| key.get_with(f, |x| x.to_string()))
+        .collect()
+}
+
+fn group_key(fields: &[String], values: &[String]) -> Key {
+    fields.iter().zip(values)
+        .fold(Key::empty(), |key, (f, v)| key.add_pair(f, v))
+}
+
+/// Groups series (or tips) by values of `fields` and aggregates each group
+///
+/// Resulting keys contain only grouping fields. Series that lack any of
+/// the fields are skipped, same as in `SumBy`.
+pub fn group_by(fields: &[String], agg: &Aggregation, src: Dataset)
+    -> Dataset
+{
+    use Dataset::*;
+    if let Err(c) = check(agg) {
+        return Incompatible(c);
+    }
+    match src {
+        SingleSeries(key, chunk, ts) => {
+            group_series(fields, agg, vec![(key, chunk, ts)])
+        }
+        MultiSeries(vec) => group_series(fields, agg, vec),
+        SingleTip(key, value, tslice) => {
+            group_tips(fields, agg, vec![(key, value, tslice)])
+        }
+        MultiTip(vec) => group_tips(fields, agg, vec),
+        src @ Incompatible(_) => src,
+        Chart(_) => Incompatible(Conflict::CantSumChart),
+        Empty => Empty,
+    }
+}
+
+fn group_series(fields: &[String], agg: &Aggregation,
+    src: Vec<(Key, Chunk, Vec<TimeStamp>)>)
+    -> Dataset
+{
+    let mut map = HashMap::new();
+    for item in src {
+        if let Some(group) = group_of(&item.0, fields) {
+            map.entry(group).or_insert_with(Vec::new).push(item);
+        }
+    }
+    let mut result = Vec::with_capacity(map.len());
+    for (group, vec) in map {
+        match aggregate_series(agg, &vec) {
+            Ok((_, chunk, ts)) => {
+                result.push((group_key(fields, &group), chunk, ts));
+            }
+            Err(c) => return Dataset::Incompatible(c),
+        }
+    }
+    Dataset::MultiSeries(result)
+}
+
+fn group_tips(fields: &[String], agg: &Aggregation,
+    src: Vec<(Key, Value, TimeSlice)>)
+    -> Dataset
+{
+    let mut map = HashMap::new();
+    for item in src {
+        if let Some(group) = group_of(&item.0, fields) {
+            map.entry(group).or_insert_with(Vec::new).push(item);
+        }
+    }
+    let mut result = Vec::with_capacity(map.len());
+    for (group, vec) in map {
+        match aggregate_values(agg, &vec) {
+            Ok(Some((value, tslice))) => {
+                result.push((group_key(fields, &group), value, tslice));
+            }
+            Ok(None) => {}
+            Err(c) => return Dataset::Incompatible(c),
+        }
+    }
+    Dataset::MultiTip(result)
+}
+
+/// Aggregates values of each series over the whole time range
+///
+/// Each series is converted into a tip with the time slice covering the
+/// range of the series. Series that have no values are skipped.
+pub fn over_time(agg: &Aggregation, src: Dataset) -> Dataset {
+    use Dataset::*;
+    if let Err(c) = check(agg) {
+        return Incompatible(c);
+    }
+    match src {
+        MultiSeries(vec) => {
+            let mut result = Vec::with_capacity(vec.len());
+            for (key, chunk, ts) in vec {
+                match series_over_time(agg, chunk, &ts) {
+                    Ok(Some((value, tslice))) => {
+                        result.push((key, value, tslice));
+                    }
+                    Ok(None) => {}
+                    Err(c) => return Incompatible(c),
+                }
+            }
+            MultiTip(result)
+        }
+        SingleSeries(key, chunk, ts) => {
+            match series_over_time(agg, chunk, &ts) {
+                Ok(Some((value, tslice))) => SingleTip(key, value, tslice),
+                Ok(None) => Empty,
+                Err(c) => Incompatible(c),
+            }
+        }
+        MultiTip(vec) => {
+            let mut result = Vec::with_capacity(vec.len());
+            for (key, value, tslice) in vec {
+                match tip_over_time(agg, value) {
+                    Ok(value) => result.push((key, value, tslice)),
+                    Err(c) => return Incompatible(c),
+                }
+            }
+            MultiTip(result)
+        }
+        SingleTip(key, value, tslice) => {
+            match tip_over_time(agg, value) {
+                Ok(value) => SingleTip(key, value, tslice),
+                Err(c) => Incompatible(c),
+            }
+        }
+        src @ Incompatible(_) => src,
+        Chart(_) => Incompatible(Conflict::CantSumChart),
+        Empty => Empty,
+    }
+}
+
+fn present<T>(vec: Vec<Option<T>>) -> Vec<T> {
+    vec.into_iter().filter_map(|x| x).collect()
+}
+
+fn series_over_time(agg: &Aggregation, chunk: Chunk, ts: &[TimeStamp])
+    -> Result<Option<(Value, TimeSlice)>, Conflict>
+{
+    use history::Chunk as C;
+    use values::Value as V;
+    // timestamps go from newest to oldest
+    let tslice = match (ts.first(), ts.last()) {
+        (Some(&newest), Some(&oldest)) => (newest, oldest),
+        _ => return Ok(None),
+    };
+    let value = match chunk {
+        C::State(_) => return Err(Conflict::CantSumStates),
+        C::Counter(vec) => calc(agg, present(vec))
+            .map(|x| to_value(x, V::Counter)),
+        C::Integer(vec) => calc(agg, present(vec))
+            .map(|x| to_value(x, V::Integer)),
+        C::Float(vec) => calc(agg, present(vec))
+            .map(|x| to_value(x, V::Float)),
+    };
+    Ok(value.map(|v| (v, tslice)))
+}
+
+/// Tip is aggregated as a series of single value
+fn tip_over_time(agg: &Aggregation, value: Value) -> Result<Value, Conflict> {
+    use values::Value as V;
+    let result = match value {
+        V::State(_) => return Err(Conflict::CantSumStates),
+        V::Counter(x) => calc(agg, vec![x]).map(|x| to_value(x, V::Counter)),
+        V::Integer(x) => calc(agg, vec![x]).map(|x| to_value(x, V::Integer)),
+        V::Float(x) => calc(agg, vec![x]).map(|x| to_value(x, V::Float)),
+    };
+    Ok(result.expect("single value is always aggregated"))
+}
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Chunk};
+    use values::Value;
+    use {Dataset, Aggregation};
+    use super::{across_series, over_time, group_by};
+
+    fn series() -> Dataset {
+        Dataset::MultiSeries(vec![
+            (Key::metric("a"), Chunk::Integer(vec![Some(1), Some(4), None]),
+                vec![3000, 2000, 1000]),
+            (Key::metric("b"), Chunk::Integer(vec![Some(3), None, None]),
+                vec![3000, 2000, 1000]),
+        ])
+    }
+
+    fn across(agg: Aggregation) -> String {
+        match across_series(&agg, series()) {
+            Dataset::SingleSeries(_, chunk, _) => format!("{:?}", chunk),
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_across() {
+        use Aggregation::*;
+        assert_eq!(across(Min), "Integer([Some(1), Some(4), None])");
+        assert_eq!(across(Max), "Integer([Some(3), Some(4), None])");
+        assert_eq!(across(Avg), "Float([Some(2.0), Some(4.0), None])");
+        assert_eq!(across(Count), "Integer([Some(2), Some(1), Some(0)])");
+        assert_eq!(across(Percentile(50.)),
+            "Float([Some(2.0), Some(4.0), None])");
+    }
+
+    #[test]
+    fn test_percentile() {
+        let data = Dataset::SingleSeries(Key::metric("x"),
+            Chunk::Float((1..11).map(|x| Some(x as f64)).collect()),
+            (1..11).rev().collect());
+        match over_time(&Aggregation::Percentile(95.), data) {
+            Dataset::SingleTip(_, Value::Float(x), (10, 1)) => {
+                assert!((x - 9.55).abs() < 1e-9);
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_over_time() {
+        match over_time(&Aggregation::Max, series()) {
+            Dataset::MultiTip(ref vec) => {
+                assert_eq!(vec.len(), 2);
+                assert_eq!(format!("{:?}", vec[0].1), "Integer(4)");
+                assert_eq!(vec[0].2, (3000, 1000));
+                assert_eq!(format!("{:?}", vec[1].1), "Integer(3)");
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_bad_percentile() {
+        match across_series(&Aggregation::Percentile(101.), series()) {
+            Dataset::Incompatible(_) => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_group_by() {
+        let tip = |cgroup: &str, metric: &str, pid: &str, val: i64| {
+            (Key::pairs(&[("cgroup", cgroup), ("metric", metric),
+                          ("pid", pid)]),
+             Value::Integer(val), (1000, 1000))
+        };
+        let src = Dataset::MultiTip(vec![
+            tip("a", "rss", "1", 10),
+            tip("a", "rss", "2", 30),
+            tip("a", "vsize", "1", 50),
+            tip("b", "rss", "3", 20),
+        ]);
+        let fields = vec![String::from("cgroup"), String::from("metric")];
+        match group_by(&fields, &Aggregation::Max, src) {
+            Dataset::MultiTip(vec) => {
+                let mut items = vec.iter()
+                    .map(|&(ref k, ref v, _)| {
+                        format!("{} {:?}", k.to_json(), v)
+                    }).collect::<Vec<_>>();
+                items.sort();
+                assert_eq!(items, vec![
+                    r#"{"cgroup":"a","metric":"rss"} Integer(30)"#,
+                    r#"{"cgroup":"a","metric":"vsize"} Integer(50)"#,
+                    r#"{"cgroup":"b","metric":"rss"} Integer(20)"#,
+                ]);
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+}
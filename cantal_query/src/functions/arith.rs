@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use num::traits::ToPrimitive;
+
+use history::{Key, Chunk, TimeStamp};
+use values::Value;
+use {Dataset, Conflict, BinaryOp};
+
+
+fn apply(op: BinaryOp, a: f64, b: f64) -> Option<f64> {
+    match op {
+        BinaryOp::Add => Some(a + b),
+        BinaryOp::Sub => Some(a - b),
+        BinaryOp::Mul => Some(a * b),
+        BinaryOp::Div if b == 0. => None,
+        BinaryOp::Div => Some(a / b),
+    }
+}
+
+fn chunk_floats(chunk: Chunk) -> Result<Vec<Option<f64>>, Conflict> {
+    fn conv<T: ToPrimitive>(vec: Vec<Option<T>>) -> Vec<Option<f64>> {
+        vec.into_iter().map(|x| x.and_then(|x| x.to_f64())).collect()
+    }
+    match chunk {
+        Chunk::State(_) => Err(Conflict::CantSumStates),
+        Chunk::Counter(vec) => Ok(conv(vec)),
+        Chunk::Integer(vec) => Ok(conv(vec)),
+        Chunk::Float(vec) => Ok(vec),
+    }
+}
+
+fn value_float(value: Value) -> Result<f64, Conflict> {
+    match value {
+        Value::State(_) => Err(Conflict::CantSumStates),
+        Value::Counter(x) => Ok(x as f64),
+        Value::Integer(x) => Ok(x as f64),
+        Value::Float(x) => Ok(x),
+    }
+}
+
+/// Splits items into ones having `field` equal to `left` and `right`
+///
+/// Items are keyed by the rest of the key. Other items are dropped.
+fn split<T>(field: &str, left: &str, right: &str, items: Vec<(Key, T)>)
+    -> (HashMap<Key, T>, HashMap<Key, T>)
+{
+    let mut lmap = HashMap::new();
+    let mut rmap = HashMap::new();
+    for (key, item) in items {
+        match key.get_with(field, |x| (x == left, x == right)) {
+            Some((true, _)) => {
+                lmap.insert(key.remove_pair(field), item);
+            }
+            Some((_, true)) => {
+                rmap.insert(key.remove_pair(field), item);
+            }
+            _ => {}
+        }
+    }
+    (lmap, rmap)
+}
+
+/// Applies `op` to pairs of series matched by all the fields except
+/// `field`, where the left one has `field=left` and the right one has
+/// `field=right`
+///
+/// Resulting keys lack the `field`. Series that have no pair are
+/// skipped. Result is always a float, division by zero yields no value.
+pub fn binary(op: BinaryOp, field: &str, left: &str, right: &str,
+    src: Dataset)
+    -> Dataset
+{
+    use Dataset::*;
+    match src {
+        SingleSeries(key, chunk, ts) => {
+            binary_series(op, field, left, right, vec![(key, chunk, ts)])
+        }
+        MultiSeries(vec) => binary_series(op, field, left, right, vec),
+        SingleTip(key, value, tslice) => {
+            binary_tips(op, field, left, right, vec![(key, value, tslice)])
+        }
+        MultiTip(vec) => binary_tips(op, field, left, right, vec),
+        Chart(_) => Incompatible(Conflict::CantSumChart),
+        src @ Incompatible(_) => src,
+        Empty => Empty,
+    }
+}
+
+fn binary_series(op: BinaryOp, field: &str, left: &str, right: &str,
+    src: Vec<(Key, Chunk, Vec<TimeStamp>)>)
+    -> Dataset
+{
+    let items = src.into_iter()
+        .map(|(key, chunk, ts)| (key, (chunk, ts)))
+        .collect();
+    let (lmap, mut rmap) = split(field, left, right, items);
+    let mut result = Vec::with_capacity(lmap.len());
+    for (key, (lchunk, lts)) in lmap {
+        let (rchunk, rts) = match rmap.remove(&key) {
+            Some(x) => x,
+            None => continue,
+        };
+        if lts != rts {
+            error!("Incompatible timestamps: {:?} {:?} /// {:?}",
+                key, lts, rts);
+            return Dataset::Incompatible(Conflict::CantSumTimestamps);
+        }
+        let lvals = match chunk_floats(lchunk) {
+            Ok(x) => x,
+            Err(c) => return Dataset::Incompatible(c),
+        };
+        let rvals = match chunk_floats(rchunk) {
+            Ok(x) => x,
+            Err(c) => return Dataset::Incompatible(c),
+        };
+        let values = lvals.into_iter().zip(rvals).map(|pair| match pair {
+            (Some(a), Some(b)) => apply(op, a, b),
+            _ => None,
+        }).collect();
+        result.push((key, Chunk::Float(values), lts));
+    }
+    Dataset::MultiSeries(result)
+}
+
+fn binary_tips(op: BinaryOp, field: &str, left: &str, right: &str,
+    src: Vec<(Key, Value, (TimeStamp, TimeStamp))>)
+    -> Dataset
+{
+    let items = src.into_iter()
+        .map(|(key, value, tslice)| (key, (value, tslice)))
+        .collect();
+    let (lmap, mut rmap) = split(field, left, right, items);
+    let mut result = Vec::with_capacity(lmap.len());
+    for (key, (lvalue, tslice)) in lmap {
+        let (rvalue, _) = match rmap.remove(&key) {
+            Some(x) => x,
+            None => continue,
+        };
+        let a = match value_float(lvalue) {
+            Ok(x) => x,
+            Err(c) => return Dataset::Incompatible(c),
+        };
+        let b = match value_float(rvalue) {
+            Ok(x) => x,
+            Err(c) => return Dataset::Incompatible(c),
+        };
+        if let Some(value) = apply(op, a, b) {
+            result.push((key, Value::Float(value), tslice));
+        }
+    }
+    Dataset::MultiTip(result)
+}
+
+/// Multiplies all the values by `factor`, result is always a float
+pub fn scale(factor: f64, src: Dataset) -> Dataset {
+    use Dataset::*;
+    fn scale_chunk(factor: f64, chunk: Chunk) -> Result<Chunk, Conflict> {
+        chunk_floats(chunk).map(|vec| Chunk::Float(vec.into_iter()
+            .map(|x| x.map(|x| x * factor))
+            .collect()))
+    }
+    match src {
+        SingleSeries(key, chunk, ts) => match scale_chunk(factor, chunk) {
+            Ok(chunk) => SingleSeries(key, chunk, ts),
+            Err(c) => Incompatible(c),
+        },
+        MultiSeries(vec) => {
+            let mut result = Vec::with_capacity(vec.len());
+            for (key, chunk, ts) in vec {
+                match scale_chunk(factor, chunk) {
+                    Ok(chunk) => result.push((key, chunk, ts)),
+                    Err(c) => return Incompatible(c),
+                }
+            }
+            MultiSeries(result)
+        }
+        SingleTip(key, value, tslice) => match value_float(value) {
+            Ok(x) => SingleTip(key, Value::Float(x * factor), tslice),
+            Err(c) => Incompatible(c),
+        },
+        MultiTip(vec) => {
+            let mut result = Vec::with_capacity(vec.len());
+            for (key, value, tslice) in vec {
+                match value_float(value) {
+                    Ok(x) => result.push((key, Value::Float(x * factor),
+                                          tslice)),
+                    Err(c) => return Incompatible(c),
+                }
+            }
+            MultiTip(result)
+        }
+        Chart(_) => Incompatible(Conflict::CantSumChart),
+        src @ Incompatible(_) => src,
+        Empty => Empty,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Chunk};
+    use values::Value;
+    use {Dataset, BinaryOp};
+    use super::{binary, scale};
+
+    fn series(metric: &str, pid: &str, values: Vec<Option<u64>>)
+        -> (Key, Chunk, Vec<u64>)
+    {
+        (Key::pairs(&[("metric", metric), ("pid", pid)]),
+         Chunk::Counter(values), vec![2000, 1000])
+    }
+
+    #[test]
+    fn test_ratio() {
+        let src = Dataset::MultiSeries(vec![
+            series("requests.duration", "1", vec![Some(100), Some(60)]),
+            series("requests.number", "1", vec![Some(10), Some(0)]),
+            series("requests.duration", "2", vec![Some(5), None]),
+            series("requests.number", "3", vec![Some(1), Some(1)]),
+        ]);
+        let result = binary(BinaryOp::Div, "metric",
+            "requests.duration", "requests.number", src);
+        match result {
+            Dataset::MultiSeries(vec) => {
+                assert_eq!(vec.len(), 1);
+                assert_eq!(vec[0].0, Key::from_pair("pid", "1"));
+                assert_eq!(format!("{:?}", vec[0].1),
+                           "Float([Some(10.0), None])");
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_tips() {
+        let src = Dataset::MultiTip(vec![
+            (Key::metric("memory.MemAvailable"), Value::Integer(1024),
+             (1000, 1000)),
+            (Key::metric("memory.MemTotal"), Value::Integer(4096),
+             (1000, 1000)),
+        ]);
+        let result = binary(BinaryOp::Div, "metric",
+            "memory.MemAvailable", "memory.MemTotal", src);
+        match scale(100., result) {
+            Dataset::MultiTip(vec) => {
+                assert_eq!(vec.len(), 1);
+                assert_eq!(vec[0].0, Key::empty());
+                assert_eq!(format!("{:?}", vec[0].1), "Float(25.0)");
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+}
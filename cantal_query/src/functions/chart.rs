@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use history::Chunk;
+use values::Value;
+use {Dataset, Conflict};
+
+
+/// Name of the bucket that holds all the states not in the top
+const OTHER: &'static str = "other";
+
+
+/// Counts how many keys are in each state
+///
+/// Only `limit` most frequent states are kept, the rest are summed up in
+/// the `"other"` bucket. Note: if there is a state named `"other"` in the
+/// top it's merged with the bucket.
+pub fn state_chart(limit: usize, src: Dataset) -> Dataset {
+    use Dataset::*;
+    let mut counts = HashMap::new();
+    match src {
+        SingleSeries(_, chunk, _) => {
+            if let Err(c) = count_chunk(&mut counts, chunk) {
+                return Incompatible(c);
+            }
+        }
+        MultiSeries(vec) => {
+            for (_, chunk, _) in vec {
+                if let Err(c) = count_chunk(&mut counts, chunk) {
+                    return Incompatible(c);
+                }
+            }
+        }
+        SingleTip(_, value, _) => {
+            if let Err(c) = count_value(&mut counts, value) {
+                return Incompatible(c);
+            }
+        }
+        MultiTip(vec) => {
+            for (_, value, _) in vec {
+                if let Err(c) = count_value(&mut counts, value) {
+                    return Incompatible(c);
+                }
+            }
+        }
+        Chart(chart) => counts = chart,
+        src @ Incompatible(_) => return src,
+        Empty => return Empty,
+    }
+    Chart(top(counts, limit))
+}
+
+fn count_value(counts: &mut HashMap<String, usize>, value: Value)
+    -> Result<(), Conflict>
+{
+    match value {
+        Value::State((_, state)) => {
+            *counts.entry(state).or_insert(0) += 1;
+            Ok(())
+        }
+        _ => Err(Conflict::CantChartValues),
+    }
+}
+
+fn count_chunk(counts: &mut HashMap<String, usize>, chunk: Chunk)
+    -> Result<(), Conflict>
+{
+    match chunk {
+        // state chunk contains only the latest value
+        Chunk::State((_, state)) => {
+            *counts.entry(state).or_insert(0) += 1;
+            Ok(())
+        }
+        _ => Err(Conflict::CantChartValues),
+    }
+}
+
+fn top(counts: HashMap<String, usize>, limit: usize)
+    -> HashMap<String, usize>
+{
+    if counts.len() <= limit {
+        return counts;
+    }
+    let mut items = counts.into_iter().collect::<Vec<_>>();
+    // Sort by name too, so that result is stable when counts are equal
+    items.sort_by(|&(ref a, na), &(ref b, nb)| nb.cmp(&na).then(a.cmp(b)));
+    let rest = items.split_off(limit);
+    let mut result = items.into_iter().collect::<HashMap<_, _>>();
+    *result.entry(OTHER.to_string()).or_insert(0) +=
+        rest.iter().map(|&(_, n)| n).sum::<usize>();
+    return result;
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use history::Key;
+    use values::Value;
+    use {Dataset, Conflict};
+    use super::state_chart;
+
+    fn tips(states: &[&str]) -> Dataset {
+        Dataset::MultiTip(states.iter().enumerate().map(|(i, s)| {
+            (Key::pairs(&[("pid", &i.to_string()), ("metric", "state")]),
+             Value::State((1000, s.to_string())),
+             (1000, 1000))
+        }).collect())
+    }
+
+    fn chart(limit: usize, src: Dataset) -> Vec<(String, usize)> {
+        match state_chart(limit, src) {
+            Dataset::Chart(map) => {
+                let mut items = map.into_iter().collect::<Vec<_>>();
+                items.sort();
+                items
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_all() {
+        assert_eq!(chart(10, tips(&["idle", "run", "idle"])), vec![
+            (String::from("idle"), 2),
+            (String::from("run"), 1),
+        ]);
+    }
+
+    #[test]
+    fn test_other() {
+        let src = tips(&["idle", "run", "idle", "sleep", "zombie", "run",
+                         "idle"]);
+        assert_eq!(chart(2, src), vec![
+            (String::from("idle"), 3),
+            (String::from("other"), 2),
+            (String::from("run"), 2),
+        ]);
+    }
+
+    #[test]
+    fn test_relimit() {
+        let mut map = HashMap::new();
+        map.insert(String::from("a"), 1);
+        map.insert(String::from("b"), 5);
+        map.insert(String::from("other"), 3);
+        assert_eq!(chart(1, Dataset::Chart(map)), vec![
+            (String::from("b"), 5),
+            (String::from("other"), 4),
+        ]);
+    }
+
+    #[test]
+    fn test_numbers() {
+        let src = Dataset::SingleTip(Key::metric("x"), Value::Integer(1),
+                                     (1000, 1000));
+        match state_chart(10, src) {
+            Dataset::Incompatible(Conflict::CantChartValues) => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+}
@@ -1,5 +1,3 @@
-use std::ops::Sub;
-
 use num::traits::ToPrimitive;
 
 use history::{Chunk, TimeStamp};
@@ -26,7 +24,7 @@ pub fn non_negative_derivative(src: Dataset) -> Dataset {
     }
 }
 
-fn derive_vec<T:Sub<T, Output=T>+Copy+ToPrimitive>(
+fn derive_vec<T:Copy+ToPrimitive>(
     vec: Vec<Option<T>>, timestamps: Vec<TimeStamp>)
     -> (Chunk, Vec<TimeStamp>)
 {
@@ -35,7 +33,14 @@ fn derive_vec<T:Sub<T, Output=T>+Copy+ToPrimitive>(
     let (nval, ts) = first.zip(second).map(|((a, &ta), (b, &tb))|
         match (a, b) {
             (&Some(a), &Some(b)) => {
-                (Some((a - b).to_f64().unwrap() * 1000. / (ta - tb) as f64), ta)
+                // Subtract as floats, so that counter reset doesn't
+                // underflow. Negative values (i.e. resets) are skipped
+                let diff = a.to_f64().unwrap() - b.to_f64().unwrap();
+                if diff >= 0. && ta > tb {
+                    (Some(diff * 1000. / (ta - tb) as f64), ta)
+                } else {
+                    (None, ta)
+                }
             }
             _ => (None, ta),
         }
@@ -54,3 +59,25 @@ fn derive_series(chunk: Chunk, timestamps: Vec<TimeStamp>)
         Float(items) => derive_vec(items, timestamps),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Chunk};
+    use Dataset;
+    use super::non_negative_derivative;
+
+    #[test]
+    fn test_counter_reset() {
+        let src = Dataset::SingleSeries(Key::metric("x"),
+            Chunk::Counter(vec![Some(5), Some(100), Some(90)]),
+            vec![3000, 2000, 1000]);
+        match non_negative_derivative(src) {
+            Dataset::SingleSeries(_, chunk, ts) => {
+                assert_eq!(format!("{:?}", chunk),
+                           "Float([None, Some(10.0)])");
+                assert_eq!(ts, vec![3000, 2000]);
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use history::Chunk;
+use values::Value;
+use {Dataset, Conflict, MetricKind, Expectation};
+
+
+fn chunk_kind(chunk: &Chunk) -> MetricKind {
+    match *chunk {
+        Chunk::State(_) => MetricKind::State,
+        Chunk::Counter(_) => MetricKind::Counter,
+        Chunk::Integer(_) | Chunk::Float(_) => MetricKind::Level,
+    }
+}
+
+fn value_kind(value: &Value) -> MetricKind {
+    match *value {
+        Value::State(_) => MetricKind::State,
+        Value::Counter(_) => MetricKind::Counter,
+        Value::Integer(_) | Value::Float(_) => MetricKind::Level,
+    }
+}
+
+/// Checks that dataset has expected shape and kind of values
+///
+/// Empty and incompatible datasets are passed through as is.
+pub fn expect(exp: &Expectation, src: Dataset) -> Dataset {
+    use Dataset as D;
+    use Expectation as E;
+    let matches = match (exp, &src) {
+        (_, &D::Empty) | (_, &D::Incompatible(_)) => true,
+        (&E::SingleSeries(ref kind), &D::SingleSeries(_, ref chunk, _)) => {
+            chunk_kind(chunk) == *kind
+        }
+        (&E::MultiSeries(ref kind), &D::MultiSeries(ref vec)) => {
+            vec.iter().all(|&(_, ref chunk, _)| chunk_kind(chunk) == *kind)
+        }
+        (&E::SingleTip(ref kind), &D::SingleTip(_, ref value, _)) => {
+            value_kind(value) == *kind
+        }
+        (&E::MultiTip(ref kind), &D::MultiTip(ref vec)) => {
+            vec.iter().all(|&(_, ref value, _)| value_kind(value) == *kind)
+        }
+        (&E::Chart, &D::Chart(_)) => true,
+        _ => false,
+    };
+    if matches {
+        src
+    } else {
+        debug!("Expected {:?}, got {:?}", exp, src);
+        D::Incompatible(Conflict::Unexpected)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Chunk};
+    use {Dataset, Conflict, MetricKind, Expectation};
+    use super::expect;
+
+    fn series(chunk: Chunk) -> Dataset {
+        Dataset::SingleSeries(Key::metric("x"), chunk, vec![2000, 1000])
+    }
+
+    #[test]
+    fn test_matches() {
+        let exp = Expectation::SingleSeries(MetricKind::Level);
+        match expect(&exp, series(Chunk::Float(vec![Some(1.), None]))) {
+            Dataset::SingleSeries(..) => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+        match expect(&exp, Dataset::Empty) {
+            Dataset::Empty => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_wrong_kind() {
+        let exp = Expectation::SingleSeries(MetricKind::Counter);
+        match expect(&exp, series(Chunk::Integer(vec![Some(1), None]))) {
+            Dataset::Incompatible(Conflict::Unexpected) => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_wrong_shape() {
+        let exp = Expectation::MultiSeries(MetricKind::Level);
+        match expect(&exp, series(Chunk::Integer(vec![Some(1), None]))) {
+            Dataset::Incompatible(Conflict::Unexpected) => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+}
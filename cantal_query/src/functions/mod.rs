@@ -1,5 +1,12 @@
 mod sum;
 mod derive;
+mod aggregate;
+mod chart;
+mod expect;
+mod rate;
+mod resample;
+mod arith;
+mod topk;
 
 use {Function, Dataset, UndefFilter};
 
@@ -9,12 +16,25 @@ impl Function {
     pub fn exec(d: Dataset, func: &Function) -> Dataset {
         use Function::*;
         match func {
-            &Expect(_) => unimplemented!(),
+            &Expect(ref exp) => expect::expect(exp, d),
             &NonNegativeDerivative => derive::non_negative_derivative(d),
             &Sum(UndefFilter::Ignore) => sum::sum(d),
             &SumBy(ref key, UndefFilter::Ignore, total)
             =>  sum::sum_by(&key, total, d),
-            &StateChart(_num) => unimplemented!(),
+            &StateChart(num) => chart::state_chart(num, d),
+            &Aggregate(ref agg) => aggregate::across_series(agg, d),
+            &AggregateOverTime(ref agg) => aggregate::over_time(agg, d),
+            &GroupBy(ref fields, ref agg)
+            => aggregate::group_by(fields, agg, d),
+            &Rate(interpolate) => rate::rate(interpolate, d),
+            &Increase(interpolate) => rate::increase(interpolate, d),
+            &Delta(interpolate) => rate::delta(interpolate, d),
+            &Resample(step, ref agg) => resample::resample(step, agg, d),
+            &Binary(op, ref field, ref left, ref right)
+            => arith::binary(op, field, left, right, d),
+            &Scale(factor) => arith::scale(factor, d),
+            &TopK(num, ranking) => topk::top_k(num, ranking, false, d),
+            &BottomK(num, ranking) => topk::top_k(num, ranking, true, d),
         }
     }
 }
@@ -0,0 +1,206 @@
+use num::traits::ToPrimitive;
+
+use history::{Chunk, TimeStamp};
+use {Dataset, Conflict};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Per-second increase of a counter
+    Rate,
+    /// Increase of a counter over each interval
+    Increase,
+    /// Difference between values, may be negative
+    Delta,
+}
+
+pub fn rate(interpolate: bool, src: Dataset) -> Dataset {
+    derive(Mode::Rate, interpolate, src)
+}
+
+pub fn increase(interpolate: bool, src: Dataset) -> Dataset {
+    derive(Mode::Increase, interpolate, src)
+}
+
+pub fn delta(interpolate: bool, src: Dataset) -> Dataset {
+    derive(Mode::Delta, interpolate, src)
+}
+
+fn derive(mode: Mode, interpolate: bool, src: Dataset) -> Dataset {
+    use Dataset::*;
+    match src {
+        MultiSeries(vec) => {
+            let mut result = Vec::with_capacity(vec.len());
+            for (key, chunk, ts) in vec {
+                match derive_series(mode, interpolate, chunk, ts) {
+                    Some((nval, nts)) => result.push((key, nval, nts)),
+                    None => return Incompatible(Conflict::CantDerive),
+                }
+            }
+            MultiSeries(result)
+        }
+        SingleSeries(key, chunk, ts) => {
+            match derive_series(mode, interpolate, chunk, ts) {
+                Some((nval, nts)) => SingleSeries(key, nval, nts),
+                None => Incompatible(Conflict::CantDerive),
+            }
+        }
+        SingleTip(..) | MultiTip(_) | Chart(_) => {
+            Incompatible(Conflict::CantDerive)
+        }
+        Incompatible(x) => Incompatible(x),
+        Empty => Empty,
+    }
+}
+
+/// Returns `None` for states
+fn derive_series(mode: Mode, interp: bool, chunk: Chunk,
+    timestamps: Vec<TimeStamp>)
+    -> Option<(Chunk, Vec<TimeStamp>)>
+{
+    use history::Chunk::*;
+    match chunk {
+        State(_) => None,
+        Counter(items) => Some(derive_vec(mode, interp, items, timestamps)),
+        Integer(items) => Some(derive_vec(mode, interp, items, timestamps)),
+        Float(items) => Some(derive_vec(mode, interp, items, timestamps)),
+    }
+}
+
+/// Change of value from `older` to `newer`
+fn change(mode: Mode, newer: f64, older: f64) -> f64 {
+    match mode {
+        Mode::Delta => newer - older,
+        // Counter went down, so the process was restarted. Assume the
+        // counter started from zero
+        Mode::Rate | Mode::Increase if newer < older => newer,
+        Mode::Rate | Mode::Increase => newer - older,
+    }
+}
+
+/// Calculates a value for each interval between adjacent timestamps
+///
+/// Value is put at the timestamp of the newer point of the interval. If
+/// either point is missing the value is `None`, unless `interpolate` is
+/// set. In the latter case change between the nearest known points is
+/// spread over the intervals in between proportionally to their length.
+fn derive_vec<T: Copy + ToPrimitive>(mode: Mode, interpolate: bool,
+    vec: Vec<Option<T>>, mut timestamps: Vec<TimeStamp>)
+    -> (Chunk, Vec<TimeStamp>)
+{
+    let num = vec.len().min(timestamps.len());
+    if num < 2 {
+        return (Chunk::Float(Vec::new()), Vec::new());
+    }
+    let values = vec.iter()
+        .map(|x| x.and_then(|x| x.to_f64()))
+        .collect::<Vec<_>>();
+    let mut result = vec![None; num - 1];
+    let mut newer: Option<(usize, f64)> = None;
+    for (idx, value) in values[..num].iter().enumerate() {
+        let value = match *value {
+            Some(x) => x,
+            None => continue,
+        };
+        if let Some((nidx, nvalue)) = newer {
+            let span = timestamps[nidx].saturating_sub(timestamps[idx]);
+            if (idx == nidx + 1 || interpolate) && span > 0 {
+                let diff = change(mode, nvalue, value);
+                for i in nidx..idx {
+                    let interval = timestamps[i]
+                        .saturating_sub(timestamps[i+1]);
+                    result[i] = Some(match mode {
+                        Mode::Rate => diff * 1000. / span as f64,
+                        Mode::Increase | Mode::Delta => {
+                            diff * interval as f64 / span as f64
+                        }
+                    });
+                }
+            }
+        }
+        newer = Some((idx, value));
+    }
+    timestamps.truncate(num - 1);
+    (Chunk::Float(result), timestamps)
+}
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Chunk};
+    use Dataset;
+    use super::{rate, increase, delta};
+
+    fn counter(values: Vec<Option<u64>>) -> Dataset {
+        let ts = (0..values.len() as u64).rev()
+            .map(|x| 1000 + x * 1000).collect();
+        Dataset::SingleSeries(Key::metric("x"), Chunk::Counter(values), ts)
+    }
+
+    fn check(result: Dataset) -> String {
+        match result {
+            Dataset::SingleSeries(_, chunk, ts) => {
+                format!("{:?} {:?}", chunk, ts)
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_restart() {
+        // newest first: counter restarted between 3000 and 4000
+        let data = || counter(vec![Some(5), Some(100), Some(90), Some(80)]);
+        assert_eq!(check(rate(false, data())),
+            "Float([Some(5.0), Some(10.0), Some(10.0)]) [4000, 3000, 2000]");
+        assert_eq!(check(increase(false, data())),
+            "Float([Some(5.0), Some(10.0), Some(10.0)]) [4000, 3000, 2000]");
+        assert_eq!(check(delta(false, data())),
+            "Float([Some(-95.0), Some(10.0), Some(10.0)]) \
+             [4000, 3000, 2000]");
+    }
+
+    #[test]
+    fn test_restart_to_zero() {
+        let data = counter(vec![Some(3), Some(0), Some(70)]);
+        assert_eq!(check(increase(false, data)),
+            "Float([Some(3.0), Some(0.0)]) [3000, 2000]");
+    }
+
+    #[test]
+    fn test_gap() {
+        let data = || counter(vec![Some(30), None, Some(10), None]);
+        assert_eq!(check(rate(false, data())),
+            "Float([None, None, None]) [4000, 3000, 2000]");
+        assert_eq!(check(rate(true, data())),
+            "Float([Some(10.0), Some(10.0), None]) [4000, 3000, 2000]");
+        assert_eq!(check(increase(true, data())),
+            "Float([Some(10.0), Some(10.0), None]) [4000, 3000, 2000]");
+    }
+
+    #[test]
+    fn test_restart_in_gap() {
+        let data = Dataset::SingleSeries(Key::metric("x"),
+            Chunk::Counter(vec![Some(4), None, Some(50)]),
+            vec![4000, 3000, 0]);
+        assert_eq!(check(increase(true, data)),
+            "Float([Some(1.0), Some(3.0)]) [4000, 3000]");
+    }
+
+    #[test]
+    fn test_levels() {
+        let data = Dataset::SingleSeries(Key::metric("x"),
+            Chunk::Float(vec![Some(1.5), Some(2.5), None]),
+            vec![3000, 1000, 500]);
+        assert_eq!(check(delta(false, data)),
+            "Float([Some(-1.0), None]) [3000, 1000]");
+    }
+
+    #[test]
+    fn test_state() {
+        let data = Dataset::SingleSeries(Key::metric("x"),
+            Chunk::State((1000, "x".into())), vec![1000]);
+        match rate(false, data) {
+            Dataset::Incompatible(_) => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use num::traits::ToPrimitive;
+
+use history::{Chunk, TimeStamp, TimeDelta};
+use {Dataset, Conflict, Aggregation};
+use super::aggregate::{check, calc, to_chunk};
+
+
+/// Maximum number of points in the resampled series
+const MAX_POINTS: u64 = 100000;
+
+
+/// Aligns all the series to a common grid of `step` milliseconds
+///
+/// Values that fall into the same step are aggregated. Resulting
+/// timestamps are the starts of the steps (multiples of `step`), newest
+/// first, and are the same for all the series, so they can be summed up.
+/// State series, tips and charts have no timeline and are left as is.
+pub fn resample(step: TimeDelta, agg: &Aggregation, src: Dataset)
+    -> Dataset
+{
+    use Dataset::*;
+    if let Err(c) = check(agg) {
+        return Incompatible(c);
+    }
+    if step == 0 {
+        return Incompatible(Conflict::BadStep);
+    }
+    let step = step as u64;
+    match src {
+        SingleSeries(key, chunk, ts) => {
+            let grid = match make_grid(step, Some(&ts).into_iter()) {
+                Ok(grid) => grid,
+                Err(c) => return Incompatible(c),
+            };
+            let (chunk, ts) = resample_series(step, agg, chunk, ts, &grid);
+            SingleSeries(key, chunk, ts)
+        }
+        MultiSeries(vec) => {
+            let grid = match make_grid(step,
+                vec.iter().map(|&(_, _, ref ts)| ts))
+            {
+                Ok(grid) => grid,
+                Err(c) => return Incompatible(c),
+            };
+            MultiSeries(vec.into_iter().map(|(key, chunk, ts)| {
+                let (chunk, ts) = resample_series(step, agg, chunk, ts,
+                                                  &grid);
+                (key, chunk, ts)
+            }).collect())
+        }
+        src @ SingleTip(..) => src,
+        src @ MultiTip(..) => src,
+        src @ Chart(..) => src,
+        src @ Incompatible(_) => src,
+        Empty => Empty,
+    }
+}
+
+/// Returns timestamps of the grid covering all the series, newest first
+fn make_grid<'x, I>(step: u64, series: I) -> Result<Vec<TimeStamp>, Conflict>
+    where I: Iterator<Item=&'x Vec<TimeStamp>>
+{
+    let mut range: Option<(TimeStamp, TimeStamp)> = None;
+    for &ts in series.flat_map(|x| x) {
+        range = Some(match range {
+            Some((newest, oldest)) => (newest.max(ts), oldest.min(ts)),
+            None => (ts, ts),
+        });
+    }
+    let (newest, oldest) = match range {
+        Some((newest, oldest)) => {
+            (newest - newest % step, oldest - oldest % step)
+        }
+        None => return Ok(Vec::new()),
+    };
+    let num = (newest - oldest) / step + 1;
+    if num > MAX_POINTS {
+        return Err(Conflict::BadStep);
+    }
+    Ok((0..num).map(|i| newest - i * step).collect())
+}
+
+fn resample_series(step: u64, agg: &Aggregation, chunk: Chunk,
+    ts: Vec<TimeStamp>, grid: &[TimeStamp])
+    -> (Chunk, Vec<TimeStamp>)
+{
+    use history::Chunk::*;
+    let chunk = match chunk {
+        State(x) => return (State(x), ts),
+        Counter(items) => bucket(step, agg, items, &ts, grid, Counter),
+        Integer(items) => bucket(step, agg, items, &ts, grid, Integer),
+        Float(items) => bucket(step, agg, items, &ts, grid, Float),
+    };
+    (chunk, grid.to_vec())
+}
+
+fn bucket<T, W>(step: u64, agg: &Aggregation, values: Vec<Option<T>>,
+    ts: &[TimeStamp], grid: &[TimeStamp], wrap: W)
+    -> Chunk
+    where T: Copy + PartialOrd + ToPrimitive,
+          W: FnOnce(Vec<Option<T>>) -> Chunk,
+{
+    let mut buckets = vec![Vec::new(); grid.len()];
+    if let Some(&newest) = grid.first() {
+        for (value, &tstamp) in values.into_iter().zip(ts) {
+            if let Some(value) = value {
+                let idx = (newest - (tstamp - tstamp % step)) / step;
+                buckets[idx as usize].push(value);
+            }
+        }
+    }
+    let results = buckets.into_iter().map(|b| calc(agg, b)).collect();
+    to_chunk(agg, results, wrap)
+}
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Chunk};
+    use {Dataset, Aggregation};
+    use super::resample;
+    use super::super::sum::sum;
+
+    #[test]
+    fn test_sum_misaligned() {
+        let src = Dataset::MultiSeries(vec![
+            (Key::metric("a"), Chunk::Integer(vec![Some(1), Some(2)]),
+                vec![3100, 2100]),
+            (Key::metric("b"), Chunk::Integer(vec![Some(10), Some(20),
+                                                   Some(30)]),
+                vec![3900, 2900, 1900]),
+        ]);
+        match sum(resample(1000, &Aggregation::Max, src)) {
+            Dataset::SingleSeries(_, chunk, ts) => {
+                assert_eq!(ts, vec![3000, 2000, 1000]);
+                assert_eq!(format!("{:?}", chunk),
+                           "Integer([Some(11), Some(22), Some(30)])");
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let src = Dataset::SingleSeries(Key::metric("a"),
+            Chunk::Float(vec![Some(4.), None, Some(2.), Some(1.)]),
+            vec![7000, 6000, 5000, 1000]);
+        match resample(5000, &Aggregation::Avg, src) {
+            Dataset::SingleSeries(_, chunk, ts) => {
+                assert_eq!(ts, vec![5000, 0]);
+                assert_eq!(format!("{:?}", chunk),
+                           "Float([Some(3.0), Some(1.0)])");
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_bad_step() {
+        let src = Dataset::SingleSeries(Key::metric("a"),
+            Chunk::Float(vec![Some(1.), Some(1.)]),
+            vec![1_000_000_000, 0]);
+        match resample(1, &Aggregation::Avg, src) {
+            Dataset::Incompatible(_) => {}
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+}
@@ -46,7 +46,21 @@ pub fn sum_by(by: &str, total: bool, src: Dataset) -> Dataset {
             }
             Err(c) => Incompatible(c),
         },
-        MultiTip(_) => unimplemented!(),
+        MultiTip(vec) => match sum_tips_by(by, vec) {
+            Ok(mut vec) => {
+                if total && vec.len() > 1 {
+                    match sum_values(&vec) {
+                        Ok(Some((value, tslice))) => {
+                            vec.push((Key::empty(), value, tslice));
+                        }
+                        Ok(None) => {}
+                        Err(e) => return Incompatible(e),
+                    }
+                }
+                MultiTip(vec)
+            }
+            Err(c) => Incompatible(c),
+        },
         src @ SingleSeries(_, _, _) => src,
         src @ SingleTip(_, _, _) => src,
         src @ Incompatible(_) => src,
@@ -55,6 +69,26 @@ pub fn sum_by(by: &str, total: bool, src: Dataset) -> Dataset {
     }
 }
 
+fn sum_tips_by(by: &str, vec: Vec<(Key, Value, TimeSlice)>)
+    -> Result<Vec<(Key, Value, TimeSlice)>, Conflict>
+{
+    let mut map = HashMap::new();
+    for (key, value, tslice) in vec.into_iter() {
+        key.get_with(by, |x| x.to_string()).map(|kstr| {
+            map.entry(kstr)
+                .or_insert_with(Vec::new)
+                .push((key, value, tslice));
+        });
+    }
+    let mut res = Vec::new();
+    for (key, vec) in map.into_iter() {
+        if let Some((value, tslice)) = try!(sum_values(&vec)) {
+            res.push((Key::from_pair(by, &key[..]), value, tslice));
+        }
+    }
+    return Ok(res);
+}
+
 fn sum_series_by(by: &str, vec: Vec<(Key, Chunk, Vec<TimeStamp>)>)
     -> Result<Vec<(Key, Chunk, Vec<TimeStamp>)>, Conflict>
 {
@@ -133,32 +167,80 @@ fn sum_iter<A:Add<A,Output=A>, I:Iterator<Item=A>>(mut iter: I) -> A {
 }
 
 fn sum_tip(mut src: Vec<(Key, Value, TimeSlice)>) -> Dataset {
+    if src.len() == 1 {
+        let (k, c, t) = src.pop().unwrap();
+        return Dataset::SingleTip(k, c, t);
+    }
+    match sum_values(&src) {
+        Ok(Some((value, tslice))) => {
+            Dataset::SingleTip(Key::empty(), value, tslice)
+        }
+        Ok(None) => Dataset::Empty,
+        Err(c) => Dataset::Incompatible(c),
+    }
+}
+
+fn sum_values(src: &[(Key, Value, TimeSlice)])
+    -> Result<Option<(Value, TimeSlice)>, Conflict>
+{
     use history::ValueSet as S;
     use values::Value as V;
 
     // For now assuming that timestamps are equal
     // for different timestamps we need more complex algo
     if src.len() == 0 {
-        return Dataset::Empty;
-    }
-    if src.len() == 1 {
-        let (k, c, t) = src.pop().unwrap();
-        return Dataset::SingleTip(k, c, t);
+        return Ok(None);
     }
     for &(_, _, ref nts) in &src[1..] {
         if &src[0].2 != nts {
-            return Dataset::Incompatible(Conflict::CantSumTimestamps);
+            return Err(Conflict::CantSumTimestamps);
         }
     }
     let value = match
         ValueSet::merge(src.iter().map(|&(_, ref chunk, _)| chunk))
     {
-        S::Empty => return Dataset::Empty,
+        S::Empty => return Ok(None),
         S::Counters(lst) => V::Counter(sum_iter(lst.into_iter())),
         S::Integers(lst) => V::Integer(sum_iter(lst.into_iter())),
         S::Floats(lst) => V::Float(sum_iter(lst.into_iter())),
-        S::States(_) => return Dataset::Incompatible(Conflict::CantSumStates),
-        S::Conflict => return Dataset::Incompatible(Conflict::Dissimilar),
+        S::States(_) => return Err(Conflict::CantSumStates),
+        S::Conflict => return Err(Conflict::Dissimilar),
     };
-    Dataset::SingleTip(Key::empty(), value, src.pop().unwrap().2)
+    Ok(Some((value, src[0].2)))
+}
+
+#[cfg(test)]
+mod test {
+    use history::Key;
+    use values::Value;
+    use Dataset;
+    use super::sum_by;
+
+    #[test]
+    fn test_sum_tips_by() {
+        let tip = |cgroup: &str, pid: &str, val: u64| {
+            (Key::pairs(&[("cgroup", cgroup), ("pid", pid)]),
+             Value::Counter(val), (1000, 900))
+        };
+        let src = Dataset::MultiTip(vec![
+            tip("a", "1", 1),
+            tip("a", "2", 2),
+            tip("b", "3", 5),
+        ]);
+        match sum_by("cgroup", true, src) {
+            Dataset::MultiTip(vec) => {
+                let mut items = vec.iter()
+                    .map(|&(ref k, ref v, _)| {
+                        format!("{} {:?}", k.to_json(), v)
+                    }).collect::<Vec<_>>();
+                items.sort();
+                assert_eq!(items, vec![
+                    r#"{"cgroup":"a"} Counter(3)"#,
+                    r#"{"cgroup":"b"} Counter(5)"#,
+                    r#"{} Counter(8)"#,
+                ]);
+            }
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
 }
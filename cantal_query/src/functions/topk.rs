@@ -0,0 +1,170 @@
+use std::cmp::Ordering;
+
+use num::traits::ToPrimitive;
+
+use history::Chunk;
+use values::Value;
+use {Dataset, Conflict, Ranking};
+
+
+fn rank_values<T: Copy + ToPrimitive>(ranking: Ranking, vec: &[Option<T>])
+    -> Option<f64>
+{
+    let mut values = vec.iter().filter_map(|x| x.and_then(|x| x.to_f64()));
+    match ranking {
+        // values go from newest to oldest
+        Ranking::Last => values.next(),
+        Ranking::Max => values.fold(None, |m, x| match m {
+            Some(m) if m >= x => Some(m),
+            _ => Some(x),
+        }),
+        Ranking::Avg => {
+            let (sum, num) = values.fold((0., 0), |(s, n), x| (s + x, n + 1));
+            if num > 0 {
+                Some(sum / num as f64)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn rank_chunk(ranking: Ranking, chunk: &Chunk) -> Result<Option<f64>, Conflict>
+{
+    match *chunk {
+        Chunk::State(_) => Err(Conflict::CantSumStates),
+        Chunk::Counter(ref vec) => Ok(rank_values(ranking, vec)),
+        Chunk::Integer(ref vec) => Ok(rank_values(ranking, vec)),
+        Chunk::Float(ref vec) => Ok(rank_values(ranking, vec)),
+    }
+}
+
+fn rank_value(value: &Value) -> Result<Option<f64>, Conflict> {
+    match *value {
+        Value::State(_) => Err(Conflict::CantSumStates),
+        Value::Counter(x) => Ok(Some(x as f64)),
+        Value::Integer(x) => Ok(Some(x as f64)),
+        Value::Float(x) => Ok(Some(x)),
+    }
+}
+
+/// Sorts items by rank and keeps first `num` of them
+///
+/// Items without a rank (no defined values) go last in both directions.
+fn select<T>(num: usize, bottom: bool, mut items: Vec<(Option<f64>, T)>)
+    -> Vec<T>
+{
+    items.sort_by(|&(a, _), &(b, _)| match (a, b) {
+        (Some(a), Some(b)) if bottom => {
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        (Some(a), Some(b)) => b.partial_cmp(&a).unwrap_or(Ordering::Equal),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+    items.truncate(num);
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Keeps `num` series with the largest (or smallest if `bottom` is true)
+/// ranking value, sorted by that value
+///
+/// For tips the value itself is used regardless of the ranking.
+pub fn top_k(num: usize, ranking: Ranking, bottom: bool, src: Dataset)
+    -> Dataset
+{
+    use Dataset::*;
+    match src {
+        SingleSeries(key, chunk, ts) => {
+            match rank_chunk(ranking, &chunk) {
+                Ok(_) if num == 0 => Empty,
+                Ok(_) => SingleSeries(key, chunk, ts),
+                Err(c) => Incompatible(c),
+            }
+        }
+        MultiSeries(vec) => {
+            let mut items = Vec::with_capacity(vec.len());
+            for item in vec {
+                match rank_chunk(ranking, &item.1) {
+                    Ok(rank) => items.push((rank, item)),
+                    Err(c) => return Incompatible(c),
+                }
+            }
+            MultiSeries(select(num, bottom, items))
+        }
+        SingleTip(key, value, tslice) => {
+            match rank_value(&value) {
+                Ok(_) if num == 0 => Empty,
+                Ok(_) => SingleTip(key, value, tslice),
+                Err(c) => Incompatible(c),
+            }
+        }
+        MultiTip(vec) => {
+            let mut items = Vec::with_capacity(vec.len());
+            for item in vec {
+                match rank_value(&item.1) {
+                    Ok(rank) => items.push((rank, item)),
+                    Err(c) => return Incompatible(c),
+                }
+            }
+            MultiTip(select(num, bottom, items))
+        }
+        Chart(_) => Incompatible(Conflict::CantSumChart),
+        src @ Incompatible(_) => src,
+        Empty => Empty,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use history::{Key, Chunk};
+    use {Dataset, Ranking};
+    use super::top_k;
+
+    fn dataset() -> Dataset {
+        Dataset::MultiSeries(vec![
+            (Key::from_pair("pid", "1"),
+             Chunk::Integer(vec![Some(10), Some(100), None]),
+             vec![3000, 2000, 1000]),
+            (Key::from_pair("pid", "2"),
+             Chunk::Integer(vec![None, Some(20), Some(30)]),
+             vec![3000, 2000, 1000]),
+            (Key::from_pair("pid", "3"),
+             Chunk::Integer(vec![None, None, None]),
+             vec![3000, 2000, 1000]),
+            (Key::from_pair("pid", "4"),
+             Chunk::Integer(vec![Some(50), Some(50), Some(50)]),
+             vec![3000, 2000, 1000]),
+        ])
+    }
+
+    fn pids(ds: Dataset) -> Vec<String> {
+        match ds {
+            Dataset::MultiSeries(vec) => vec.iter()
+                .map(|&(ref key, _, _)| key.get_with("pid", |x| x.to_string())
+                     .unwrap())
+                .collect(),
+            x => panic!("Wrong dataset {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_top() {
+        assert_eq!(pids(top_k(2, Ranking::Last, false, dataset())),
+                   vec!["4", "2"]);
+        assert_eq!(pids(top_k(2, Ranking::Max, false, dataset())),
+                   vec!["1", "4"]);
+        assert_eq!(pids(top_k(1, Ranking::Avg, false, dataset())),
+                   vec!["1"]);
+    }
+
+    #[test]
+    fn test_bottom() {
+        assert_eq!(pids(top_k(2, Ranking::Last, true, dataset())),
+                   vec!["1", "2"]);
+        // series without values go last
+        assert_eq!(pids(top_k(10, Ranking::Max, true, dataset())),
+                   vec!["2", "4", "1", "3"]);
+    }
+}
@@ -14,9 +14,17 @@ mod rule;
 mod dataset;
 mod query;
 mod functions;
+mod parser;
+mod cache;
+mod limits;
 
 pub use condition::Condition;
 pub use rule::{Source, Filter, Extract, Rule};
-pub use rule::{MetricKind, UndefFilter, Function};
+pub use rule::{MetricKind, UndefFilter, Function, Aggregation};
+pub use rule::{BinaryOp, Ranking};
+pub use rule::Expectation;
 pub use dataset::{Dataset, Conflict, TimeSlice};
-pub use query::query_history;
+pub use query::{query_history, query_series};
+pub use parser::{parse_rule, ParseError};
+pub use cache::Cache;
+pub use limits::{Limits, Cost, Budget, MAX_QUERY_SECONDS};
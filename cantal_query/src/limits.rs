@@ -0,0 +1,198 @@
+use std::cmp::min;
+use std::time::{Duration, Instant};
+use std::usize;
+
+use Conflict;
+
+
+/// Wall time is checked once in this number of series or keys scanned
+const TIME_CHECK_INTERVAL: usize = 64;
+
+/// Largest wall time limit of a query in seconds, larger limits are
+/// clamped to it
+///
+/// Keeps the deadline within the range of `Instant`
+pub const MAX_QUERY_SECONDS: u64 = 86400;
+
+
+/// Limits on the amount of work done by a single query
+///
+/// A query may consist of several rules, limits apply to all of them
+/// together.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of series (or tip values) extracted from history
+    pub max_series: usize,
+    /// Maximum number of data points extracted from history
+    pub max_points: usize,
+    /// Maximum wall time spent on the query
+    pub max_time: Option<Duration>,
+}
+
+/// Amount of work done by a query
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cost {
+    pub series: usize,
+    pub points: usize,
+}
+
+/// Limits and the cost of the query being executed
+#[derive(Debug)]
+pub struct Budget {
+    limits: Limits,
+    deadline: Option<Instant>,
+    cost: Cost,
+    /// Number of series and keys since wall time was checked
+    unchecked: usize,
+    exceeded: bool,
+}
+
+impl Limits {
+    pub fn unlimited() -> Limits {
+        Limits {
+            max_series: usize::MAX,
+            max_points: usize::MAX,
+            max_time: None,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::unlimited()
+    }
+}
+
+impl Budget {
+    /// Starts accounting a query, wall time is counted from now
+    pub fn new(limits: Limits) -> Budget {
+        Budget {
+            limits: limits,
+            deadline: limits.max_time
+                .map(|t| {
+                    let max = Duration::from_secs(MAX_QUERY_SECONDS);
+                    Instant::now() + min(t, max)
+                }),
+            cost: Cost::default(),
+            unchecked: 0,
+            exceeded: false,
+        }
+    }
+    pub fn unlimited() -> Budget {
+        Budget::new(Limits::unlimited())
+    }
+    /// Accounts a series (or a tip value) of `points` data points
+    pub fn add_series(&mut self, points: usize) -> Result<(), Conflict> {
+        self.cost.series += 1;
+        self.cost.points += points;
+        if self.cost.series > self.limits.max_series {
+            return self.exceed(Conflict::TooManySeries);
+        }
+        if self.cost.points > self.limits.max_points {
+            return self.exceed(Conflict::TooManyPoints);
+        }
+        self.tick()
+    }
+    /// Accounts a key checked against the condition of a rule
+    ///
+    /// Matching is cheap but there may be lots of keys, so wall time is
+    /// checked here too.
+    pub fn scan_key(&mut self) -> Result<(), Conflict> {
+        self.tick()
+    }
+    fn tick(&mut self) -> Result<(), Conflict> {
+        self.unchecked += 1;
+        if self.unchecked >= TIME_CHECK_INTERVAL {
+            self.unchecked = 0;
+            return self.check_time();
+        }
+        Ok(())
+    }
+    /// Returns an error if the query runs for too long
+    pub fn check_time(&mut self) -> Result<(), Conflict> {
+        match self.deadline {
+            Some(deadline) if Instant::now() > deadline => {
+                self.exceed(Conflict::Timeout)
+            }
+            _ => Ok(()),
+        }
+    }
+    fn exceed(&mut self, conflict: Conflict) -> Result<(), Conflict> {
+        self.exceeded = true;
+        Err(conflict)
+    }
+    /// Returns true if any of the limits was exceeded
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+    pub fn cost(&self) -> Cost {
+        self.cost
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{u64, usize};
+    use std::thread::sleep;
+    use std::time::Duration;
+    use Conflict;
+    use super::{Budget, Limits, TIME_CHECK_INTERVAL};
+
+    #[test]
+    fn test_series() {
+        let mut budget = Budget::new(Limits {
+            max_series: 2,
+            .. Limits::unlimited()
+        });
+        assert!(budget.add_series(10).is_ok());
+        assert!(budget.add_series(10).is_ok());
+        assert!(!budget.exceeded());
+        match budget.add_series(10) {
+            Err(Conflict::TooManySeries) => {}
+            x => panic!("Wrong result {:?}", x),
+        }
+        assert!(budget.exceeded());
+        assert_eq!(budget.cost().points, 30);
+    }
+
+    #[test]
+    fn test_points() {
+        let mut budget = Budget::new(Limits {
+            max_points: 15,
+            .. Limits::unlimited()
+        });
+        assert!(budget.add_series(10).is_ok());
+        match budget.add_series(10) {
+            Err(Conflict::TooManyPoints) => {}
+            x => panic!("Wrong result {:?}", x),
+        }
+        assert_eq!(Limits::unlimited().max_series, usize::MAX);
+    }
+
+    #[test]
+    fn test_time() {
+        let mut budget = Budget::new(Limits {
+            max_time: Some(Duration::new(0, 0)),
+            .. Limits::unlimited()
+        });
+        sleep(Duration::from_millis(1));
+        let mut result = Ok(());
+        for _ in 0..TIME_CHECK_INTERVAL {
+            result = budget.scan_key();
+        }
+        match result {
+            Err(Conflict::Timeout) => {}
+            x => panic!("Wrong result {:?}", x),
+        }
+        assert!(budget.exceeded());
+    }
+
+    #[test]
+    fn test_huge_time() {
+        let mut budget = Budget::new(Limits {
+            max_time: Some(Duration::new(u64::MAX, 0)),
+            .. Limits::unlimited()
+        });
+        assert!(budget.check_time().is_ok());
+    }
+}
@@ -0,0 +1,705 @@
+//! Textual query language
+//!
+//! Query is compiled into a `Rule`:
+//!
+//! ```text
+//! fine{metric="rss", cgroup=~"lithos.*"}[5m] | derivative | sum_by(cgroup)
+//! ```
+//!
+//! * Source is one of `tip`, `fine`, `coarse`
+//! * Conditions in braces are joined by "and": `field="value"`,
+//!   `field!="value"`, `field=~"regex"`, `field!~"regex"`, `field^="prefix"`,
+//!   `field in ("a", "b")`, `field<1024`, `field>1024` (value is compared
+//!   as a number), `field` (has field), `!field` (has no field). Regexes
+//!   are not anchored.
+//! * Optional range in brackets: `[5m]` (units are `ms`, `s`, `m`, `h`,
+//!   `d`), `[100]` (number of data points), `[diff 300]` or
+//!   `[1500000000000..1500000600000]` (milliseconds, both inclusive).
+//!   Without a range only the latest values are returned.
+//! * Functions are applied left to right: `derivative`, `rate`,
+//!   `increase`, `delta` (with optional `(interpolate)` argument), `sum`,
+//!   `sum_by(field)`, `sum_by(field, total)`, `state_chart(10)`,
+//!   `expect(multi_series, level)`, `expect(chart)`, aggregations
+//!   `min`, `max`, `avg`, `count`, `percentile(95)` and their
+//!   `*_over_time` variants, `group_by(max, field1, field2)`,
+//!   `resample(1m, avg)`, `top_k(10, max)` and `bottom_k(10, max)` (series
+//!   ranked by `last`, `max` or `avg`), `scale(100)` and binary operations
+//!   `add`, `sub`, `mul`, `div` taking a field and its values for the left
+//!   and right operand: `div(metric, "requests.duration", "requests.number")`.
+use std::fmt;
+use std::error::Error;
+
+use regex::Regex;
+
+use history::TimeDelta;
+use {Rule, Filter, Source, Extract, Condition, Function};
+use {UndefFilter, Aggregation, Expectation, MetricKind, BinaryOp};
+use Ranking;
+
+
+/// Error of parsing textual query
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Character position in the query, starting from 1
+    pub column: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Ident(&'a str),
+    Str(String),
+    /// Number with optional suffix, e.g. `5m`
+    Number(&'a str),
+    Punct(&'static str),
+    End,
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<(usize, Token<'a>)>,
+    idx: usize,
+}
+
+const PUNCT: &'static [&'static str] = &[
+    // longer ones go first
+    "!=", "=~", "!~", "^=", "..",
+    "{", "}", "[", "]", "(", ")", ",", "|", "=", "!", "<", ">",
+];
+
+/// Parses textual query into a rule
+pub fn parse_rule(text: &str) -> Result<Rule, ParseError> {
+    let mut parser = Parser {
+        text: text,
+        tokens: try!(tokenize(text)),
+        idx: 0,
+    };
+    parser.query()
+}
+
+fn column(text: &str, pos: usize) -> usize {
+    text[..pos].chars().count() + 1
+}
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut iter = text.char_indices().peekable();
+    'outer: while let Some(&(pos, ch)) = iter.peek() {
+        if ch.is_whitespace() {
+            iter.next();
+        } else if ch.is_ascii_alphabetic() || ch == '_' {
+            let mut end = text.len();
+            while let Some(&(idx, c)) = iter.peek() {
+                if !c.is_ascii_alphanumeric() && c != '_' {
+                    end = idx;
+                    break;
+                }
+                iter.next();
+            }
+            tokens.push((pos, Token::Ident(&text[pos..end])));
+        } else if ch.is_ascii_digit() {
+            let mut end = text.len();
+            while let Some(&(idx, c)) = iter.peek() {
+                let fraction = c == '.' &&
+                    text[idx+1..].starts_with(|c: char| c.is_ascii_digit());
+                if !c.is_ascii_alphanumeric() && !fraction {
+                    end = idx;
+                    break;
+                }
+                iter.next();
+            }
+            tokens.push((pos, Token::Number(&text[pos..end])));
+        } else if ch == '"' {
+            iter.next();
+            let mut value = String::new();
+            while let Some((idx, c)) = iter.next() {
+                match c {
+                    '"' => {
+                        tokens.push((pos, Token::Str(value)));
+                        continue 'outer;
+                    }
+                    '\\' => match iter.next() {
+                        Some((_, '"')) => value.push('"'),
+                        Some((_, '\\')) => value.push('\\'),
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 't')) => value.push('\t'),
+                        _ => return Err(ParseError {
+                            column: column(text, idx),
+                            message: "invalid escape sequence".into(),
+                        }),
+                    },
+                    _ => value.push(c),
+                }
+            }
+            return Err(ParseError {
+                column: column(text, pos),
+                message: "unterminated string".into(),
+            });
+        } else {
+            match PUNCT.iter().find(|p| text[pos..].starts_with(*p)) {
+                Some(&p) => {
+                    for _ in 0..p.len() {
+                        iter.next();
+                    }
+                    tokens.push((pos, Token::Punct(p)));
+                }
+                None => return Err(ParseError {
+                    column: column(text, pos),
+                    message: format!("unexpected character {:?}", ch),
+                }),
+            }
+        }
+    }
+    tokens.push((text.len(), Token::End));
+    return Ok(tokens);
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token<'a> {
+        &self.tokens[self.idx].1
+    }
+    fn error_at<T>(&self, idx: usize, message: String)
+        -> Result<T, ParseError>
+    {
+        Err(ParseError {
+            column: column(self.text, self.tokens[idx].0),
+            message: message,
+        })
+    }
+    fn unexpected<T>(&self, expected: &str) -> Result<T, ParseError> {
+        self.error_at(self.idx,
+            format!("expected {}, found {}", expected, self.peek()))
+    }
+    fn eat(&mut self, punct: &'static str) -> bool {
+        if *self.peek() == Token::Punct(punct) {
+            self.idx += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, punct: &'static str)
+        -> Result<(), ParseError>
+    {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("{:?}", punct))
+        }
+    }
+    fn ident(&mut self, expected: &str) -> Result<&'a str, ParseError> {
+        match *self.peek() {
+            Token::Ident(x) => {
+                self.idx += 1;
+                Ok(x)
+            }
+            _ => self.unexpected(expected),
+        }
+    }
+    /// Field name, either bare or quoted
+    fn field(&mut self) -> Result<String, ParseError> {
+        let field = match *self.peek() {
+            Token::Ident(x) => x.to_string(),
+            Token::Str(ref x) => x.clone(),
+            _ => return self.unexpected("field name"),
+        };
+        self.idx += 1;
+        Ok(field)
+    }
+    fn string(&mut self) -> Result<String, ParseError> {
+        match *self.peek() {
+            Token::Str(ref x) => {
+                let x = x.clone();
+                self.idx += 1;
+                Ok(x)
+            }
+            _ => self.unexpected("quoted string"),
+        }
+    }
+    fn number(&mut self) -> Result<&'a str, ParseError> {
+        match *self.peek() {
+            Token::Number(x) => {
+                self.idx += 1;
+                Ok(x)
+            }
+            _ => self.unexpected("number"),
+        }
+    }
+    fn float(&mut self) -> Result<f64, ParseError> {
+        let idx = self.idx;
+        let num = try!(self.number());
+        num.parse().or_else(|_| {
+            self.error_at(idx, format!("invalid number {:?}", num))
+        })
+    }
+    fn integer(&mut self) -> Result<u64, ParseError> {
+        let idx = self.idx;
+        let num = try!(self.number());
+        num.parse().or_else(|_| {
+            self.error_at(idx, format!("invalid integer {:?}", num))
+        })
+    }
+    fn query(&mut self) -> Result<Rule, ParseError> {
+        let source = match try!(self.ident("source")) {
+            "tip" => Source::Tip,
+            "fine" => Source::Fine,
+            "coarse" => Source::Coarse,
+            x => {
+                return self.error_at(self.idx-1, format!(
+                    "unknown source {:?}, expected tip, fine or coarse", x));
+            }
+        };
+        let condition = try!(self.conditions());
+        let extract = if self.eat("[") {
+            let extract = try!(self.range());
+            try!(self.expect("]"));
+            extract
+        } else {
+            Extract::Tip
+        };
+        let mut functions = Vec::new();
+        while self.eat("|") {
+            functions.push(try!(self.function()));
+        }
+        if *self.peek() != Token::End {
+            return self.unexpected("\"|\" or end of query");
+        }
+        Ok(Rule {
+            series: Filter {
+                source: source,
+                condition: condition,
+            },
+            extract: extract,
+            functions: functions,
+        })
+    }
+    fn conditions(&mut self) -> Result<Condition, ParseError> {
+        if !self.eat("{") {
+            return self.unexpected("\"{\"");
+        }
+        let mut result = try!(self.condition());
+        while self.eat(",") {
+            let cond = try!(self.condition());
+            result = Condition::And(Box::new(result), Box::new(cond));
+        }
+        try!(self.expect("}"));
+        return Ok(result);
+    }
+    fn condition(&mut self) -> Result<Condition, ParseError> {
+        use Condition::*;
+        if *self.peek() == Token::Punct("}") {
+            return self.unexpected("condition (at least one is required)");
+        }
+        if self.eat("!") {
+            return Ok(Not(Box::new(Has(try!(self.field())))));
+        }
+        let field = try!(self.field());
+        if self.eat("=") {
+            Ok(Eq(field, try!(self.string())))
+        } else if self.eat("!=") {
+            Ok(NotEq(field, try!(self.string())))
+        } else if self.eat("=~") {
+            Ok(RegexLike(field, try!(self.regex()).into()))
+        } else if self.eat("!~") {
+            Ok(Not(Box::new(RegexLike(field, try!(self.regex()).into()))))
+        } else if self.eat("^=") {
+            Ok(Prefix(field, try!(self.string())))
+        } else if self.eat("<") {
+            Ok(Lt(field, try!(self.float())))
+        } else if self.eat(">") {
+            Ok(Gt(field, try!(self.float())))
+        } else if *self.peek() == Token::Ident("in") {
+            self.idx += 1;
+            try!(self.expect("("));
+            let mut values = vec![try!(self.string())];
+            while self.eat(",") {
+                values.push(try!(self.string()));
+            }
+            try!(self.expect(")"));
+            Ok(In(field, values))
+        } else {
+            Ok(Has(field))
+        }
+    }
+    fn regex(&mut self) -> Result<Regex, ParseError> {
+        let idx = self.idx;
+        let text = try!(self.string());
+        Regex::new(&text).or_else(|e| {
+            self.error_at(idx, format!("invalid regex: {}", e))
+        })
+    }
+    fn range(&mut self) -> Result<Extract, ParseError> {
+        if let Token::Ident("diff") = *self.peek() {
+            self.idx += 1;
+            return Ok(Extract::DiffToAtMost(try!(self.integer()) as usize));
+        }
+        if let Token::Number(num) = *self.peek() {
+            if !num.contains(|c: char| c.is_ascii_alphabetic()) {
+                let value = try!(self.integer());
+                if self.eat("..") {
+                    let to = try!(self.integer());
+                    return Ok(Extract::TimeRange(value, to));
+                }
+                return Ok(Extract::HistoryByNum(value as usize));
+            }
+        }
+        Ok(Extract::HistoryByTime(try!(self.duration())))
+    }
+    /// Duration with a unit, returns milliseconds
+    fn duration(&mut self) -> Result<TimeDelta, ParseError> {
+        let idx = self.idx;
+        let num = try!(self.number());
+        let split = num.find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(num.len());
+        let (value, unit) = num.split_at(split);
+        let mult = match unit {
+            "ms" => 1.,
+            "s" => 1000.,
+            "m" => 60000.,
+            "h" => 3600000.,
+            "d" => 86400000.,
+            "" => return self.error_at(idx, format!(
+                "expected duration with a unit (e.g. 5m), found {}", num)),
+            _ => return self.error_at(idx, format!(
+                "unknown unit {:?}, expected one of ms, s, m, h, d", unit)),
+        };
+        match value.parse::<f64>() {
+            Ok(x) if x * mult <= TimeDelta::max_value() as f64 => {
+                Ok((x * mult) as TimeDelta)
+            }
+            Ok(_) => self.error_at(idx,
+                format!("duration {:?} is too large", num)),
+            Err(_) => self.error_at(idx,
+                format!("invalid duration {:?}", num)),
+        }
+    }
+    fn function(&mut self) -> Result<Function, ParseError> {
+        use Function::*;
+        let idx = self.idx;
+        let name = try!(self.ident("function name"));
+        let func = match name {
+            "derivative" | "non_negative_derivative" => NonNegativeDerivative,
+            "rate" => Rate(try!(self.interpolate())),
+            "increase" => Increase(try!(self.interpolate())),
+            "delta" => Delta(try!(self.interpolate())),
+            "sum" => Sum(UndefFilter::Ignore),
+            "sum_by" => {
+                try!(self.expect("("));
+                let field = try!(self.field());
+                let mut total = false;
+                if self.eat(",") {
+                    try!(self.ident("\"total\"").and_then(|x| match x {
+                        "total" => Ok(()),
+                        _ => self.error_at(self.idx-1,
+                            format!("expected \"total\", found {}", x)),
+                    }));
+                    total = true;
+                }
+                try!(self.expect(")"));
+                SumBy(field, UndefFilter::Ignore, total)
+            }
+            "resample" => {
+                try!(self.expect("("));
+                let step = try!(self.duration());
+                try!(self.expect(","));
+                let agg_name = try!(self.ident("aggregation"));
+                let agg = try!(self.aggregation(agg_name));
+                try!(self.expect(")"));
+                Resample(step, agg)
+            }
+            "add" | "sub" | "mul" | "div" => {
+                let op = match name {
+                    "add" => BinaryOp::Add,
+                    "sub" => BinaryOp::Sub,
+                    "mul" => BinaryOp::Mul,
+                    _ => BinaryOp::Div,
+                };
+                try!(self.expect("("));
+                let field = try!(self.field());
+                try!(self.expect(","));
+                let left = try!(self.string());
+                try!(self.expect(","));
+                let right = try!(self.string());
+                try!(self.expect(")"));
+                Binary(op, field, left, right)
+            }
+            "scale" => {
+                try!(self.expect("("));
+                let factor = try!(self.float());
+                try!(self.expect(")"));
+                Scale(factor)
+            }
+            "top_k" | "bottom_k" => {
+                try!(self.expect("("));
+                let num = try!(self.integer()) as usize;
+                try!(self.expect(","));
+                let idx = self.idx;
+                let ranking = match try!(self.ident("ranking")) {
+                    "last" => Ranking::Last,
+                    "max" => Ranking::Max,
+                    "avg" => Ranking::Avg,
+                    x => return self.error_at(idx, format!(
+                        "unknown ranking {:?}, expected one of \
+                         last, max, avg", x)),
+                };
+                try!(self.expect(")"));
+                if name == "top_k" {
+                    TopK(num, ranking)
+                } else {
+                    BottomK(num, ranking)
+                }
+            }
+            "state_chart" => {
+                try!(self.expect("("));
+                let num = try!(self.integer());
+                try!(self.expect(")"));
+                StateChart(num as usize)
+            }
+            "expect" => {
+                try!(self.expect("("));
+                let exp = try!(self.expectation());
+                try!(self.expect(")"));
+                Expect(exp)
+            }
+            "group_by" => {
+                try!(self.expect("("));
+                let agg_name = try!(self.ident("aggregation"));
+                let agg = try!(self.aggregation(agg_name));
+                let mut fields = Vec::new();
+                while self.eat(",") {
+                    fields.push(try!(self.field()));
+                }
+                if fields.is_empty() {
+                    return self.unexpected("\",\" and a field name");
+                }
+                try!(self.expect(")"));
+                GroupBy(fields, agg)
+            }
+            _ if name.ends_with("_over_time") => {
+                let agg_name = &name[..name.len() - "_over_time".len()];
+                AggregateOverTime(try!(self.aggregation(agg_name)))
+            }
+            "min" | "max" | "avg" | "count" | "percentile" => {
+                Aggregate(try!(self.aggregation(name)))
+            }
+            _ => {
+                return self.error_at(idx,
+                    format!("unknown function {:?}", name));
+            }
+        };
+        Ok(func)
+    }
+    /// Optional `(interpolate)` argument of rate-like functions
+    fn interpolate(&mut self) -> Result<bool, ParseError> {
+        if !self.eat("(") {
+            return Ok(false);
+        }
+        let idx = self.idx;
+        match try!(self.ident("\"interpolate\"")) {
+            "interpolate" => {}
+            x => return self.error_at(idx,
+                format!("expected \"interpolate\", found {}", x)),
+        }
+        try!(self.expect(")"));
+        Ok(true)
+    }
+    /// Parses arguments of the aggregation, the name is already parsed
+    fn aggregation(&mut self, name: &str) -> Result<Aggregation, ParseError> {
+        use Aggregation::*;
+        match name {
+            "min" => Ok(Min),
+            "max" => Ok(Max),
+            "avg" => Ok(Avg),
+            "count" => Ok(Count),
+            "percentile" => {
+                try!(self.expect("("));
+                let idx = self.idx;
+                let num = try!(self.number());
+                let value = match num.parse::<f64>() {
+                    Ok(x) if x >= 0. && x <= 100. => x,
+                    _ => return self.error_at(idx, format!(
+                        "percentile must be a number in range 0..100, \
+                         found {:?}", num)),
+                };
+                try!(self.expect(")"));
+                Ok(Percentile(value))
+            }
+            _ => self.error_at(self.idx-1, format!(
+                "unknown aggregation {:?}, expected one of \
+                 min, max, avg, count, percentile", name)),
+        }
+    }
+    fn expectation(&mut self) -> Result<Expectation, ParseError> {
+        use Expectation::*;
+        let idx = self.idx;
+        let shape = try!(self.ident("dataset shape"));
+        if shape == "chart" {
+            return Ok(Chart);
+        }
+        let constructor: fn(MetricKind) -> Expectation = match shape {
+            "single_series" => SingleSeries,
+            "multi_series" => MultiSeries,
+            "single_tip" => SingleTip,
+            "multi_tip" => MultiTip,
+            _ => return self.error_at(idx, format!(
+                "unknown dataset shape {:?}, expected one of single_series, \
+                 multi_series, single_tip, multi_tip, chart", shape)),
+        };
+        try!(self.expect(","));
+        let idx = self.idx;
+        let kind = match try!(self.ident("metric kind")) {
+            "counter" => MetricKind::Counter,
+            "level" => MetricKind::Level,
+            "state" => MetricKind::State,
+            x => return self.error_at(idx, format!(
+                "unknown metric kind {:?}, expected one of \
+                 counter, level, state", x)),
+        };
+        Ok(constructor(kind))
+    }
+}
+
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Ident(x) => write!(f, "{}", x),
+            Token::Str(ref x) => write!(f, "{:?}", x),
+            Token::Number(x) => write!(f, "{}", x),
+            Token::Punct(x) => write!(f, "{:?}", x),
+            Token::End => write!(f, "end of query"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use regex::Regex;
+    use {Rule, Filter, Source, Extract, Condition, Function};
+    use {UndefFilter, Aggregation, BinaryOp, Ranking};
+    use super::parse_rule;
+
+    fn err(text: &str) -> String {
+        parse_rule(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_example() {
+        use Condition::*;
+        assert_eq!(parse_rule(r#"
+            fine{metric="rss", cgroup=~"lithos.*"}[5m]
+            | derivative | sum_by(cgroup)
+            "#).unwrap(),
+            Rule {
+                series: Filter {
+                    source: Source::Fine,
+                    condition: And(
+                        Box::new(Eq("metric".into(), "rss".into())),
+                        Box::new(RegexLike("cgroup".into(),
+                            Regex::new("lithos.*").unwrap().into()))),
+                },
+                extract: Extract::HistoryByTime(300000),
+                functions: vec![
+                    Function::NonNegativeDerivative,
+                    Function::SumBy("cgroup".into(), UndefFilter::Ignore,
+                                    false),
+                ],
+            });
+    }
+
+    #[test]
+    fn test_tip() {
+        assert_eq!(parse_rule(r#"tip{!pid, state}"#).unwrap(), Rule {
+            series: Filter {
+                source: Source::Tip,
+                condition: Condition::And(
+                    Box::new(Condition::Not(Box::new(
+                        Condition::Has("pid".into())))),
+                    Box::new(Condition::Has("state".into()))),
+            },
+            extract: Extract::Tip,
+            functions: vec![],
+        });
+    }
+
+    #[test]
+    fn test_conditions() {
+        use Condition::*;
+        let cond = |text| parse_rule(text).unwrap().series.condition;
+        assert_eq!(cond(r#"tip{cgroup^="lithos."}"#),
+                   Prefix("cgroup".into(), "lithos.".into()));
+        assert_eq!(cond(r#"tip{pid in ("1", "2")}"#),
+                   In("pid".into(), vec!["1".into(), "2".into()]));
+        assert_eq!(cond(r#"tip{port>1023, port<65536}"#),
+                   And(Box::new(Gt("port".into(), 1023.)),
+                       Box::new(Lt("port".into(), 65536.))));
+    }
+
+    #[test]
+    fn test_ranges() {
+        let extract = |text| parse_rule(text).unwrap().extract;
+        assert_eq!(extract("fine{metric}[100]"), Extract::HistoryByNum(100));
+        assert_eq!(extract("fine{metric}[1.5s]"),
+                   Extract::HistoryByTime(1500));
+        assert_eq!(extract("fine{metric}[diff 30]"),
+                   Extract::DiffToAtMost(30));
+        assert_eq!(extract("coarse{metric}[1000..2000]"),
+                   Extract::TimeRange(1000, 2000));
+    }
+
+    #[test]
+    fn test_aggregations() {
+        use Function::*;
+        use Aggregation::*;
+        let funcs = |text| parse_rule(text).unwrap().functions;
+        assert_eq!(funcs("fine{a}[1h] | percentile(95) | max_over_time"),
+                   vec![Aggregate(Percentile(95.)),
+                        AggregateOverTime(Max)]);
+        assert_eq!(funcs("fine{a}[1h] | rate | increase(interpolate)"),
+                   vec![Rate(false), Increase(true)]);
+        assert_eq!(funcs("fine{a}[1h] | resample(1m, max) | sum"),
+                   vec![Resample(60000, Max), Sum(UndefFilter::Ignore)]);
+        assert_eq!(funcs(r#"tip{a} | group_by(avg, cgroup, "metric")"#),
+                   vec![GroupBy(vec!["cgroup".into(), "metric".into()],
+                                Avg)]);
+        assert_eq!(funcs(r#"tip{metric} | div(metric,
+                            "memory.MemAvailable", "memory.MemTotal")
+                          | scale(100)"#),
+                   vec![Binary(BinaryOp::Div, "metric".into(),
+                               "memory.MemAvailable".into(),
+                               "memory.MemTotal".into()),
+                        Scale(100.)]);
+        assert_eq!(funcs("fine{a}[5m] | top_k(10, avg) | bottom_k(2, last)"),
+                   vec![TopK(10, Ranking::Avg), BottomK(2, Ranking::Last)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(err("fine{metric=rss}"),
+            "expected quoted string, found rss at column 13");
+        assert_eq!(err("fine{}"),
+            "expected condition (at least one is required), \
+             found \"}\" at column 6");
+        assert_eq!(err("fine{a} | derivate"),
+            "unknown function \"derivate\" at column 11");
+        assert_eq!(err("fine{a}[5y]"),
+            "unknown unit \"y\", expected one of ms, s, m, h, d \
+             at column 9");
+        assert_eq!(err("fine{a=\"b}"), "unterminated string at column 8");
+        assert_eq!(err("fine{a} sum"),
+            "expected \"|\" or end of query, found sum at column 9");
+        assert_eq!(err("fine{a=~\"(\"}").starts_with("invalid regex"),
+                   true);
+    }
+}
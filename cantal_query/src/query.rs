@@ -1,50 +1,127 @@
-use history::{History, Value, Chunk, Backlog, TimeStamp};
+use history::{History, Key, Value, Chunk, Backlog, TimeStamp, TimeDelta};
 use values::Value as TipValue;
 
-use {Rule, Source, Dataset, Extract, Function, TimeSlice};
+use {Rule, Source, Dataset, Extract, Function, TimeSlice, Budget, Conflict};
 
-pub fn query_history(rule: &Rule, history: &History) -> Dataset {
-    let dset = match rule.series.source {
+/// Queries history and applies functions of the rule
+///
+/// If the query exceeds the limits of the budget, the
+/// `Dataset::Incompatible` is returned
+pub fn query_history(rule: &Rule, history: &History, budget: &mut Budget)
+    -> Dataset
+{
+    let series = query_series(rule, history, budget);
+    apply_functions(rule, series, budget)
+}
+
+/// Applies functions of the rule, checking wall time between them
+pub fn apply_functions(rule: &Rule, series: Dataset, budget: &mut Budget)
+    -> Dataset
+{
+    rule.functions.iter().fold(series, |dataset, func| {
+        match (dataset, budget.check_time()) {
+            (dataset @ Dataset::Incompatible(_), _) => dataset,
+            (_, Err(c)) => Dataset::Incompatible(c),
+            (dataset, Ok(())) => Function::exec(dataset, func),
+        }
+    })
+}
+
+/// Extracts series matching the rule without applying functions
+///
+/// This is useful to merge data from several histories (i.e. snapshots)
+/// before functions are applied.
+pub fn query_series(rule: &Rule, history: &History, budget: &mut Budget)
+    -> Dataset
+{
+    match rule.series.source {
         Source::Tip => {
             let mut result = Vec::new();
             // TODO(tailhook) do not duplicate keys and values
             for (key, &(ts, ref value)) in history.tip.values.iter() {
+                if let Err(c) = budget.scan_key() {
+                    return Dataset::Incompatible(c);
+                }
                 if rule.series.condition.matches(key) {
+                    if let Err(c) = budget.add_series(1) {
+                        return Dataset::Incompatible(c);
+                    }
                     result.push((key.clone(), value.clone(), (ts, ts)));
                 }
             }
             Dataset::MultiTip(result)
         }
-        Source::Fine if single_value(&rule.extract) => {
-            let mut result = Vec::new();
-            // TODO(tailhook) do not duplicate keys and values
-            for (key, value) in history.fine.values.iter() {
-                if rule.series.condition.matches(key) {
-                    extract_single(
-                        value, &history.fine, &rule.extract)
-                    .map(|(v, tslc)| result.push((key.clone(), v, tslc)));
-                    // TODO(tailhook) if extract_single returns None what we
-                    //                should do?
+        Source::Fine => query_backlog(rule, &history.fine, budget),
+        Source::Coarse => query_backlog(rule, &history.coarse, budget),
+    }
+}
+
+fn query_backlog(rule: &Rule, backlog: &Backlog, budget: &mut Budget)
+    -> Dataset
+{
+    // TODO(tailhook) do not duplicate keys and values
+    let matching = match matching_values(rule, backlog, budget) {
+        Ok(matching) => matching,
+        Err(c) => return Dataset::Incompatible(c),
+    };
+    if single_value(&rule.extract) {
+        let mut result = Vec::new();
+        for (key, value) in matching {
+            if let Some((v, tslc)) = extract_single(value, backlog,
+                                                    &rule.extract)
+            {
+                if let Err(c) = budget.add_series(1) {
+                    return Dataset::Incompatible(c);
                 }
+                result.push((key.clone(), v, tslc));
             }
-            Dataset::MultiTip(result)
+            // TODO(tailhook) if extract_single returns None what we
+            //                should do?
         }
-        Source::Fine => {
-            let mut result = Vec::new();
-            // TODO(tailhook) do not duplicate keys and values
-            for (key, value) in history.fine.values.iter() {
-                if rule.series.condition.matches(key) {
-                    extract_multi(
-                        value, &history.fine, &rule.extract)
-                    .map(|(v, t)| result.push((key.clone(), v, t)));
-                    // TODO(tailhook) if extract_multi returns None what we
-                    //                should do?
+        Dataset::MultiTip(result)
+    } else {
+        let mut result = Vec::new();
+        for (key, value) in matching {
+            if let Some((v, t)) = extract_multi(value, backlog, &rule.extract)
+            {
+                if let Err(c) = budget.add_series(t.len()) {
+                    return Dataset::Incompatible(c);
                 }
+                result.push((key.clone(), v, t));
             }
-            Dataset::MultiSeries(result)
+            // TODO(tailhook) if extract_multi returns None what we
+            //                should do?
         }
-    };
-    rule.functions.iter().fold(dset, Function::exec)
+        Dataset::MultiSeries(result)
+    }
+}
+
+/// Returns values matching the condition of the rule
+///
+/// Uses backlog index to avoid scanning all the keys when possible
+pub fn matching_values<'x>(rule: &Rule, backlog: &'x Backlog,
+    budget: &mut Budget)
+    -> Result<Vec<(&'x Key, &'x Value)>, Conflict>
+{
+    let condition = &rule.series.condition;
+    let mut result = Vec::new();
+    match condition.candidates(backlog.index()) {
+        Some(keys) => for key in keys {
+            try!(budget.scan_key());
+            if condition.matches(key) {
+                if let Some(value) = backlog.values.get(key) {
+                    result.push((key, value));
+                }
+            }
+        },
+        None => for (key, value) in backlog.values.iter() {
+            try!(budget.scan_key());
+            if condition.matches(key) {
+                result.push((key, value));
+            }
+        },
+    }
+    Ok(result)
 }
 
 pub fn single_value(extract: &Extract) -> bool {
@@ -54,6 +131,7 @@ pub fn single_value(extract: &Extract) -> bool {
         &DiffToAtMost(_) => true,
         &HistoryByNum(_) => false,
         &HistoryByTime(_) => false,
+        &TimeRange(..) => false,
     }
 }
 
@@ -116,9 +194,26 @@ pub fn extract_single(value: &Value, bl: &Backlog, extract: &Extract)
         },
         &HistoryByNum(_) => None,
         &HistoryByTime(_) => None,
+        &TimeRange(..) => None,
     }
 }
 
+/// Number of data points covering `time_delta` from the latest timestamp
+pub fn time_window(bl: &Backlog, time_delta: TimeDelta) -> Option<usize> {
+    if bl.timestamps.len() < 1 {
+        return None;
+    }
+    let tip = bl.timestamps[0].0;
+    let mut num = bl.timestamps.len();
+    for (idx, &(ts, _)) in bl.timestamps.iter().enumerate() {
+        if tip - ts >= time_delta as u64 {
+            num = idx + 1;
+            break;
+        }
+    }
+    Some(num)
+}
+
 pub fn extract_multi(value: &Value, bl: &Backlog, extract: &Extract)
     -> Option<(Chunk, Vec<TimeStamp>)>
 {
@@ -143,17 +238,10 @@ pub fn extract_multi(value: &Value, bl: &Backlog, extract: &Extract)
             (values, timestamps)
         }),
         &HistoryByTime(time_delta) => Some({
-            if bl.timestamps.len() < 1 {
-                return None;
-            }
-            let tip = bl.timestamps[0].0;
-            let mut num = bl.timestamps.len();
-            for (idx, &(ts, _)) in bl.timestamps.iter().enumerate() {
-                if tip - ts >= time_delta as u64 {
-                    num = idx + 1;
-                    break;
-                }
-            }
+            let num = match time_window(bl, time_delta) {
+                Some(num) => num,
+                None => return None,
+            };
             let timestamps = bl.timestamps.iter()
                 .take(num).map(|&(x, _)| x).collect();
             let values = match value {
@@ -166,5 +254,31 @@ pub fn extract_multi(value: &Value, bl: &Backlog, extract: &Extract)
             };
             (values, timestamps)
         }),
+        &TimeRange(from, to) => {
+            // timestamps are sorted from newest to oldest
+            let start = bl.timestamps.iter()
+                .position(|&(ts, _)| ts <= to)
+                .unwrap_or(bl.timestamps.len());
+            let num = bl.timestamps.iter().skip(start)
+                .take_while(|&&(ts, _)| ts >= from)
+                .count();
+            if num == 0 {
+                return None;
+            }
+            let timestamps = bl.timestamps.iter()
+                .skip(start).take(num).map(|&(x, _)| x).collect();
+            let values = match value {
+                &B::Counter(ref x)
+                => C::Counter(x.history(bl.age)
+                         .skip(start).take(num).collect()),
+                &B::Integer(ref x)
+                => C::Integer(x.history(bl.age)
+                         .skip(start).take(num).collect()),
+                &B::Float(ref x)
+                => C::Float(x.history(bl.age)
+                         .skip(start).take(num).collect()),
+            };
+            Some((values, timestamps))
+        }
     }
 }
@@ -1,4 +1,7 @@
-use history::{TimeDelta};
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use history::{TimeStamp, TimeDelta};
 use Condition;
 
 #[derive(RustcDecodable, Debug, Clone, Copy)]
@@ -6,11 +9,13 @@ use Condition;
 pub enum Source {
     Tip,
     Fine,
+    Coarse,
 }
 
 probor_enum_encoder_decoder!(Source {
     #0 Tip(),
     #1 Fine(),
+    #2 Coarse(),
 });
 
 probor_struct!(
@@ -71,13 +76,128 @@ probor_enum_encoder_decoder!(UndefFilter {
     #0 Ignore(),
 });
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Aggregation {
+    Min,
+    Max,
+    Avg,
+    /// Number of defined values
+    Count,
+    /// Percentile in range `0..100`, linearly interpolated between values
+    Percentile(f64),
+}
+
+probor_enum_encoder_decoder!(Aggregation {
+    #0 Min(),
+    #1 Max(),
+    #2 Avg(),
+    #3 Count(),
+    #4 Percentile(value #1),
+});
+
+json_enum_decoder!(Aggregation {
+    Min(),
+    Max(),
+    Avg(),
+    Count(),
+    Percentile(value),
+});
+
+// NaN percentile is rejected by aggregation functions anyway, so we
+// don't care that it's not equal to itself
+impl Eq for Aggregation {}
+
+impl Hash for Aggregation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use self::Aggregation::*;
+        match *self {
+            Min => 0.hash(state),
+            Max => 1.hash(state),
+            Avg => 2.hash(state),
+            Count => 3.hash(state),
+            Percentile(p) => {
+                4.hash(state);
+                p.to_bits().hash(state);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+probor_enum_encoder_decoder!(BinaryOp {
+    #0 Add(),
+    #1 Sub(),
+    #2 Mul(),
+    #3 Div(),
+});
+
+json_enum_decoder!(BinaryOp {
+    Add(),
+    Sub(),
+    Mul(),
+    Div(),
+});
+
+/// Value by which series are ranked in `TopK` and `BottomK`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ranking {
+    /// The newest defined value
+    Last,
+    Max,
+    Avg,
+}
+
+probor_enum_encoder_decoder!(Ranking {
+    #0 Last(),
+    #1 Max(),
+    #2 Avg(),
+});
+
+json_enum_decoder!(Ranking {
+    Last(),
+    Max(),
+    Avg(),
+});
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Function {
     Expect(Expectation),
     NonNegativeDerivative,
     Sum(UndefFilter),
     SumBy(String, UndefFilter, bool),
     StateChart(/* limit of distinct values */ usize),
+    /// Aggregate values of all series at each timestamp into single series
+    Aggregate(Aggregation),
+    /// Aggregate values of each series over time into a single value
+    AggregateOverTime(Aggregation),
+    /// Aggregate series having same values of the fields
+    GroupBy(Vec<String>, Aggregation),
+    /// Per-second increase of a counter, handles counter resets
+    Rate(/* interpolate gaps */ bool),
+    /// Increase of a counter over each interval, handles counter resets
+    Increase(/* interpolate gaps */ bool),
+    /// Difference between adjacent values
+    Delta(/* interpolate gaps */ bool),
+    /// Align series to a common grid of `step` milliseconds, aggregating
+    /// values that fall into the same step
+    Resample(TimeDelta, Aggregation),
+    /// Apply operator to series having `field` equal to `left` and
+    /// `right` values, series are matched by the rest of the key
+    Binary(BinaryOp, /* field */ String,
+           /* left */ String, /* right */ String),
+    /// Multiply all values by a number
+    Scale(f64),
+    /// Keep `n` series having the largest ranking value
+    TopK(usize, Ranking),
+    /// Keep `n` series having the smallest ranking value
+    BottomK(usize, Ranking),
 }
 
 probor_enum_encoder_decoder!(Function {
@@ -86,6 +206,17 @@ probor_enum_encoder_decoder!(Function {
     #2 Sum(undef_filter #1),
     #3 SumBy(field #1, undef_filter #2, total #3),
     #4 StateChart(distinct_num #1),
+    #5 Aggregate(aggregation #1),
+    #6 AggregateOverTime(aggregation #1),
+    #7 GroupBy(fields #1, aggregation #2),
+    #8 Rate(interpolate #1),
+    #9 Increase(interpolate #1),
+    #10 Delta(interpolate #1),
+    #11 Resample(step #1, aggregation #2),
+    #12 Binary(op #1, field #2, left #3, right #4),
+    #13 Scale(factor #1),
+    #14 TopK(num #1, ranking #2),
+    #15 BottomK(num #1, ranking #2),
 });
 
 json_enum_decoder!(Function {
@@ -94,14 +225,73 @@ json_enum_decoder!(Function {
     Sum(undef_filter),
     SumBy(field, undef_filter, bool),
     StateChart(distinct_num),
+    Aggregate(aggregation),
+    AggregateOverTime(aggregation),
+    GroupBy(fields, aggregation),
+    Rate(interpolate),
+    Increase(interpolate),
+    Delta(interpolate),
+    Resample(step, aggregation),
+    Binary(op, field, left, right),
+    Scale(factor),
+    TopK(num, ranking),
+    BottomK(num, ranking),
 });
 
+// Same as for `Aggregation`, the only effect of NaN factor is that such
+// rule is not equal to itself
+impl Eq for Function {}
+
+impl Hash for Function {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use self::Function::*;
+        mem::discriminant(self).hash(state);
+        match *self {
+            Expect(ref exp) => exp.hash(state),
+            NonNegativeDerivative => {}
+            Sum(ref undef) => undef.hash(state),
+            SumBy(ref field, ref undef, total) => {
+                field.hash(state);
+                undef.hash(state);
+                total.hash(state);
+            }
+            StateChart(num) => num.hash(state),
+            Aggregate(ref agg) => agg.hash(state),
+            AggregateOverTime(ref agg) => agg.hash(state),
+            GroupBy(ref fields, ref agg) => {
+                fields.hash(state);
+                agg.hash(state);
+            }
+            Rate(interpolate) => interpolate.hash(state),
+            Increase(interpolate) => interpolate.hash(state),
+            Delta(interpolate) => interpolate.hash(state),
+            Resample(step, ref agg) => {
+                step.hash(state);
+                agg.hash(state);
+            }
+            Binary(op, ref field, ref left, ref right) => {
+                op.hash(state);
+                field.hash(state);
+                left.hash(state);
+                right.hash(state);
+            }
+            Scale(factor) => factor.to_bits().hash(state),
+            TopK(num, ranking) | BottomK(num, ranking) => {
+                num.hash(state);
+                ranking.hash(state);
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Extract {
     Tip,
     DiffToAtMost(usize),
     HistoryByNum(usize),
     HistoryByTime(TimeDelta),
+    /// Absolute range of timestamps `(from, to)`, both inclusive
+    TimeRange(TimeStamp, TimeStamp),
 }
 
 probor_enum_encoder_decoder!(Extract {
@@ -109,6 +299,7 @@ probor_enum_encoder_decoder!(Extract {
     #1 DiffToAtMost(limit #1),
     #2 HistoryByNum(limit #1),
     #3 HistoryByTime(millis #1),
+    #4 TimeRange(from #1, to #2),
 });
 
 json_enum_decoder!(Extract {
@@ -116,6 +307,7 @@ json_enum_decoder!(Extract {
     DiffToAtMost(limit),
     HistoryByNum(limit),
     HistoryByTime(millis),
+    TimeRange(from, to),
 });
 
 
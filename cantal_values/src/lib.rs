@@ -29,7 +29,7 @@ mod util;
 pub mod itertools;
 
 
-#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+#[derive(Clone, Debug, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum Value {
     Counter(u64),
     Integer(i64),
@@ -0,0 +1,224 @@
+//! Access to hourly snapshots stored in the storage dir
+//!
+//! When query asks for data older than in-memory backlog has, hourly
+//! snapshots are loaded by a separate thread and merged into the result.
+//! Snapshots are quite big, so only few of them are kept decoded in memory.
+//!
+//! Snapshots of processes are also read by this thread.
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+use futures::sync::oneshot;
+use self_meter_http::Meter;
+
+use history::{History, TimeStamp};
+use query::{Rule, Source, Dataset, Extract, Function, Budget};
+use query::query_series;
+use storage::{read_snapshot, hourly_snapshots};
+use storage::{ProcessSnapshot, read_processes, process_snapshots};
+use watchdog;
+
+
+const HOUR: u64 = 3_600_000;
+/// Number of decoded snapshots kept in memory
+const CACHE_SIZE: usize = 4;
+
+
+pub struct Item {
+    pub name: String,
+    pub rule: Rule,
+    /// Data extracted from in-memory history, without functions applied
+    pub dataset: Dataset,
+    /// Timestamp of the oldest value in in-memory history
+    pub cutoff: TimeStamp,
+}
+
+enum Job {
+    Query(Vec<Item>, Budget,
+          oneshot::Sender<(Vec<(String, Dataset)>, Budget)>),
+    Processes(TimeStamp, oneshot::Sender<Option<Arc<ProcessSnapshot>>>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Archive(Option<Arc<Mutex<Sender<Job>>>>);
+
+struct Cache {
+    lru: VecDeque<(u64, Arc<History>)>,
+    /// Last process snapshot read, as the same one is often requested
+    /// several times in a row
+    processes: Option<Arc<ProcessSnapshot>>,
+}
+
+impl Archive {
+    /// Archive which has nothing in it, used when there is no storage dir
+    pub fn empty() -> Archive {
+        Archive(None)
+    }
+    pub fn new(path: &Path, meter: &Meter) -> Archive {
+        let (tx, rx) = channel();
+        let path = path.to_path_buf();
+        let meter = meter.clone();
+        thread::spawn(move || {
+            let _watchdog = watchdog::ExitOnReturn(84);
+            meter.track_current_thread("archive");
+            archive_loop(&path, rx);
+        });
+        Archive(Some(Arc::new(Mutex::new(tx))))
+    }
+    /// Returns true if query for the range needs data from archive
+    pub fn needs(&self, rule: &Rule, cutoff: TimeStamp) -> bool {
+        match (rule.series.source, &rule.extract) {
+            (Source::Fine, &Extract::TimeRange(from, _)) => {
+                self.0.is_some() && from < cutoff
+            }
+            _ => false,
+        }
+    }
+    /// Fills in datasets with the data from older snapshots and applies
+    /// functions of respective rules
+    ///
+    /// Data read from snapshots is accounted in the `budget` of the query,
+    /// which is returned back along with the datasets.
+    pub fn query(&self, items: Vec<Item>, budget: Budget)
+        -> oneshot::Receiver<(Vec<(String, Dataset)>, Budget)>
+    {
+        let (tx, rx) = oneshot::channel();
+        self.send(Job::Query(items, budget, tx));
+        rx
+    }
+    /// Returns the latest snapshot of processes taken at or before
+    /// `timestamp`
+    pub fn processes_at(&self, timestamp: TimeStamp)
+        -> oneshot::Receiver<Option<Arc<ProcessSnapshot>>>
+    {
+        let (tx, rx) = oneshot::channel();
+        self.send(Job::Processes(timestamp, tx));
+        rx
+    }
+    fn send(&self, job: Job) {
+        if let Some(ref chan) = self.0 {
+            chan.lock().expect("archive channel not poisoned")
+                .send(job)
+                .map_err(|_| error!("Archive thread is dead"))
+                .ok();
+        }
+        // if there is no archive thread, `job` is dropped here and
+        // receiver gets `Canceled`
+    }
+}
+
+impl Cache {
+    fn new() -> Cache {
+        Cache {
+            lru: VecDeque::with_capacity(CACHE_SIZE),
+            processes: None,
+        }
+    }
+    fn get(&mut self, dir: &Path, hour: u64) -> Option<Arc<History>> {
+        if let Some(idx) = self.lru.iter().position(|&(h, _)| h == hour) {
+            let item = self.lru.remove(idx).expect("valid index");
+            let result = item.1.clone();
+            self.lru.push_front(item);
+            return Some(result);
+        }
+        let path = dir.join(format!("hourly-{}.cbor", hour));
+        let history = match read_snapshot(&path) {
+            Ok(history) => Arc::new(history),
+            Err(()) => return None,
+        };
+        debug!("Loaded snapshot {:?}", path);
+        if self.lru.len() >= CACHE_SIZE {
+            self.lru.pop_back();
+        }
+        self.lru.push_front((hour, history.clone()));
+        return Some(history);
+    }
+    fn processes_at(&mut self, dir: &Path, timestamp: TimeStamp)
+        -> Option<Arc<ProcessSnapshot>>
+    {
+        let tstamp = latest_processes(dir, timestamp)?;
+        if let Some(ref snapshot) = self.processes {
+            if snapshot.timestamp == tstamp {
+                return Some(snapshot.clone());
+            }
+        }
+        let snapshot = Arc::new(read_processes(dir, tstamp).ok()?);
+        self.processes = Some(snapshot.clone());
+        return Some(snapshot);
+    }
+}
+
+/// Timestamp of the latest snapshot of processes taken at or before
+/// `timestamp`
+fn latest_processes(dir: &Path, timestamp: TimeStamp) -> Option<TimeStamp> {
+    process_snapshots(dir).into_iter()
+        .take_while(|&ts| ts <= timestamp).last()
+}
+
+fn fill(dir: &Path, cache: &mut Cache, hours: &[u64], item: Item,
+    budget: &mut Budget)
+    -> (String, Dataset)
+{
+    let Item { name, rule, mut dataset, mut cutoff } = item;
+    let (from, to) = match rule.extract {
+        Extract::TimeRange(from, to) => (from, to),
+        _ => unreachable!(),
+    };
+    // Snapshot `hourly-N` contains data up to the start of hour N and as
+    // much before it as `--keep-history` is, which is at least an hour.
+    // So we need snapshots from the one taken in the hour of cutoff down
+    // to the one taken right after the start of the range.
+    for &hour in hours.iter().rev() {
+        if cutoff <= from || budget.exceeded() {
+            break;
+        }
+        if hour > cutoff / HOUR + 1 {
+            continue;
+        }
+        if hour * HOUR < from {
+            break;
+        }
+        let history = match cache.get(dir, hour) {
+            Some(history) => history,
+            None => continue,
+        };
+        let oldest = match history.fine.timestamps.back() {
+            Some(&(ts, _)) => ts,
+            None => continue,
+        };
+        let sub_rule = Rule {
+            extract: Extract::TimeRange(from, min(to, cutoff-1)),
+            functions: Vec::new(),
+            .. rule.clone()
+        };
+        dataset = dataset.append_older(query_series(&sub_rule, &history,
+            budget));
+        cutoff = min(cutoff, oldest);
+    }
+    (name, rule.functions.iter().fold(dataset, Function::exec))
+}
+
+fn archive_loop(dir: &Path, rx: Receiver<Job>) {
+    let mut cache = Cache::new();
+    // the requester may have gone away already, so results of `send`
+    // are ignored
+    for job in rx {
+        match job {
+            Job::Query(items, mut budget, reply) => {
+                let hours = hourly_snapshots(dir);
+                let result = items.into_iter()
+                    .map(|item| fill(dir, &mut cache, &hours, item,
+                                     &mut budget))
+                    .collect();
+                reply.send((result, budget)).ok();
+            }
+            Job::Processes(timestamp, reply) => {
+                reply.send(cache.processes_at(dir, timestamp)).ok();
+            }
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use std::sync::{RwLock, Arc};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 use tk_carbon::{Carbon, Config as CarbonConfig};
@@ -6,9 +9,11 @@ use tk_easyloop::{spawn, interval, handle};
 
 use ns_env_config::Router;
 use futures::{Stream};
-use failure::Error;
+use futures::sync::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
+use futures::sync::oneshot;
+use failure::{Error, err_msg};
 use stats::Stats;
-use configs::Configs;
+use configs;
 
 mod config;
 mod util;
@@ -31,24 +36,106 @@ fn send(snd: &Carbon, cfg: &Config, stats: &Stats) {
     }
 }
 
-pub fn spawn_sinks(ns: &Router,
-    configs: &Arc<Configs>, stats: &Arc<RwLock<Stats>>)
-    -> Result<(), Error>
-{
-    for cfg in &configs.carbon {
-        let (carbon, init) = Carbon::new(&CarbonConfig::new().done());
-        init.connect_to(ns.subscribe_many(&[&cfg.host], cfg.port), &handle());
-        let ivl = Duration::new(cfg.interval as u64, 0);
-        let carbon = carbon.clone();
-        let cfg = cfg.clone();
-        let stats = stats.clone();
-        spawn(interval(ivl)
-            .map_err(|_| -> () { unreachable!() })
-            .map(move |()| -> () {
-                debug!("Sending data to carbon {}:{}",
-                    cfg.host, cfg.port);
-                send(&carbon, &cfg, &stats.read().expect("Can't lock stats"));
-            }).for_each(|()| Ok(())));
+type Reply = oneshot::Sender<Result<usize, Error>>;
+
+/// Carbon sinks which can be restarted with the new configuration
+#[derive(Clone)]
+pub struct Sinks {
+    config_dir: Arc<PathBuf>,
+    stats: Arc<RwLock<Stats>>,
+    router: Arc<Mutex<Option<Router>>>,
+    /// Incremented on every reload, sinks of older generations stop
+    generation: Arc<AtomicUsize>,
+    /// Reloads requested from other threads, served by the main loop
+    requests: UnboundedSender<Reply>,
+    /// Taken by the main loop when sinks are started
+    receiver: Arc<Mutex<Option<UnboundedReceiver<Reply>>>>,
+}
+
+impl Sinks {
+    pub fn new(config_dir: &Path, stats: &Arc<RwLock<Stats>>) -> Sinks {
+        let (tx, rx) = unbounded();
+        Sinks {
+            config_dir: Arc::new(config_dir.to_path_buf()),
+            stats: stats.clone(),
+            router: Arc::new(Mutex::new(None)),
+            generation: Arc::new(AtomicUsize::new(0)),
+            requests: tx,
+            receiver: Arc::new(Mutex::new(Some(rx))),
+        }
+    }
+    /// Reads configs and spawns sinks, must be called in the main loop
+    pub fn start(&self, ns: &Router) -> Result<usize, Error> {
+        *self.router.lock().expect("sinks not poisoned") = Some(ns.clone());
+        let receiver = self.receiver.lock().expect("sinks not poisoned")
+            .take();
+        if let Some(rx) = receiver {
+            let sinks = self.clone();
+            spawn(rx.for_each(move |reply| {
+                // requester may have gone away already
+                reply.send(sinks.reload()).ok();
+                Ok(())
+            }));
+        }
+        self.reload()
+    }
+    /// Same as `reload` but may be called from any thread
+    ///
+    /// Sinks are spawned by the main loop, so the result is received when
+    /// it processes the request.
+    pub fn request_reload(&self) -> oneshot::Receiver<Result<usize, Error>> {
+        let (tx, rx) = oneshot::channel();
+        let started = self.receiver.lock().expect("sinks not poisoned")
+            .is_none();
+        if !started {
+            tx.send(Err(err_msg("carbon sinks are not started yet"))).ok();
+        } else if let Err(e) = self.requests.unbounded_send(tx) {
+            e.into_inner()
+                .send(Err(err_msg("carbon sinks are stopped"))).ok();
+        }
+        rx
+    }
+    /// Rereads configs and replaces all the sinks by the new ones
+    ///
+    /// Returns number of sinks started. Old sinks stop on their next tick.
+    /// Must be called in the main loop.
+    pub fn reload(&self) -> Result<usize, Error> {
+        let router = self.router.lock().expect("sinks not poisoned");
+        let ns = match *router {
+            Some(ref ns) => ns,
+            None => return Err(err_msg("carbon sinks are not started yet")),
+        };
+        let configs = configs::read(&self.config_dir);
+        let gen = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        for cfg in &configs.carbon {
+            let (carbon, init) = Carbon::new(&CarbonConfig::new().done());
+            init.connect_to(ns.subscribe_many(&[&cfg.host], cfg.port),
+                &handle());
+            let ivl = Duration::new(cfg.interval as u64, 0);
+            let carbon = carbon.clone();
+            let cfg = cfg.clone();
+            let stats = self.stats.clone();
+            let generation = self.generation.clone();
+            spawn(interval(ivl)
+                .map_err(|_| -> () { unreachable!() })
+                .take_while(move |()| {
+                    Ok(generation.load(Ordering::SeqCst) == gen)
+                })
+                .map(move |()| -> () {
+                    debug!("Sending data to carbon {}:{}",
+                        cfg.host, cfg.port);
+                    send(&carbon, &cfg, &Stats::snapshot(&stats));
+                }).for_each(|()| Ok(())));
+        }
+        Ok(configs.carbon.len())
+    }
+}
+
+impl fmt::Debug for Sinks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sinks")
+            .field("config_dir", &self.config_dir)
+            .field("generation", &self.generation)
+            .finish()
     }
-    Ok(())
 }
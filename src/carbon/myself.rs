@@ -3,6 +3,12 @@ use tk_carbon::Carbon;
 
 use super::config::Config;
 use gossip::{NUM_PEERS, NUM_STALE};
+use gossip::{REJECTED_SIGNATURE, REJECTED_CLUSTER, REJECTED_MALFORMED};
+use gossip::REJECTED_STALE;
+use frontend::query::{QUERIES, QUERIES_REFUSED, QUERY_TIME};
+use frontend::query::{QUERY_SERIES, QUERY_POINTS};
+use frontend::query::{GRAPHQL_QUERIES, GRAPHQL_QUERIES_REFUSED, GRAPHQL_TIME};
+use frontend::query::{GRAPHQL_SERIES, GRAPHQL_POINTS};
 
 
 pub fn scan(sender: &Carbon, _cfg: &Config, stats: &Stats) {
@@ -16,4 +22,39 @@ pub fn scan(sender: &Carbon, _cfg: &Config, stats: &Stats) {
         format_args!("cantal.{}.{}.apps.{}.groups.gossip.num_stale",
             cls, stats.hostname, "cantal"),
         NUM_STALE.get());
+    for &(name, counter) in &[
+        ("rejected_signature", &*REJECTED_SIGNATURE),
+        ("rejected_cluster", &*REJECTED_CLUSTER),
+        ("rejected_malformed", &*REJECTED_MALFORMED),
+        ("rejected_stale", &*REJECTED_STALE),
+    ] {
+        sender.add_value(
+            format_args!("cantal.{}.{}.apps.{}.groups.gossip.{}",
+                cls, stats.hostname, "cantal", name),
+            counter.get());
+    }
+    for &(name, counter) in &[
+        ("queries", &*QUERIES),
+        ("queries_refused", &*QUERIES_REFUSED),
+        ("series", &*QUERY_SERIES),
+        ("points", &*QUERY_POINTS),
+        ("time", &*QUERY_TIME),
+    ] {
+        sender.add_value(
+            format_args!("cantal.{}.{}.apps.{}.groups.query.{}",
+                cls, stats.hostname, "cantal", name),
+            counter.get());
+    }
+    for &(name, counter) in &[
+        ("queries", &*GRAPHQL_QUERIES),
+        ("queries_refused", &*GRAPHQL_QUERIES_REFUSED),
+        ("series", &*GRAPHQL_SERIES),
+        ("points", &*GRAPHQL_POINTS),
+        ("time", &*GRAPHQL_TIME),
+    ] {
+        sender.add_value(
+            format_args!("cantal.{}.{}.apps.{}.groups.graphql_metrics.{}",
+                cls, stats.hostname, "cantal", name),
+            counter.get());
+    }
 }
@@ -28,7 +28,7 @@ pub fn serve<S: 'static>(stats: &Arc<RwLock<Stats>>)
 {
     let stats = stats.clone();
     reply(move |e| {
-        let stats: &Stats = &*stats.read().expect("stats not poisoned");
+        let stats = Stats::snapshot(&stats);
         let ref fts = stats.history.fine.timestamps;
         let fage = stats.history.fine.age;
         let vec: Vec<(&Key, TimeStamp, Value)> =
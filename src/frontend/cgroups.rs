@@ -24,7 +24,7 @@ pub fn cgroups<'x>(ctx: &ContextRef<'x>, filter: Option<Filter>)
 {
     let mut buf = BTreeMap::new();
     let name_prefix = filter.as_ref().and_then(|x| x.name_prefix.as_ref());
-    for pro in &ctx.stats.processes {
+    for pro in ctx.stats.processes.iter() {
         if let Some(ref gname) = pro.cgroup {
             if !name_prefix.map(|x| gname.starts_with(x)).unwrap_or(true) {
                 continue;
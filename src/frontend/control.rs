@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+
+use failure::err_msg;
+use futures::Future;
+use juniper::FieldError;
+
+use id::Id;
+use frontend::graphql::{ContextRef, Timestamp};
+
+
+#[derive(GraphQLObject)]
+#[graphql(description="Result of adding a host to the gossip")]
+pub struct AddHost {
+    /// Address the host is pinged at
+    address: String,
+    /// Whether some known peer already has this address
+    already_known: bool,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description="Result of removing a peer")]
+pub struct RemovePeer {
+    /// False if there was no peer with such id
+    removed: bool,
+    hostname: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description="State of \"remote\" on this host")]
+pub struct Remote {
+    enabled: bool,
+    /// Whether it was already enabled before the mutation
+    was_enabled: bool,
+    /// Number of peers having "remote" enabled
+    peers_with_remote: i32,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description="Result of reloading configs")]
+pub struct Reload {
+    /// Number of carbon sinks started with the new configuration
+    carbon_sinks: i32,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description="Result of requesting a snapshot of metrics")]
+pub struct Snapshot {
+    /// Bytes of the write-ahead log to be compacted into the snapshot,
+    /// snapshot is stored on the next scan
+    log_size: f64,
+    /// Time of the scan of the latest stored snapshot
+    last_snapshot: Option<Timestamp>,
+}
+
+fn gossip_enabled(ctx: &ContextRef) -> Result<(), FieldError> {
+    if ctx.gossip.is_enabled() {
+        Ok(())
+    } else {
+        Err(FieldError::from("gossip is disabled (no cluster name)"))
+    }
+}
+
+pub fn add_host(ctx: &ContextRef, addr: String)
+    -> Result<AddHost, FieldError>
+{
+    gossip_enabled(ctx)?;
+    let addr: SocketAddr = addr.parse()
+        .map_err(|e| FieldError::from(format!("bad address: {}", e)))?;
+    let known = ctx.gossip.has_address(addr);
+    ctx.gossip.add_host(addr);
+    Ok(AddHost {
+        address: addr.to_string(),
+        already_known: known,
+    })
+}
+
+pub fn remove_peer(ctx: &ContextRef, id: String)
+    -> Result<RemovePeer, FieldError>
+{
+    gossip_enabled(ctx)?;
+    let id: Id = id.parse()
+        .map_err(|_| FieldError::from("bad peer id"))?;
+    Ok(match ctx.gossip.remove_peer(&id) {
+        Some(peer) => RemovePeer {
+            removed: true,
+            hostname: peer.hostname.clone(),
+            name: peer.name.clone(),
+        },
+        None => RemovePeer {
+            removed: false,
+            hostname: None,
+            name: None,
+        },
+    })
+}
+
+pub fn enable_remote(ctx: &ContextRef) -> Result<Remote, FieldError> {
+    gossip_enabled(ctx)?;
+    let was_enabled = ctx.gossip.notify_remote(true);
+    let (_, with_remote) = ctx.gossip.get_peer_numbers();
+    Ok(Remote {
+        enabled: true,
+        was_enabled: was_enabled,
+        peers_with_remote: with_remote as i32,
+    })
+}
+
+pub fn reload_config(ctx: &ContextRef) -> Result<Reload, FieldError> {
+    let result = if ctx.may_block {
+        // HTTP queries are executed in a thread pool, but sinks can only
+        // be spawned by the main loop, so wait for it to reload them
+        ctx.carbon.request_reload().wait()
+            .unwrap_or_else(|_| Err(err_msg("main loop is not running")))
+    } else {
+        ctx.carbon.reload()
+    };
+    let num = result.map_err(|e| FieldError::from(e.to_string()))?;
+    Ok(Reload {
+        carbon_sinks: num as i32,
+    })
+}
+
+pub fn trigger_snapshot(ctx: &ContextRef) -> Result<Snapshot, FieldError> {
+    let storage = match ctx.storage {
+        Some(storage) => storage,
+        None => return Err(FieldError::from("storage dir is not configured")),
+    };
+    storage.request_snapshot();
+    let last = ctx.stats.storage.timestamp;
+    Ok(Snapshot {
+        log_size: ctx.stats.storage.log_size as f64,
+        last_snapshot: if last > 0 {
+            Timestamp::from_millis(last)
+        } else {
+            None
+        },
+    })
+}
@@ -1,28 +1,50 @@
 use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
 
+use futures::Future;
+use futures_cpupool::CpuPool;
 use gossip::Gossip;
+use humantime::parse_rfc3339;
 use juniper::{self, InputValue, RootNode, FieldError, execute};
 use juniper::{Value, ExecutionError};
 use self_meter_http::{Meter};
 use serde_json::{Value as Json, to_value};
 use tk_http::Status;
 
+use archive::Archive;
+use carbon::Sinks;
+use storage::Storage;
 use time_util::duration_to_millis;
 use stats::Stats;
-use gossip::Peer;
+use gossip::{Peer, PeerFilter};
 use frontend::{Request};
 use frontend::routing::Format;
 use frontend::quick_reply::{read_json, respond, respond_status};
-use frontend::{status, cgroups, processes};
+use frontend::{status, cgroups, processes, query, control};
 
+/// Milliseconds since the epoch at the end of the year 9999, the latest
+/// timestamp accepted as input
+const MAX_TIMESTAMP: u64 = 253_402_300_799_999;
+
+lazy_static! {
+    /// Queries received by HTTP are executed here, so resolvers can wait
+    /// for the archive thread without blocking the main loop
+    static ref POOL: CpuPool = CpuPool::new(2);
+}
 
 pub struct ContextRef<'a> {
     pub stats: &'a Stats,
     pub meter: &'a Meter,
     pub gossip: &'a Gossip,
+    pub archive: &'a Archive,
+    pub storage: Option<&'a Storage>,
+    pub carbon: &'a Sinks,
+    /// Whether resolvers may block waiting for the archive, this is false
+    /// for websocket queries and subscriptions, as they are executed on
+    /// the main loop
+    pub may_block: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -30,6 +52,10 @@ pub struct Context {
     pub stats: Arc<RwLock<Stats>>,
     pub meter: Meter,
     pub gossip: Gossip,
+    pub archive: Archive,
+    /// Only set if storage dir is configured
+    pub storage: Option<Arc<Storage>>,
+    pub carbon: Sinks,
 }
 
 pub type Schema<'a> = RootNode<'a, &'a Query, &'a Mutation>;
@@ -75,10 +101,29 @@ graphql_object!(<'a> Local<'a>: ContextRef<'a> as "Local" |&self| {
     {
         cgroups::cgroups(executor.context(), filter)
     }
-    field processes(&executor, filter: Option<processes::Filter>)
-        -> Vec<&processes::Process>
+    field processes(&executor, filter: Option<processes::Filter>,
+        timestamp: Option<Timestamp> as "If set processes are read from \
+            the snapshot taken at or before this time (snapshots are \
+            stored every five minutes), only allowed over HTTP")
+        -> Result<Vec<processes::Process>, FieldError>
+    {
+        processes::processes(executor.context(), filter, timestamp)
+    }
+    field metrics(&executor,
+        filter: String as "Source and conditions in the query language, \
+            e.g. `fine{metric=\"rss\", cgroup^=\"lithos.\"}`",
+        extract: Option<String> as "Range without brackets, e.g. `5m` or \
+            `100`, only latest values are returned if not set",
+        from: Option<Timestamp> as "Start of the time range (inclusive), \
+            can't be used together with `extract`",
+        to: Option<Timestamp> as "End of the time range (inclusive), \
+            requires `from`",
+        functions: Option<Vec<String>> as "Functions applied left to \
+            right, e.g. `[\"derivative\", \"sum_by(cgroup)\"]`")
+        -> Result<Vec<query::Series>, FieldError>
     {
-        processes::processes(executor.context(), filter)
+        query::metrics(executor.context(), filter, extract, from, to,
+            functions)
     }
 });
 
@@ -89,8 +134,14 @@ graphql_object!(<'a> &'a Query: ContextRef<'a> as "Query" |&self| {
     field local(&executor) -> Local<'a> {
         Local(PhantomData)
     }
-    field peers(&executor) -> Vec<Arc<Peer>> {
-        executor.context().gossip.get_peers()
+    field peers(&executor, filter: Option<PeerFilter>) -> Vec<Arc<Peer>> {
+        let peers = executor.context().gossip.get_peers();
+        match filter {
+            Some(filter) => {
+                peers.into_iter().filter(|p| filter.matches(p)).collect()
+            }
+            None => peers,
+        }
     }
 });
 
@@ -98,71 +149,107 @@ graphql_object!(<'a> &'a Mutation: ContextRef<'a> as "Mutation" |&self| {
     field noop(&executor) -> Result<Okay, FieldError> {
         Ok(Okay { ok: true })
     }
+    field add_host(&executor, addr: String as "Address of the gossip \
+        port, e.g. `10.0.0.1:22682`")
+        -> Result<control::AddHost, FieldError>
+    {
+        control::add_host(executor.context(), addr)
+    }
+    field remove_peer(&executor, id: String)
+        -> Result<control::RemovePeer, FieldError>
+    {
+        control::remove_peer(executor.context(), id)
+    }
+    field enable_remote(&executor) -> Result<control::Remote, FieldError> {
+        control::enable_remote(executor.context())
+    }
+    field reload_config(&executor) -> Result<control::Reload, FieldError> {
+        control::reload_config(executor.context())
+    }
+    field trigger_snapshot(&executor)
+        -> Result<control::Snapshot, FieldError>
+    {
+        control::trigger_snapshot(executor.context())
+    }
 });
 
 graphql_scalar!(Timestamp {
-    description: "A timestamp transferred as a number of milliseconds"
+    description: "A timestamp transferred as a number of milliseconds, \
+        RFC3339 strings are also accepted as input"
 
     resolve(&self) -> Value {
-        Value::float(duration_to_millis(self.0.duration_since(UNIX_EPOCH)
-            .expect("time always in future"))
-            as f64)
+        Value::float(self.to_millis() as f64)
     }
 
-    from_input_value(_v: &InputValue) -> Option<Timestamp> {
-        unimplemented!();
+    from_input_value(v: &InputValue) -> Option<Timestamp> {
+        if let Some(ms) = v.as_float_value() {
+            if ms >= 0. && ms <= MAX_TIMESTAMP as f64 {
+                return Timestamp::from_millis(ms as u64);
+            }
+            return None;
+        }
+        if let Some(ms) = v.as_int_value() {
+            if ms >= 0 {
+                return Timestamp::from_millis(ms as u64);
+            }
+            return None;
+        }
+        v.as_string_value()
+            .and_then(|s| parse_rfc3339(s).ok())
+            .map(Timestamp)
     }
 });
 
+impl Timestamp {
+    /// Returns `None` for timestamps after the year 9999
+    pub fn from_millis(ms: u64) -> Option<Timestamp> {
+        if ms > MAX_TIMESTAMP {
+            return None;
+        }
+        Some(Timestamp(UNIX_EPOCH + Duration::from_millis(ms)))
+    }
+    /// Milliseconds since the epoch, timestamps before the epoch are zero
+    pub fn to_millis(&self) -> u64 {
+        self.0.duration_since(UNIX_EPOCH)
+            .map(duration_to_millis)
+            .unwrap_or(0)
+    }
+}
+
 pub fn serve<S: 'static>(context: &Context, format: Format)
     -> Request<S>
 {
     let ctx = context.clone();
     read_json(move |input: Input, e| {
-        let stats: &Stats = &*ctx.stats.read().expect("stats not poisoned");
-        let context = ContextRef {
-            stats,
-            meter: &ctx.meter,
-            gossip: &ctx.gossip,
-        };
-
-        let variables = input.variables.unwrap_or_else(HashMap::new);
-
-        let result = execute(&input.query,
-            input.operation_name.as_ref().map(|x| &x[..]),
-            &Schema::new(&Query, &Mutation),
-            &variables,
-            &context);
-        let out = match result {
-            Ok((data, errors)) => {
-                Output {
-                    data: Some(data),
-                    errors: ErrorWrapper::Execution(errors),
-                }
-            }
-            Err(e) => {
-                Output {
-                    data: None,
-                    errors: ErrorWrapper::Fatal(
-                        to_value(&e).expect("can serialize error")),
-                }
+        Box::new(POOL.spawn_fn(move || {
+            Ok::<_, ()>(execute_input(&ctx, &input, true))
+        }).then(move |result| {
+            let out = result.expect("executing query never fails");
+            if out.data.is_some() {
+                respond(e, format, out)
+            } else {
+                respond_status(Status::BadRequest, e, format, out)
             }
-        };
-
-        if out.data.is_some() {
-            Box::new(respond(e, format, out))
-        } else {
-            Box::new(respond_status(Status::BadRequest, e, format, out))
-        }
+        }))
     })
 }
 
 pub fn ws_response<'a>(context: &Context, input: &'a Input) -> Output {
-    let stats: &Stats = &*context.stats.read().expect("stats not poisoned");
+    execute_input(context, input, false)
+}
+
+fn execute_input(context: &Context, input: &Input, may_block: bool)
+    -> Output
+{
+    let stats = Stats::snapshot(&context.stats);
     let context = ContextRef {
-        stats,
+        stats: &stats,
         meter: &context.meter,
         gossip: &context.gossip,
+        archive: &context.archive,
+        storage: context.storage.as_ref().map(|x| &**x),
+        carbon: &context.carbon,
+        may_block: may_block,
     };
 
     let empty = HashMap::new();
@@ -202,3 +289,110 @@ impl ErrorWrapper {
 }
 
 impl<'a> juniper::Context for ContextRef<'a> {}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+
+    use juniper::{InputValue, FromInputValue};
+    use ns_env_config;
+    use self_meter_http::Meter;
+    use serde_json::to_value;
+    use tk_easyloop::{self, handle};
+
+    use archive::Archive;
+    use carbon::Sinks;
+    use gossip;
+    use id::Id;
+    use stats::Stats;
+    use super::{Context, Input, Timestamp, POOL, execute_input};
+
+    fn context() -> Context {
+        let stats = Arc::new(RwLock::new(Stats::new(
+            "test".into(), "localhost".into(), None,
+            &Id::new([1u8; 16]), Vec::new())));
+        Context {
+            meter: Meter::new(),
+            gossip: gossip::noop(),
+            archive: Archive::empty(),
+            storage: None,
+            carbon: Sinks::new(Path::new("/nonexistent"), &stats),
+            stats: stats,
+        }
+    }
+
+    fn parse(v: InputValue) -> Option<u64> {
+        Timestamp::from_input_value(&v).map(|x| x.to_millis())
+    }
+
+    #[test]
+    fn test_timestamp_input() {
+        assert_eq!(parse(InputValue::float(1500000000123.)),
+                   Some(1500000000123));
+        assert_eq!(parse(InputValue::int(1000)), Some(1000));
+        assert_eq!(parse(InputValue::float(253402300799999.)),
+                   Some(253402300799999));
+        assert_eq!(parse(InputValue::string("2017-07-14T02:40:00Z")),
+                   Some(1500000000000));
+        assert_eq!(parse(InputValue::string("2017-07-14T02:40:00.5Z")),
+                   Some(1500000000500));
+    }
+
+    #[test]
+    fn test_bad_timestamp_input() {
+        assert_eq!(parse(InputValue::string("yesterday")), None);
+        assert_eq!(parse(InputValue::float(-1.)), None);
+        assert_eq!(parse(InputValue::float(1e300)), None);
+        assert_eq!(parse(InputValue::float(253402300800000.)), None);
+        assert_eq!(parse(InputValue::int(-1)), None);
+        assert_eq!(parse(InputValue::boolean(true)), None);
+        assert_eq!(parse(InputValue::null()), None);
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let ctx = context();
+        let input = Input {
+            query: "mutation { reloadConfig { carbonSinks } }".into(),
+            operation_name: None,
+            variables: None,
+        };
+        // sinks are not started yet, so reload is refused
+        let out = to_value(&execute_input(&ctx, &input, true)).unwrap();
+        assert!(out["errors"].is_array());
+        // resolver runs in the pool and waits for the main loop to reload
+        let out = tk_easyloop::run(|| {
+            let ns = ns_env_config::init(&handle()).unwrap();
+            ctx.carbon.start(&ns).unwrap();
+            let ctx = ctx.clone();
+            POOL.spawn_fn(move || {
+                Ok::<_, ()>(execute_input(&ctx, &input, true))
+            })
+        }).unwrap();
+        let out = to_value(&out).unwrap();
+        assert!(out.get("errors").is_none());
+        assert_eq!(out["data"]["reloadConfig"]["carbonSinks"].as_u64(),
+                   Some(0));
+    }
+
+    #[test]
+    fn test_mutations_disabled() {
+        let ctx = context();
+        for query in &[
+            "mutation { addHost(addr: \"127.0.0.1:22682\") { address } }",
+            "mutation { removePeer(id: \"0102\") { removed } }",
+            "mutation { enableRemote { enabled } }",
+            "mutation { triggerSnapshot { logSize } }",
+        ] {
+            let input = Input {
+                query: query.to_string(),
+                operation_name: None,
+                variables: None,
+            };
+            // no cluster name and no storage dir
+            let out = to_value(&execute_input(&ctx, &input, true)).unwrap();
+            assert!(out["errors"].is_array(), "{}: {:?}", query, out);
+        }
+    }
+}
@@ -3,7 +3,7 @@ mod all_metrics;
 mod disk;
 mod error_page;
 pub mod graphql;
-mod query;
+pub mod query;
 mod quick_reply;
 mod routing;
 mod sockets;
@@ -13,6 +13,7 @@ mod websocket;
 mod status;
 mod cgroups;
 mod processes;
+mod control;
 
 use std::sync::{Arc, RwLock};
 
@@ -81,6 +82,13 @@ impl<S> DispatcherTrait<S> for Dispatcher
             AllProcesses(format) => {
                 Ok(processes::serve(&self.stats, format))
             }
+            ProcessesAt(Some(timestamp), format) => {
+                Ok(processes::serve_at(&self.graphql.archive,
+                    timestamp, format))
+            }
+            ProcessesAt(None, _) => {
+                serve_error_page(Http::BadRequest)
+            }
             AllSockets(format) => {
                 Ok(sockets::serve(&self.stats, format))
             }
@@ -100,7 +108,7 @@ impl<S> DispatcherTrait<S> for Dispatcher
                 serve_error_page(Http::NotImplemented)
             }
             Query(format) => {   // POST
-                Ok(query::serve(&self.stats, format))
+                Ok(query::serve(&self.stats, &self.graphql.archive, format))
             }
             AddHost(format) => { // POST
                 Ok(add_host::add_host(&self.gossip, format))
@@ -1,11 +1,17 @@
+use std::cmp::max;
 use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, Duration, UNIX_EPOCH};
 
+use futures::Future;
+use juniper::FieldError;
+use tk_http::Status;
+
+use archive::Archive;
 use stats::Stats;
 use frontend::{Request};
 use frontend::routing::Format;
-use frontend::quick_reply::{reply, respond};
-use frontend::graphql::ContextRef;
+use frontend::quick_reply::{reply, respond, respond_status};
+use frontend::graphql::{ContextRef, Timestamp};
 pub use scan::processes::MinimalProcess as Process;
 
 
@@ -22,38 +28,115 @@ pub fn serve<S: 'static>(stats: &Arc<RwLock<Stats>>, format: Format)
 {
     let stats = stats.clone();
     reply(move |e| {
-        let stats: &Stats = &*stats.read().expect("stats not poisoned");
+        let stats = Stats::snapshot(&stats);
         Box::new(respond(e, format,
             &ProcessesData {
                 boot_time: stats.boot_time,
-                all: &stats.processes,
+                all: &*stats.processes,
             }
         ))
     })
 }
 
+#[derive(Serialize)]
+struct NotFound {
+    error: &'static str,
+}
+
+/// Serves the latest stored snapshot of processes taken at or before
+/// `timestamp`
+pub fn serve_at<S: 'static>(archive: &Archive, timestamp: u64, format: Format)
+    -> Request<S>
+{
+    let archive = archive.clone();
+    reply(move |e| {
+        Box::new(archive.processes_at(timestamp).then(move |result| {
+            match result {
+                Ok(Some(snapshot)) => respond(e, format, &*snapshot),
+                Ok(None) | Err(_) => {
+                    respond_status(Status::NotFound, e, format,
+                        NotFound { error: "no snapshot of processes" })
+                }
+            }
+        }))
+    })
+}
+
 // ---------------------- graphql ----------------------
 
 #[derive(GraphQLInputObject)]
 #[graphql(name="ProcessFilter", description="Filter for processes")]
 pub struct Filter {
     maximum_uptime: Option<i32>,
+    /// Only processes started at or after this time
+    started_after: Option<Timestamp>,
+    /// Only processes started at or before this time
+    started_before: Option<Timestamp>,
 }
 
-pub fn processes<'x>(ctx: &ContextRef<'x>, filter: Option<Filter>)
-    -> Vec<&'x Process>
-{
-    let timestamp = filter.as_ref()
+fn started_after(filter: &Option<Filter>, now: SystemTime) -> Option<u64> {
+    let by_uptime = filter.as_ref()
         .and_then(|x| x.maximum_uptime)
         .and_then(|x| {
-            let dur = (SystemTime::now() - Duration::from_millis(x as u64))
+            let dur = (now - Duration::from_millis(x as u64))
                        .duration_since(UNIX_EPOCH).ok()?;
             dur.as_secs().checked_mul(1000)?
             .checked_add(dur.subsec_nanos() as u64 / 1000000)
         });
-    return ctx.stats.processes.iter()
-        .filter(|p| {
-            timestamp.map(|ts| ts <= p.start_timestamp).unwrap_or(true)
-        })
-        .collect();
+    let by_time = filter.as_ref()
+        .and_then(|x| x.started_after.as_ref())
+        .map(|x| x.to_millis());
+    match (by_uptime, by_time) {
+        (Some(a), Some(b)) => Some(max(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn started_before(filter: &Option<Filter>) -> Option<u64> {
+    filter.as_ref()
+        .and_then(|x| x.started_before.as_ref())
+        .map(|x| x.to_millis())
+}
+
+fn matches(start: Option<u64>, end: Option<u64>, p: &Process) -> bool {
+    start.map(|ts| ts <= p.start_timestamp).unwrap_or(true) &&
+    end.map(|ts| p.start_timestamp <= ts).unwrap_or(true)
+}
+
+/// Returns processes from the last scan, or if `timestamp` is specified
+/// from the stored snapshot taken at or before it
+///
+/// Note: `maximum_uptime` is counted from the time of the snapshot
+pub fn processes<'x>(ctx: &ContextRef<'x>, filter: Option<Filter>,
+    timestamp: Option<Timestamp>)
+    -> Result<Vec<Process>, FieldError>
+{
+    let end = started_before(&filter);
+    let ts = match timestamp {
+        Some(ts) => ts.to_millis(),
+        None => {
+            let start = started_after(&filter, SystemTime::now());
+            return Ok(ctx.stats.processes.iter()
+                .filter(|p| matches(start, end, p))
+                .cloned()
+                .collect());
+        }
+    };
+    if !ctx.may_block {
+        return Err(FieldError::from(
+            "snapshots of processes can only be queried over HTTP"));
+    }
+    // Graphql resolvers are synchronous, so this blocks the thread
+    // for the time of reading the snapshot, it's not the main loop
+    let snapshot = match ctx.archive.processes_at(ts).wait() {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return Err(FieldError::from("no snapshot of processes")),
+        Err(_) => return Err(FieldError::from("archive is unavailable")),
+    };
+    let now = UNIX_EPOCH + Duration::from_millis(snapshot.timestamp);
+    let start = started_after(&filter, now);
+    return Ok(snapshot.processes.iter()
+        .filter(|p| matches(start, end, p))
+        .cloned()
+        .collect());
 }
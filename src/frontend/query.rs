@@ -1,17 +1,61 @@
 use std::sync::{Arc, RwLock};
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
+use std::u64;
+use std::time::{Instant, Duration, UNIX_EPOCH};
 
+use cantal::Value as TipValue;
+use futures::Future;
+use history::{History, Key, Chunk, TimeStamp};
+use juniper::{FieldError, InputValue, Value};
+use libcantal::Counter;
 use probor;
+use tk_http::Status;
 
+use archive::{Archive, Item};
 use stats::Stats;
 use frontend::{Request};
+use frontend::graphql::{ContextRef, Timestamp};
 use frontend::routing::Format;
-use frontend::quick_reply::{read_json_old, respond_probor};
-use query::{Rule, Dataset, query_history};
+use frontend::quick_reply::{read_json_old, respond_probor, respond_status};
+use query::{Rule, Extract, Dataset, TimeSlice, Budget, Cost, query_series};
+use query::parse_rule;
+use time_util::duration_to_millis;
+
+
+lazy_static! {
+    /// Number of `/query` requests
+    pub static ref QUERIES: Counter = Counter::new();
+    /// Number of `/query` requests refused because of query limits
+    pub static ref QUERIES_REFUSED: Counter = Counter::new();
+    /// Number of series extracted from history by `/query`
+    pub static ref QUERY_SERIES: Counter = Counter::new();
+    /// Number of data points extracted from history by `/query`
+    pub static ref QUERY_POINTS: Counter = Counter::new();
+    /// Milliseconds spent querying live history in `/query`
+    pub static ref QUERY_TIME: Counter = Counter::new();
+    /// Number of `metrics` queries in GraphQL
+    pub static ref GRAPHQL_QUERIES: Counter = Counter::new();
+    /// Number of `metrics` queries refused because of query limits
+    pub static ref GRAPHQL_QUERIES_REFUSED: Counter = Counter::new();
+    /// Number of series extracted from history by `metrics` queries
+    pub static ref GRAPHQL_SERIES: Counter = Counter::new();
+    /// Number of data points extracted from history by `metrics` queries
+    pub static ref GRAPHQL_POINTS: Counter = Counter::new();
+    /// Milliseconds spent on `metrics` queries, including archive reads
+    pub static ref GRAPHQL_TIME: Counter = Counter::new();
+}
 
 #[derive(RustcDecodable)]
 struct Query {
-    rules: HashMap<String, Rule>,
+    rules: Option<HashMap<String, Rule>>,
+    /// Rules in the textual query language
+    queries: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize)]
+struct BadQuery {
+    name: String,
+    error: String,
 }
 
 struct Response {
@@ -29,18 +73,284 @@ impl<'x> probor::Encodable for Response {
     }
 }
 
-pub fn serve<S: 'static>(stats: &Arc<RwLock<Stats>>, _format: Format)
+/// Accounts series and points extracted by a `/query` request
+fn account_query(cost: Cost, exceeded: bool) {
+    QUERY_SERIES.incr(cost.series as u64);
+    QUERY_POINTS.incr(cost.points as u64);
+    if exceeded {
+        QUERIES_REFUSED.incr(1);
+    }
+}
+
+/// Timestamp of the oldest value in in-memory history, older values are
+/// read from archive
+fn cutoff(history: &History, rule: &Rule) -> TimeStamp {
+    match (history.fine.timestamps.back(), &rule.extract) {
+        (Some(&(ts, _)), _) => ts,
+        (None, &Extract::TimeRange(_, to)) => to.saturating_add(1),
+        (None, _) => 0,
+    }
+}
+
+pub fn serve<S: 'static>(stats: &Arc<RwLock<Stats>>, archive: &Archive,
+    _format: Format)
     -> Request<S>
 {
     let stats = stats.clone();
+    let archive = archive.clone();
     read_json_old(move |input: Query, e| {
-        let stats: &Stats = &*stats.read().expect("stats not poisoned");
-        let ref h = stats.history;
-        Box::new(respond_probor(e,
-            &Response {
-                values: input.rules.into_iter()
-                    .map(|(key, rule)| (key, query_history(&rule, h)))
-                    .collect(),
-            }))
+        let mut rules = input.rules.unwrap_or_else(HashMap::new);
+        for (name, text) in input.queries.unwrap_or_else(HashMap::new) {
+            match parse_rule(&text) {
+                Ok(rule) => {
+                    rules.insert(name, rule);
+                }
+                Err(err) => {
+                    return Box::new(respond_status(Status::BadRequest, e,
+                        Format::Json,
+                        BadQuery { name: name, error: err.to_string() }));
+                }
+            }
+        }
+        let mut values = HashMap::new();
+        let mut archived = Vec::new();
+        let budget = {
+            let stats = Stats::snapshot(&stats);
+            let ref h = stats.history;
+            let start = Instant::now();
+            let mut budget = Budget::new(stats.query_limits);
+            for (name, rule) in rules {
+                let cutoff = cutoff(h, &rule);
+                if archive.needs(&rule, cutoff) {
+                    let dataset = query_series(&rule, h, &mut budget);
+                    archived.push(Item { name, rule, dataset, cutoff });
+                } else {
+                    let dataset = stats.query_cache
+                        .query(&rule, h, &mut budget);
+                    values.insert(name, dataset);
+                }
+            }
+            QUERIES.incr(1);
+            QUERY_TIME.incr(duration_to_millis(start.elapsed()));
+            budget
+        };
+        if archived.is_empty() {
+            account_query(budget.cost(), budget.exceeded());
+            return Box::new(respond_probor(e, &Response { values }));
+        }
+        // limits apply to the data read from archive too, so the budget
+        // is passed to the archive thread and accounted when it's back
+        let live_cost = budget.cost();
+        Box::new(archive.query(archived, budget).then(move |result| {
+            match result {
+                Ok((items, budget)) => {
+                    account_query(budget.cost(), budget.exceeded());
+                    values.extend(items);
+                }
+                Err(_) => {
+                    account_query(live_cost, false);
+                    error!("Archive query is canceled");
+                }
+            }
+            respond_probor(e, &Response { values })
+        }))
     })
 }
+
+// ---------------------- graphql ----------------------
+
+/// Fields of the key, e.g. `{"metric": "rss", "cgroup": "system"}`
+pub struct KeyFields(BTreeMap<String, String>);
+
+pub struct Series {
+    key: KeyFields,
+    timestamps: Vec<Timestamp>,
+    values: Vec<Option<f64>>,
+    state: Option<String>,
+}
+
+graphql_scalar!(KeyFields as "Json" {
+    description: "Fields of the metric key as a JSON object of strings"
+
+    resolve(&self) -> Value {
+        Value::Object(self.0.iter()
+            .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+            .collect())
+    }
+
+    from_input_value(v: &InputValue) -> Option<KeyFields> {
+        v.to_object_value().and_then(|obj| {
+            obj.into_iter()
+                .map(|(k, v)| {
+                    v.as_string_value().map(|v| (k.to_string(), v.to_string()))
+                })
+                .collect::<Option<BTreeMap<_, _>>>()
+        }).map(KeyFields)
+    }
+});
+
+graphql_object!(Series: () as "Series" |&self| {
+    description: "Values of a single key, newest first"
+    field key() -> &KeyFields { &self.key }
+    field timestamps() -> &[Timestamp] { &self.timestamps }
+    field values() -> &[Option<f64>] as "Numeric values, null where \
+        value is missing (and for states)"
+    {
+        &self.values
+    }
+    field state() -> &Option<String> as "Text of the state for state \
+        metrics"
+    {
+        &self.state
+    }
+});
+
+fn timestamp(ts: TimeStamp) -> Timestamp {
+    Timestamp(UNIX_EPOCH + Duration::from_millis(ts))
+}
+
+fn key_fields(key: &Key) -> KeyFields {
+    KeyFields(key.with_pairs(|pairs| pairs.iter()
+        .map(|&(k, v)| (k.to_string(), v.to_string()))
+        .collect()))
+}
+
+fn series(key: &Key, chunk: Chunk, timestamps: Vec<TimeStamp>) -> Series {
+    let (values, timestamps, state) = match chunk {
+        Chunk::State((ts, text)) => (vec![None], vec![ts], Some(text)),
+        Chunk::Counter(vec) => (vec.into_iter()
+            .map(|x| x.map(|x| x as f64)).collect(), timestamps, None),
+        Chunk::Integer(vec) => (vec.into_iter()
+            .map(|x| x.map(|x| x as f64)).collect(), timestamps, None),
+        Chunk::Float(vec) => (vec, timestamps, None),
+    };
+    Series {
+        key: key_fields(key),
+        timestamps: timestamps.into_iter().map(timestamp).collect(),
+        values: values,
+        state: state,
+    }
+}
+
+fn tip(key: &Key, value: TipValue, (_, ts): TimeSlice) -> Series {
+    let (value, state) = match value {
+        TipValue::Counter(x) => (Some(x as f64), None),
+        TipValue::Integer(x) => (Some(x as f64), None),
+        TipValue::Float(x) => (Some(x), None),
+        TipValue::State((_, text)) => (None, Some(text)),
+    };
+    Series {
+        key: key_fields(key),
+        timestamps: vec![timestamp(ts)],
+        values: vec![value],
+        state: state,
+    }
+}
+
+/// Composes a query in the textual query language out of its parts
+fn rule_text(filter: &str, extract: Option<&str>, functions: &[String])
+    -> String
+{
+    let mut text = String::from(filter);
+    if let Some(extract) = extract {
+        text.push('[');
+        text.push_str(extract);
+        text.push(']');
+    }
+    for func in functions {
+        text.push_str(" | ");
+        text.push_str(func);
+    }
+    return text;
+}
+
+/// Queries local history, older data is read from archive if needed
+///
+/// Filter, extract and functions are in the textual query language, e.g.
+/// `fine{metric="rss"}`, `5m` and `["sum_by(cgroup)"]`. Instead of extract
+/// a time range can be set by `from` and `to` (both inclusive, `to` is
+/// only allowed with `from`). Query cache and query limits are shared with
+/// the `/query` endpoint.
+///
+/// Archive is only read for queries sent over HTTP, websocket queries and
+/// subscriptions are executed on the main loop (and subscriptions are
+/// re-executed on every scan), so ranges older than local history are
+/// refused there.
+pub fn metrics<'x>(ctx: &ContextRef<'x>, filter: String,
+    extract: Option<String>, from: Option<Timestamp>, to: Option<Timestamp>,
+    functions: Option<Vec<String>>)
+    -> Result<Vec<Series>, FieldError>
+{
+    let range = from.is_some() || to.is_some();
+    if range && extract.is_some() {
+        return Err(FieldError::from(
+            "either extract or from/to can be specified"));
+    }
+    if to.is_some() && from.is_none() {
+        return Err(FieldError::from("`to` requires `from`"));
+    }
+    let text = rule_text(&filter, extract.as_ref().map(|x| &x[..]),
+        functions.as_ref().map(|x| &x[..]).unwrap_or(&[]));
+    let mut rule = parse_rule(&text)
+        .map_err(|e| FieldError::from(format!("bad query: {}", e)))?;
+    if range {
+        rule.extract = Extract::TimeRange(
+            from.map(|x| x.to_millis()).unwrap_or(0),
+            to.map(|x| x.to_millis()).unwrap_or(u64::MAX));
+    }
+    let ref h = ctx.stats.history;
+    let start = Instant::now();
+    let mut budget = Budget::new(ctx.stats.query_limits);
+    let cutoff = cutoff(h, &rule);
+    let dataset = if ctx.archive.needs(&rule, cutoff) {
+        if !ctx.may_block {
+            return Err(FieldError::from(
+                "archived data can only be queried over HTTP"));
+        }
+        let dataset = query_series(&rule, h, &mut budget);
+        let item = Item { name: String::new(), rule, dataset, cutoff };
+        // Graphql resolvers are synchronous, so this blocks the thread
+        // for the time of reading snapshots, it's not the main loop
+        match ctx.archive.query(vec![item], budget).wait() {
+            Ok((mut items, archive_budget)) => {
+                budget = archive_budget;
+                items.pop().map(|(_, ds)| ds).unwrap_or(Dataset::Empty)
+            }
+            Err(_) => {
+                GRAPHQL_QUERIES.incr(1);
+                return Err(FieldError::from("archive is unavailable"));
+            }
+        }
+    } else {
+        ctx.stats.query_cache.query(&rule, h, &mut budget)
+    };
+    let cost = budget.cost();
+    GRAPHQL_QUERIES.incr(1);
+    GRAPHQL_SERIES.incr(cost.series as u64);
+    GRAPHQL_POINTS.incr(cost.points as u64);
+    GRAPHQL_TIME.incr(duration_to_millis(start.elapsed()));
+    if budget.exceeded() {
+        GRAPHQL_QUERIES_REFUSED.incr(1);
+    }
+    match dataset {
+        Dataset::SingleSeries(key, chunk, ts) => {
+            Ok(vec![series(&key, chunk, ts)])
+        }
+        Dataset::MultiSeries(vec) => Ok(vec.into_iter()
+            .map(|(key, chunk, ts)| series(&key, chunk, ts))
+            .collect()),
+        Dataset::SingleTip(key, value, slice) => {
+            Ok(vec![tip(&key, value, slice)])
+        }
+        Dataset::MultiTip(vec) => Ok(vec.into_iter()
+            .map(|(key, value, slice)| tip(&key, value, slice))
+            .collect()),
+        Dataset::Chart(_) => {
+            Err(FieldError::from("state charts are not supported"))
+        }
+        Dataset::Empty => Ok(Vec::new()),
+        Dataset::Incompatible(c) => {
+            Err(FieldError::from(format!("query failed: {:?}", c)))
+        }
+    }
+}
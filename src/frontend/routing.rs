@@ -1,4 +1,5 @@
 use std::path::Path;
+use std::str::FromStr;
 use tk_http::server::{Head, WebsocketHandshake};
 
 #[derive(Clone, Debug)]
@@ -23,6 +24,7 @@ pub enum Route {
     Graphql(Format),      // POST
     Status(Format),
     AllProcesses(Format),
+    ProcessesAt(Option<u64>, Format),
     AllSockets(Format),
     AllMetrics(Format),
     AllPeers(Format),
@@ -61,6 +63,19 @@ fn validate_path<P: AsRef<Path>>(path: P) -> bool {
     return true;
 }
 
+/// Returns the value of the parameter in the query string (not decoded)
+fn query_param<'x>(query: &'x str, name: &str) -> Option<&'x str> {
+    query.split('&')
+        .filter_map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
 fn suffix(path: &str) -> &str {
     match path.bytes().rposition(|x| x == b'.' || x == b'/') {
         Some(i) if path.as_bytes()[i] == b'.' => &path[i+1..],
@@ -87,9 +102,9 @@ pub fn route(head: &Head, ws: Option<WebsocketHandshake>) -> Route {
     } else {
         return Route::NotFound;
     };
-    let path = match path.find('?') {
-        Some(x) => &path[..x],
-        None => path,
+    let (path, query) = match path.find('?') {
+        Some(x) => (&path[..x], &path[x+1..]),
+        None => (path, ""),
     };
     let route = match path_component(&path[..]) {
         ("", _) => Index,
@@ -108,6 +123,9 @@ pub fn route(head: &Head, ws: Option<WebsocketHandshake>) -> Route {
         },
         ("status", "") => Status(fmt(path)),
         ("all_processes", "") => AllProcesses(fmt(path)),
+        ("v1", "processes_at") => ProcessesAt(
+            query_param(query, "ts").and_then(|x| u64::from_str(x).ok()),
+            Format::Json),
         ("all_sockets", "") => AllSockets(fmt(path)),
         ("all_metrics", "") => AllMetrics(fmt(path)),
         ("all_peers", "") => AllPeers(fmt(path)),
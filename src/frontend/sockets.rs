@@ -11,7 +11,7 @@ pub fn serve<S: 'static>(stats: &Arc<RwLock<Stats>>, format: Format)
 {
     let stats = stats.clone();
     reply(move |e| {
-        let stats: &Stats = &*stats.read().expect("stats not poisoned");
-        Box::new(respond(e, format, &stats.connections))
+        let stats = Stats::snapshot(&stats);
+        Box::new(respond(e, format, &*stats.connections))
     })
 }
@@ -25,6 +25,16 @@ pub struct StatusData<'a> {
     threads_report: ThreadReport<'a>,
     num_peers: i64,
     num_stale: i64,
+    dropped_values: u64,
+    offenders: Vec<Offender>,
+}
+
+#[derive(Serialize, GraphQLObject)]
+pub struct Offender {
+    /// Source of the values, e.g. `appname=something` or `pid=1234`
+    source: String,
+    /// Number of values refused
+    dropped: f64,
 }
 
 pub struct GData<'a> {
@@ -82,6 +92,12 @@ graphql_object!(<'a> GData<'a>: () as "Status" |&self| {
     field num_stale() -> i32 {
         NUM_STALE.get() as i32
     }
+    field dropped_values() -> f64 {
+        self.ctx.stats.history.dropped().total as f64
+    }
+    field offenders() -> Vec<Offender> {
+        offenders(&self.ctx.stats)
+    }
 });
 
 pub struct GProcessReport(Meter);
@@ -143,7 +159,7 @@ pub fn serve<S: 'static>(meter: &Meter, stats: &Arc<RwLock<Stats>>,
     let meter = meter.clone();
     let stats = stats.clone();
     reply(move |e| {
-        let stats: &Stats = &*stats.read().expect("stats not poisoned");
+        let stats = Stats::snapshot(&stats);
         Box::new(respond(e, format,
             &StatusData {
                 version: env!("CARGO_PKG_VERSION"),
@@ -155,11 +171,23 @@ pub fn serve<S: 'static>(meter: &Meter, stats: &Arc<RwLock<Stats>>,
                 threads_report: meter.thread_report(),
                 num_peers: NUM_PEERS.get(),
                 num_stale: NUM_STALE.get(),
+                dropped_values: stats.history.dropped().total,
+                offenders: offenders(&stats),
             }
         ))
     })
 }
 
+/// Sources of metrics refused because of history limits, worst first
+fn offenders(stats: &Stats) -> Vec<Offender> {
+    stats.history.dropped().offenders().into_iter()
+        .map(|(source, cnt)| Offender {
+            source: source.to_string(),
+            dropped: cnt as f64,
+        })
+        .collect()
+}
+
 pub fn graph<'a>(ctx: &'a ContextRef<'a>) -> Result<GData<'a>, FieldError>
 {
     Ok(GData { ctx })
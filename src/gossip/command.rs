@@ -4,5 +4,7 @@ use std::net::SocketAddr;
 #[derive(Debug)]
 pub enum Command {
     AddHost(SocketAddr),
+    /// Peer is already removed from `Info`, addresses are forgotten too
+    RemovePeer(Vec<SocketAddr>),
 }
 
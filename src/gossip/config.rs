@@ -6,7 +6,7 @@ use std::time::Duration;
 use rand::{thread_rng, Rng};
 
 use id::Id;
-use gossip::Config;
+use gossip::{Config, Secret};
 use time_util::duration_to_millis;
 
 
@@ -17,6 +17,8 @@ pub struct ConfigBuilder {
     hostname: Option<String>,
     bind: Option<SocketAddr>,
     addresses: Vec<SocketAddr>,
+    /// Packets are signed and checked by this secret if set
+    secret: Option<Secret>,
 
     /// Wake up once per 1000 ms to send few probes
     interval: Duration,
@@ -97,6 +99,7 @@ impl Config {
             hostname: None,
             bind: None,
             addresses: Vec::new(),
+            secret: None,
 
             interval: Duration::new(1, 0),
             num_pings_to_send: 10,
@@ -163,6 +166,10 @@ impl ConfigBuilder {
         self.addresses = addresses.to_vec();
         self
     }
+    pub fn secret(&mut self, secret: Option<&Secret>) -> &mut Self {
+        self.secret = secret.cloned();
+        self
+    }
     pub fn done(&mut self) -> Arc<Config> {
         Arc::new(Config {
             machine_id: self.machine_id.clone().expect("machine_id"),
@@ -174,6 +181,7 @@ impl ConfigBuilder {
             addresses: self.addresses.clone(),
             str_addresses: Arc::new(
                 self.addresses.iter().map(ToString::to_string).collect()),
+            secret: self.secret.clone(),
 
             interval: self.interval,
             num_pings_to_send: self.num_pings_to_send,
@@ -5,6 +5,7 @@ mod peer;
 mod info;
 mod command;
 mod public;
+mod secret;
 
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
@@ -20,11 +21,14 @@ use id::Id;
 use incoming;
 use storage::Storage;
 
-pub use self::peer::Peer;
+pub use self::peer::{Peer, Filter as PeerFilter};
 pub use self::errors::InitError;
 pub use self::public::{Gossip, noop};
 pub use self::info::Info;
 pub use self::proto::{NUM_PEERS, NUM_STALE};
+pub use self::proto::{REJECTED_SIGNATURE, REJECTED_CLUSTER};
+pub use self::proto::{REJECTED_MALFORMED, REJECTED_STALE};
+pub use self::secret::Secret;
 
 
 /// Fields are documented in `config.rs`
@@ -37,6 +41,7 @@ pub struct Config {
     #[allow(dead_code)]
     addresses: Vec<SocketAddr>,
     str_addresses: Arc<Vec<String>>,
+    secret: Option<Secret>,
 
     interval: Duration,
     num_pings_to_send: u64,
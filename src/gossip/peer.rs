@@ -63,6 +63,24 @@ graphql_object!(Peer: () as "Peer" |&self| {
     }
 });
 
+#[derive(GraphQLInputObject)]
+#[graphql(name="PeerFilter", description="Filter for peers")]
+pub struct Filter {
+    /// Only peers known since this time or later
+    known_since_after: Option<Timestamp>,
+    /// Only peers known since this time or earlier
+    known_since_before: Option<Timestamp>,
+}
+
+impl Filter {
+    pub fn matches(&self, peer: &Peer) -> bool {
+        self.known_since_after.as_ref()
+            .map(|ts| ts.to_millis() <= peer.known_since).unwrap_or(true) &&
+        self.known_since_before.as_ref()
+            .map(|ts| peer.known_since <= ts.to_millis()).unwrap_or(true)
+    }
+}
+
 impl Peer {
     pub fn new(id: Id) -> Peer {
         Peer {
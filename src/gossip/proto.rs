@@ -1,4 +1,4 @@
-use std::cmp::{PartialOrd, Ordering, min};
+use std::cmp::{PartialOrd, Ordering, min, max};
 use std::collections::{HashMap, BinaryHeap};
 use std::io::Write;
 use std::mem;
@@ -25,14 +25,28 @@ use gossip::peer::{Report, Peer};
 use id::Id as HostId;
 use storage::Storage;
 use time_util::time_ms;
-use libcantal::Integer;
+use libcantal::{Integer, Counter};
 use incoming::{self, Subscription};
 
 lazy_static! {
     pub static ref NUM_PEERS: Integer = Integer::new();
     pub static ref NUM_STALE: Integer = Integer::new();
+    /// Packets without a valid signature (when cluster secret is set)
+    pub static ref REJECTED_SIGNATURE: Counter = Counter::new();
+    /// Packets with a different cluster name
+    pub static ref REJECTED_CLUSTER: Counter = Counter::new();
+    /// Packets that can't be decoded
+    pub static ref REJECTED_MALFORMED: Counter = Counter::new();
+    /// Signed packets with a timestamp too far from the local time
+    pub static ref REJECTED_STALE: Counter = Counter::new();
 }
 
+/// Signed packets are rejected if their timestamp differs from the local
+/// time by more than this number of milliseconds, so captured packets
+/// can't be replayed later. Pings carry the clock of the sender, so
+/// clocks of the nodes must be roughly in sync.
+const MAX_CLOCK_SKEW: u64 = 30_000;
+
 #[derive(Eq)]
 struct FutureHost {
     deadline: Instant,
@@ -103,6 +117,17 @@ pub struct FriendInfo {
     pub roundtrip: Option<(u64, u64)>,
 }
 
+/// Checks that the packet was sent (or, for pongs, the ping was sent)
+/// recently
+fn is_fresh(packet: &Packet, tm: u64) -> bool {
+    let sent = match *packet {
+        Packet::Ping { now, .. } => now,
+        // ping time is our own timestamp returned by the peer
+        Packet::Pong { ping_time, .. } => ping_time,
+    };
+    max(sent, tm) - min(sent, tm) <= MAX_CLOCK_SKEW
+}
+
 
 impl<S: Stream<Item=Command, Error=Void>> Proto<S> {
     pub fn new(info: &Arc<Mutex<Info>>, config: &Arc<Config>, stream: S,
@@ -221,6 +246,14 @@ impl<S: Stream<Item=Command, Error=Void>> Proto<S> {
                         }
                     }
                 }
+                RemovePeer(addresses) => {
+                    for addr in &addresses {
+                        self.addr_status.remove(addr);
+                    }
+                    self.store_peers();
+                    self.update_metrics();
+                    self.incoming.trigger(Subscription::Peers);
+                }
             }
         }
         Ok(())
@@ -232,12 +265,32 @@ impl<S: Stream<Item=Command, Error=Void>> Proto<S> {
         assert!(buf.len() == self.config.max_packet_size);
 
         while let Ok((bytes, addr)) = self.sock.recv_from(&mut buf) {
-            match from_slice(&buf[..bytes]) {
+            let data = match self.config.secret {
+                Some(ref secret) => match secret.verify(&buf[..bytes]) {
+                    Some(data) => data,
+                    None => {
+                        REJECTED_SIGNATURE.incr(1);
+                        debug!("Packet with invalid signature from {:?}",
+                            addr);
+                        continue;
+                    }
+                },
+                None => &buf[..bytes],
+            };
+            match from_slice(data) {
                 Ok(packet) => {
                     trace!("Packet {:?} from {:?}", packet, addr);
+                    if self.config.secret.is_some() &&
+                        !is_fresh(&packet, time_ms())
+                    {
+                        REJECTED_STALE.incr(1);
+                        debug!("Stale packet from {:?}", addr);
+                        continue;
+                    }
                     self.consume_gossip(packet, addr);
                 }
                 Err(e) => {
+                    REJECTED_MALFORMED.incr(1);
                     warn!("Errorneous packet from {:?}: {}",
                         addr, e);
                 }
@@ -256,6 +309,7 @@ impl<S: Stream<Item=Command, Error=Void>> Proto<S> {
             Packet::Ping { cluster,  me: pinfo, now, friends } => {
                 {
                     if cluster != self.config.cluster_name {
+                        REJECTED_CLUSTER.incr(1);
                         info!("Got packet from cluster {:?}", cluster);
                         return;
                     }
@@ -309,6 +363,9 @@ impl<S: Stream<Item=Command, Error=Void>> Proto<S> {
                         friends: info.get_friends(addr, &self.config),
                     }).unwrap();
                 }
+                if let Some(ref secret) = self.config.secret {
+                    secret.sign(buf);
+                }
 
                 if buf.len() >= self.config.max_packet_size {
                     // Unfortunately cbor encoder doesn't report error of
@@ -330,6 +387,7 @@ impl<S: Stream<Item=Command, Error=Void>> Proto<S> {
             => {
                 {
                     if cluster != self.config.cluster_name {
+                        REJECTED_CLUSTER.incr(1);
                         info!("Got packet from cluster {:?}", cluster);
                         return;
                     }
@@ -396,6 +454,9 @@ impl<S: Stream<Item=Command, Error=Void>> Proto<S> {
                 friends: info.get_friends(addr, &self.config),
             }).unwrap();
         }
+        if let Some(ref secret) = self.config.secret {
+            secret.sign(buf);
+        }
         if buf.len() >= self.config.max_packet_size {
             // Unfortunately cbor encoder doesn't report error of truncated
             // data so we consider full buffer the truncated data
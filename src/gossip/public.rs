@@ -1,3 +1,4 @@
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
@@ -41,8 +42,36 @@ impl Gossip {
                 .expect("can always send add host");
         }
     }
+    /// Returns false for a no-op gossip (when cluster name is not set)
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+    /// Returns true if some known peer has the address
+    pub fn has_address(&self, addr: SocketAddr) -> bool {
+        let info = self.info.lock().expect("gossip is not poisoned");
+        info.peers.values().any(|peer| {
+            peer.primary_addr == Some(addr) || peer.addresses.contains(&addr)
+        })
+    }
+    /// Removes peer from the list of known peers, returns removed peer
+    ///
+    /// Peer will be added back if it sends a packet to us or if other
+    /// peers report it as their friend.
+    pub fn remove_peer(&self, id: &Id) -> Option<Arc<Peer>> {
+        let peer = {
+            let mut info = self.info.lock().expect("gossip is not poisoned");
+            info.peers.remove(id)
+        };
+        if let (&Some(ref peer), &Some(ref sender)) = (&peer, &self.sender) {
+            let addresses = peer.addresses.iter().cloned()
+                .chain(peer.primary_addr)
+                .collect();
+            sender.unbounded_send(Command::RemovePeer(addresses))
+                .expect("can always send remove peer");
+        }
+        return peer;
+    }
     /// Number of peers total and those having "remote" enabled
-    #[allow(dead_code)] // temporarily
     pub fn get_peer_numbers(&self) -> (usize, usize) {
         let info = self.info.lock().expect("gossip is not poisoned");
         let num_remote = info.peers.iter()
@@ -55,10 +84,10 @@ impl Gossip {
         return (info.peers.len(), num_remote);
     }
 
-    #[allow(dead_code)] // temporarily
-    pub fn notify_remote(&self, value: bool) {
+    /// Sets whether "remote" is enabled on this host, returns previous value
+    pub fn notify_remote(&self, value: bool) -> bool {
         let mut info = self.info.lock().expect("gossip is not poisoned");
-        info.has_remote = value;
+        mem::replace(&mut info.has_remote, value)
     }
 
     pub fn get_peers(&self) -> Vec<Arc<Peer>> {
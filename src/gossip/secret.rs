@@ -0,0 +1,129 @@
+//! Signing of gossip packets by a shared cluster secret
+//!
+//! Packets are signed by keyed BLAKE2b, the 64-byte tag is appended to
+//! the CBOR-encoded packet. Packets are signed by the first key and ones
+//! signed by any of the keys are accepted, so keys can be rotated without
+//! splitting the cluster.
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use blake2::Blake2b;
+use crypto_mac::Mac;
+use failure::{Error, err_msg};
+
+
+/// Size of the signature appended to each packet
+pub const TAG_SIZE: usize = 64;
+/// Maximum size of a key supported by BLAKE2b
+const MAX_KEY_SIZE: usize = 64;
+/// At most two keys can be active: the current one and the previous
+/// (or the next) one
+const MAX_KEYS: usize = 2;
+
+
+#[derive(Clone)]
+pub struct Secret {
+    keys: Vec<Vec<u8>>,
+}
+
+impl Secret {
+    /// Reads keys from file, one key per line
+    ///
+    /// Empty lines and lines starting with `#` are skipped.
+    pub fn read(path: &Path) -> Result<Secret, Error> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| err_msg(format!("can't read {:?}: {}", path, e)))?;
+        Secret::from_lines(&text)
+    }
+    fn from_lines(text: &str) -> Result<Secret, Error> {
+        let keys = text.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with("#"))
+            .map(|line| line.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        if keys.is_empty() {
+            return Err(err_msg("no keys in cluster secret file"));
+        }
+        if keys.len() > MAX_KEYS {
+            return Err(err_msg(format!(
+                "at most {} keys are allowed in cluster secret file",
+                MAX_KEYS)));
+        }
+        if keys.iter().any(|k| k.len() > MAX_KEY_SIZE) {
+            return Err(err_msg(format!(
+                "keys in cluster secret file must be at most {} bytes",
+                MAX_KEY_SIZE)));
+        }
+        Ok(Secret { keys })
+    }
+    fn mac(key: &[u8]) -> Blake2b {
+        <Blake2b as Mac>::new(key).expect("key size is checked")
+    }
+    /// Appends signature to the encoded packet
+    pub fn sign(&self, buf: &mut Vec<u8>) {
+        let mut mac = Secret::mac(&self.keys[0]);
+        mac.input(&buf[..]);
+        let tag = mac.result().code();
+        buf.extend_from_slice(&tag[..]);
+    }
+    /// Returns packet data without the signature if it is signed by any
+    /// of the keys
+    pub fn verify<'x>(&self, buf: &'x [u8]) -> Option<&'x [u8]> {
+        if buf.len() < TAG_SIZE {
+            return None;
+        }
+        let (data, tag) = buf.split_at(buf.len() - TAG_SIZE);
+        for key in &self.keys {
+            let mut mac = Secret::mac(key);
+            mac.input(data);
+            if mac.verify(tag).is_ok() {
+                return Some(data);
+            }
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Secret, TAG_SIZE};
+
+    fn signed(keys: &str, data: &[u8]) -> Vec<u8> {
+        let mut buf = data.to_vec();
+        Secret::from_lines(keys).unwrap().sign(&mut buf);
+        return buf;
+    }
+
+    #[test]
+    fn test_sign() {
+        let secret = Secret::from_lines("key1").unwrap();
+        let buf = signed("key1", b"hello");
+        assert_eq!(buf.len(), 5 + TAG_SIZE);
+        assert_eq!(secret.verify(&buf), Some(&b"hello"[..]));
+        assert_eq!(secret.verify(b"hello"), None);
+        assert_eq!(secret.verify(&signed("key2", b"hello")), None);
+        let mut bad = buf.clone();
+        bad[0] = b'j';
+        assert_eq!(secret.verify(&bad), None);
+    }
+
+    #[test]
+    fn test_rotation() {
+        // new key is added first, then becomes primary, then old is removed
+        let old = Secret::from_lines("old").unwrap();
+        let both = Secret::from_lines("# rotating\nnew\nold\n").unwrap();
+        assert!(both.verify(&signed("old", b"x")).is_some());
+        assert!(both.verify(&signed("new", b"x")).is_some());
+        assert!(old.verify(&signed("old\nnew", b"x")).is_some());
+        assert!(old.verify(&signed("new\nold", b"x")).is_none());
+    }
+
+    #[test]
+    fn test_bad_keys() {
+        assert!(Secret::from_lines("\n# comment\n").is_err());
+        assert!(Secret::from_lines("a\nb\nc").is_err());
+        assert!(Secret::from_lines(&"x".repeat(65)).is_err());
+    }
+}
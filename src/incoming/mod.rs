@@ -157,6 +157,10 @@ impl Incoming {
         }
      }
      pub fn trigger(&self, subscription: &Subscription) {
+        if *subscription == Subscription::Scan {
+            self.0.context.stats.read().expect("stats not poisoned")
+                .query_cache.expire();
+        }
         let conns = self.0.state.lock().expect("lock is not poisoned")
             .subscriptions.get(&subscription)
             .map(|x| x.clone())
@@ -1,9 +1,12 @@
 extern crate anymap;
 extern crate argparse;
+extern crate blake2;
 extern crate byteorder;
 extern crate cbor;
+extern crate crypto_mac;
 extern crate env_logger;
 extern crate failure;
+extern crate flate2;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate graphql_parser;
@@ -46,8 +49,8 @@ extern crate cantal_values as cantal;
 extern crate cantal_history as history;
 extern crate cantal_query as query;
 
+use std::cmp::min;
 use std::thread;
-use std::io::BufReader;
 use std::fs::File;
 use std::net::SocketAddr;
 use std::str::FromStr;
@@ -56,7 +59,7 @@ use std::sync::{RwLock, Arc};
 use std::process::{exit};
 use std::time::Duration;
 
-use failure::Error;
+use failure::{Error, err_msg};
 use argparse::{ArgumentParser, Store, ParseOption, StoreOption, Parse, Print};
 use argparse::{StoreTrue};
 use rustc_serialize::json::Json;
@@ -64,6 +67,7 @@ use tk_easyloop::handle;
 
 use deps::{Dependencies, LockedDeps};
 
+mod archive;
 mod carbon;
 mod configs;
 mod deps;
@@ -79,6 +83,7 @@ mod scanner;
 mod stats;
 mod storage;
 mod time_util;
+mod wal;
 mod watchdog;
 
 
@@ -105,9 +110,14 @@ fn run() -> Result<(), Error> {
     let mut config_dir = PathBuf::from("/etc/cantal");
     let mut machine_id = None::<id::Id>;
     let mut cluster_name = None::<String>;
+    let mut cluster_secret_file = None::<PathBuf>;
     let mut scan_interval = 2000;
     let mut bind_localhost = false;
     let mut backlog_time = humantime::Duration::from_str("1 hour").unwrap();
+    let mut coarse_time = humantime::Duration::from_str("1 day").unwrap();
+    let mut history_limits = history::Limits::unlimited();
+    let mut query_limits = query::Limits::unlimited();
+    let mut max_query_time = None::<humantime::Duration>;
     {
         let mut ap = ArgumentParser::new();
         ap.add_option(&["--version"],
@@ -143,12 +153,56 @@ fn run() -> Result<(), Error> {
             "Sets amount of history that is stored by cantal in-memory.
              If this value is set to less that 1 hour we also disable hourly
              snapshots (because it makes them useless)");
+        ap.refer(&mut coarse_time)
+            .add_option(&["--keep-coarse-history"], Store,
+            "Sets amount of history with one-minute resolution that is
+             kept in-memory (default 1 day). Values are rolled up into this
+             history when they are removed from the fine-grained one.
+             Set to zero to disable.");
+        ap.refer(&mut history_limits.max_keys)
+            .add_option(&["--max-history-keys"], Store,
+            "Maximum number of keys (series) stored in each of fine, coarse
+             and tip history. When limit is reached new keys are refused
+             from sources that have more keys than their share (the limit
+             divided by the number of sources), these sources are reported
+             in `/status`. Coarse history allows four times more keys, as
+             integers and floats are rolled up into four series (min, max,
+             avg and last). Unlimited by default.");
+        ap.refer(&mut history_limits.max_bytes)
+            .add_option(&["--max-history-bytes"], Store,
+            "Maximum (approximate) number of bytes used by each of fine,
+             coarse and tip history. Works the same way as
+             `--max-history-keys`. Unlimited by default.");
+        ap.refer(&mut query_limits.max_series)
+            .add_option(&["--max-query-series"], Store,
+            "Maximum number of series extracted from history by a single
+             request to `/query`. Rules exceeding the limit return
+             an error instead of a dataset. Unlimited by default.");
+        ap.refer(&mut query_limits.max_points)
+            .add_option(&["--max-query-points"], Store,
+            "Maximum number of data points extracted from history by
+             a single request to `/query`. Unlimited by default.");
+        ap.refer(&mut max_query_time)
+            .add_option(&["--max-query-time"], StoreOption,
+            "Maximum time spent on a single request to `/query`. While
+             query runs, other requests and gossip packets are not
+             processed. Values above one day are clamped to one day.
+             Unlimited by default.");
         ap.refer(&mut cluster_name)
             .add_option(&["-n", "--cluster-name"], StoreOption, "
                 A name of the cluster. If cantal receives ping packet with
                 mismatching cluster name it discards the packet. If name is
                 not specified, cantal will not support discovery.
             ");
+        ap.refer(&mut cluster_secret_file)
+            .add_option(&["--cluster-secret-file"], ParseOption, "
+                A file with a shared secret used to sign gossip packets.
+                Packets without a valid signature are discarded. The file
+                may contain two keys (one per line): packets are signed by
+                the first one and accepted if signed by either, which allows
+                rotating keys without splitting the cluster. Requires
+                `--cluster-name`.
+            ");
         ap.refer(&mut machine_id)
             .add_option(&["--override-machine-id"], StoreOption, "
                 Overrides machine id. Do not use in production, put the
@@ -169,8 +223,6 @@ fn run() -> Result<(), Error> {
     let meter = self_meter_http::Meter::new();
     meter.track_current_thread("main");
 
-    let configs = Arc::new(configs::read(&config_dir));
-
     let hostname = info::hostname().unwrap();
     let addresses = info::my_addresses(port).unwrap();
     let name = name.unwrap_or(hostname.clone());
@@ -181,9 +233,25 @@ fn run() -> Result<(), Error> {
         name.clone(), hostname.clone(), cluster_name.clone(),
         &machine_id,
         addresses.iter().map(|x| x.to_string()).collect())));
+    query_limits.max_time = max_query_time.map(|x| {
+        min(*x, Duration::from_secs(query::MAX_QUERY_SECONDS))
+    });
+    {
+        let mut stats = stats.write().expect("stats not poisoned");
+        Arc::make_mut(&mut stats.history).set_limits(history_limits);
+        stats.query_limits = query_limits;
+    }
     let mut deps = Dependencies::new();
     deps.insert(stats.clone());
 
+    let secret = match cluster_secret_file {
+        Some(_) if cluster_name.is_none() => {
+            return Err(err_msg(
+                "--cluster-secret-file requires --cluster-name"));
+        }
+        Some(ref path) => Some(gossip::Secret::read(path)?),
+        None => None,
+    };
     let (graphql_tx, graphql_rx) = incoming::channel::new();
     let (gossip, gossip_init) = cluster_name.as_ref().map(|cluster| {
         gossip::Config::new()
@@ -193,6 +261,7 @@ fn run() -> Result<(), Error> {
         .addresses(&addresses)
         .hostname(&hostname)
         .name(&name)
+        .secret(secret.as_ref())
         .done()
     }).map(|x| gossip::init(&x))
       .map(|(g, i)| (g, Some(i)))
@@ -200,36 +269,22 @@ fn run() -> Result<(), Error> {
     deps.insert(gossip.clone());
 
     let storage = Arc::new(storage::Storage::new());
-    deps.insert(storage.clone());
+    // Scanner queues scans and snapshots for the storage thread, so
+    // storage is only available to it if the thread is running
+    if storage_dir.is_some() {
+        deps.insert(storage.clone());
+    }
 
     let _storage = storage_dir.as_ref().map(|path| {
         let mydeps = deps.clone();
-        let cborcfg = probor::Config {
-            max_len_array: 100000,
-            max_len_bytes: 0x500000,
-            max_len_text: 0x500000,
-            max_size_map: 100000,
-            max_nesting: 16,
-            .. probor::Config::default()
-        };
-        let result = File::open(&path.join("current.cbor"))
-            .map_err(|e| error!("Error reading old data: {}. Ignoring...", e))
-            .map(BufReader::new)
-            .map(|f| probor::Decoder::new(cborcfg, f))
-            .and_then(|mut dec| {
-                let v: history::VersionInfo = try!(probor::decode(&mut dec)
-                    .map_err(|_| error!("Can't decode version info. \
-                        Ignoring...")));
-                if v != history::VersionInfo::current() {
-                    error!("Old version of history data. Ignoring...");
-                    return Err(());
-                }
-                probor::decode(&mut dec)
-                    .map_err(|e| error!(
-                        "Error parsing old data: {}. Ignoring...", e))
-            });
-        if let Ok(history) = result {
-            mydeps.write::<stats::Stats>().history = history;
+        let result = storage::read_snapshot(&path.join("current.cbor"));
+        {
+            let mut stats = mydeps.write::<stats::Stats>();
+            if let Ok(mut history) = result {
+                history.set_limits(history_limits);
+                stats.history = Arc::new(history);
+            }
+            wal::replay(&path, Arc::make_mut(&mut stats.history));
         }
         let path = path.clone();
         let mymeter = meter.clone();
@@ -255,10 +310,19 @@ fn run() -> Result<(), Error> {
         .ok();
     }
 
+    let archive = storage_dir.as_ref()
+        .map(|path| archive::Archive::new(path, &meter))
+        .unwrap_or_else(archive::Archive::empty);
+
+    let sinks = carbon::Sinks::new(&config_dir, &stats);
+
     let graphql = frontend::graphql::Context {
         meter: meter.clone(),
         stats: stats.clone(),
         gossip: gossip.clone(),
+        archive: archive,
+        storage: storage_dir.as_ref().map(|_| storage.clone()),
+        carbon: sinks.clone(),
     };
 
     let mydeps = deps.clone();
@@ -269,7 +333,7 @@ fn run() -> Result<(), Error> {
         mymeter.track_current_thread("scan");
         scanner::scan_loop(mydeps,
             Duration::from_millis(scan_interval),
-            *backlog_time, &mygraphtx);
+            *backlog_time, *coarse_time, &mygraphtx);
     });
 
 
@@ -284,7 +348,7 @@ fn run() -> Result<(), Error> {
         let incoming = incoming::Incoming::new(&graphql);
         graphql_rx.start(&incoming);
 
-        carbon::spawn_sinks(&ns, &configs, &stats)?;
+        sinks.start(&ns)?;
         http::spawn_listener(&ns, &host, port, bind_localhost,
             &meter, &stats, &gossip, &incoming, &graphql)?;
 
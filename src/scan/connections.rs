@@ -7,27 +7,27 @@ use std::collections::{HashMap, HashSet};
 const MAX_CONNECTION_DETAILS: usize = 1000;
 
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Stats {
     pub by_state: HashMap<State, usize>,
     pub rx_queue: usize,
     pub tx_queue: usize,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Passive {
     pub stats: Stats,
     pub listeners: Vec<Socket>,
     pub clients: Option<Vec<Socket>>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Active {
     pub stats: Stats,
     pub connections: Option<Vec<Socket>>,
 }
 
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Socket {
     pub local_address: SocketAddr,
     pub remote_address: SocketAddr,
@@ -38,7 +38,7 @@ pub struct Socket {
 }
 
 #[allow(dead_code, non_camel_case_types)]
-#[derive(Clone, Copy, Debug, Serialize, Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub enum State {
     UNKNOWN = 0,
     ESTABLISHED,
@@ -54,7 +54,7 @@ pub enum State {
     CLOSING,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Connections {
     pub global: Stats,
     pub by_user: HashMap<u32, Stats>,
@@ -8,7 +8,6 @@ use std::collections::{HashMap, HashSet};
 use libc;
 
 use cantal::itertools::{NextValue, NextStr};
-use frontend::graphql::ContextRef;
 use history::Key;
 use scan::cgroups::CGroups;
 use super::Tip;
@@ -22,7 +21,7 @@ pub struct ReadCache {
     prev_processes: usize,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MinimalProcess {
     pub pid: Pid,
     pub ppid: Pid,
@@ -46,7 +45,7 @@ pub struct MinimalProcess {
     pub cgroup: Option<Arc<String>>,
 }
 
-graphql_object!(<'a> &'a MinimalProcess: ContextRef<'a> as "Process" |&self| {
+graphql_object!(MinimalProcess: () as "Process" |&self| {
     field pid() -> i32 { self.pid as i32 }
     field ppid() -> i32 { self.ppid as i32 }
     field num_threads() -> i32 { self.num_threads as i32 }
@@ -4,6 +4,7 @@ use std::time::{Duration, Instant};
 use std::thread::sleep;
 
 use probor::{Encoder, Encodable};
+use serde_cbor;
 
 use super::stats::Stats;
 use super::scan::Tip;
@@ -14,30 +15,50 @@ use super::scan::values;
 use super::scan::time_ms;
 use super::scan::cgroups;
 use super::deps::{Dependencies, LockedDeps};
-use cantal::Value;
-use history::VersionInfo;
+use history::{History, VersionInfo};
 use storage::{Storage, MetricBuffer};
+use storage::{ProcessBuffer, ProcessSnapshot};
+use wal::Scan;
 
 use incoming::{channel::Sender as Incoming, Subscription};
 
 
 const SNAPSHOT_INTERVAL: u64 = 60000;
+/// Maximum interval between compactions of the write-ahead log
+const COMPACTION_INTERVAL: u64 = 600000;
+/// Interval of storing the process table and sockets
+const PROCESSES_INTERVAL: u64 = 300000;
 
 fn to_ms(dur: Duration) -> u64 {
     return dur.as_secs() * 1000 + dur.subsec_nanos() as u64 / 1000_000;
 }
 
+fn truncate_fine(history: &mut History, tstamp: u64, coarse_time: Duration) {
+    if coarse_time > Duration::new(0, 0) {
+        history.truncate_fine(tstamp);
+    } else {
+        // Coarse history is disabled, don't waste time rolling it up
+        history.fine.truncate_by_time(tstamp);
+    }
+}
+
 pub fn scan_loop(deps: Dependencies, interval: Duration,
-    backlog_time: Duration, incoming: &Incoming)
+    backlog_time: Duration, coarse_time: Duration, incoming: &Incoming)
 {
     let stats: &RwLock<Stats> = &*deps.copy();
     let storage = deps.get::<Arc<Storage>>().map(|x| &*x);
     let mut last_store = time_ms();
+    let mut last_compaction = last_store;
+    let mut last_processes = 0;
     let mut last_scan = time_ms() - to_ms(interval);
     let mut last_hourly = last_store / 3_600_000;
     let mut process_cache = processes::ReadCache::new();
     let mut values_cache = values::ReadCache::new();
     let mut last_buffer_size = 16 << 10;
+    // History is only modified here, readers get an immutable copy
+    // published on every scan
+    let mut history = (*stats.read().expect("stats not poisoned").history)
+        .clone();
     loop {
         let start = time_ms();
         if start < last_scan {
@@ -60,72 +81,125 @@ pub fn scan_loop(deps: Dependencies, interval: Duration,
         // This is needed for values::read to attribute metrics revering to
         // the values file consistently to the same process
         processes.sort_unstable_by_key(|p| p.pid);
-        let connections = connections::read();
+        let mut connections = connections::read();
         processes::write_tip(&mut tip, &processes, &cgroups);
         values::read(&mut tip, &mut values_cache, &processes, &cgroups);
 
         let scan_duration = to_ms(start_instant.elapsed()) as u32;
+        let scan = Scan {
+            timestamp: (start, scan_duration),
+            values: tip.map,
+        };
+
+        if let Some(storage) = storage {
+            if start.saturating_sub(last_processes) > PROCESSES_INTERVAL {
+                last_processes = start;
+                let snapshot = ProcessSnapshot {
+                    timestamp: start,
+                    boot_time: boot_time,
+                    processes: processes,
+                    connections: connections,
+                };
+                serde_cbor::to_vec(&snapshot)
+                .map(|data| storage.store_processes(ProcessBuffer {
+                    timestamp: start,
+                    data: data.into_boxed_slice(),
+                }))
+                .map_err(|e| error!("Can't encode processes: {}", e)).ok();
+                processes = snapshot.processes;
+                connections = snapshot.connections;
+            }
+        }
 
-        if let Ok(ref mut stats) = stats.write() {
-            stats.scan_duration = scan_duration;
-            debug!("Got {} values and {} processes in {} ms",
-                tip.map.len(), processes.len(), scan_duration);
-
-            // TODO(tailhook) use drain-style iterator and push to both
-            // at once, so we don't need clone (each metric)
-            stats.history.tip.push((start, scan_duration), tip.map.iter()
-                .filter(|&(_, v)| matches!(v, &Value::State(_))));
-            stats.history.fine.push((start, scan_duration), tip.map.iter()
-                .filter(|&(_, v)| !matches!(v, &Value::State(_))));
+        debug!("Got {} values and {} processes in {} ms",
+            scan.values.len(), processes.len(), scan_duration);
+        scan.apply(&mut history);
+
+        let mut compaction = None;
+        let requested = storage
+            .map(|s| s.take_snapshot_request()).unwrap_or(false);
+        if requested ||
+            start.saturating_sub(last_store) > SNAPSHOT_INTERVAL
+        {
+            last_store = start;
+
+            // Don't store tip values older than a minute
+            history.tip.truncate_by_time(
+                start - min(to_ms(backlog_time), 60000));
+
+            let mut snapshot = None;
+            if backlog_time > Duration::new(3600, 0) {
+                let hourly = start / 3_600_000;
+                if hourly > last_hourly {
+                    truncate_fine(&mut history,
+                        start - to_ms(backlog_time), coarse_time);
+                    snapshot = Some(format!("hourly-{}", hourly));
+                    last_hourly = hourly;
+                }
+            } else {
+                // Never store hourly snapshot if backlog time less than
+                // an hour
+                truncate_fine(&mut history,
+                    start - to_ms(backlog_time), coarse_time);
+            }
+            history.coarse.truncate_by_time(
+                start.saturating_sub(to_ms(coarse_time)));
+
+            // Scans are stored in the write-ahead log, so we only need
+            // to compact it into a snapshot when the log grows larger
+            // than the snapshot itself, or from time to time to make
+            // startup faster
+            let log_size = stats.read().expect("stats not poisoned")
+                .storage.log_size;
+            let since_compaction = start.saturating_sub(last_compaction);
+            let compact = storage.is_some() && (requested ||
+                snapshot.is_some() ||
+                log_size > last_buffer_size ||
+                since_compaction > COMPACTION_INTERVAL);
+            if compact {
+                last_compaction = start;
+                compaction = Some(snapshot);
+            }
+        }
 
+        // Only the newest bytes of every value are copied, older data
+        // points are shared with the previously published history
+        let published = Arc::new(history.clone());
+        if let Ok(ref mut guard) = stats.write() {
+            let stats: &mut Stats = &mut **guard;
+            stats.scan_duration = scan_duration;
+            stats.history = published.clone();
             stats.last_scan = start;
             stats.boot_time = boot_time.or(stats.boot_time);
-            stats.processes = processes;
-            stats.connections = connections;
-
-            if start.saturating_sub(last_store) > SNAPSHOT_INTERVAL {
-                last_store = start;
-
-                // Don't store tip values older than a minute
-                stats.history.tip.truncate_by_time(
-                    start - min(to_ms(backlog_time), 60000));
-
-                let mut snapshot = None;
-                if backlog_time > Duration::new(3600, 0) {
-                    let hourly = start / 3_600_000;
-                    if hourly > last_hourly {
-                        stats.history.fine.truncate_by_time(
-                            start - to_ms(backlog_time));
-                        snapshot = Some(format!("hourly-{}", hourly));
-                        last_hourly = hourly;
-                    }
-                } else {
-                    // Never store hourly snapshot if backlog time less than
-                    // an hour
-                    stats.history.fine.truncate_by_time(
-                        start - to_ms(backlog_time));
+            stats.processes = Arc::new(processes);
+            stats.connections = Arc::new(connections);
+        }
+        // Scans must be queued before the snapshot containing them
+        if let Some(storage) = storage {
+            storage.append_scan(scan);
+        }
+        if let Some(snapshot) = compaction {
+            // Preallocate a buffer of same size as previous one,
+            // since it's expected about same size. But add few kb,
+            // so that 99% of the time no further allocations are
+            // necessary
+            let mut enc = Encoder::new(
+                Vec::with_capacity(last_buffer_size + 16384));
+            VersionInfo::current().encode(&mut enc)
+            .and_then(|()| published.encode(&mut enc))
+            .map(|()| {
+                let buf = enc.into_writer();
+                last_buffer_size = buf.len();
+
+                if let Some(storage) = storage {
+                    storage.store_metrics(MetricBuffer {
+                        timestamp: start,
+                        snapshot: snapshot,
+                        data: buf.into_boxed_slice(),
+                    });
                 }
-
-                // Preallocate a buffer of same size as previous one, since
-                // it's expected about same size. But add few kb, so that
-                // 99% of the time no further allocations are necessary
-                let mut enc = Encoder::new(
-                    Vec::with_capacity(last_buffer_size + 16384));
-                VersionInfo::current().encode(&mut enc)
-                .and_then(|()| stats.history.encode(&mut enc))
-                .map(|()| {
-                    let buf = enc.into_writer();
-                    last_buffer_size = buf.len();
-
-                    if let Some(storage) = storage {
-                        storage.store_metrics(MetricBuffer {
-                            timestamp: start,
-                            snapshot: snapshot,
-                            data: buf.into_boxed_slice(),
-                        });
-                    }
-                }).map_err(|e| error!("Can't encode history: {}", e)).ok();
-            }
+            }).map_err(|e| error!("Can't encode history: {}", e))
+            .ok();
         }
         last_scan = start;
         incoming.trigger(Subscription::Scan);
@@ -1,13 +1,21 @@
 use std::default::Default;
+use std::sync::{Arc, RwLock};
 
 use id::Id;
 use super::scan::time_ms;
 use super::scan;
 use history::History;
+use query::{Cache, Limits as QueryLimits};
 use super::storage::StorageStats;
 
 
-#[derive(Debug)]
+/// Statistics of the agent, kept behind a `RwLock`
+///
+/// Large fields are immutable and shared by `Arc`, the scanner replaces
+/// them (or copies on write if a reader still holds the previous
+/// version). Readers take a `snapshot()` and release the lock immediately,
+/// so slow readers never delay scans.
+#[derive(Debug, Clone)]
 pub struct Stats {
     pub id: Id,
     pub addresses_str: Vec<String>,
@@ -21,9 +29,13 @@ pub struct Stats {
     pub boot_time: Option<u64>,
 
     pub storage: StorageStats,
-    pub history: History,
-    pub processes: Vec<scan::processes::MinimalProcess>,
-    pub connections: Option<scan::connections::Connections>,
+    pub history: Arc<History>,
+    pub processes: Arc<Vec<scan::processes::MinimalProcess>>,
+    pub connections: Arc<Option<scan::connections::Connections>>,
+    /// Results of queries to the history, expired on every scan
+    pub query_cache: Arc<Cache>,
+    /// Limits of a single query
+    pub query_limits: QueryLimits,
 }
 
 impl Stats {
@@ -43,10 +55,17 @@ impl Stats {
             scan_duration: 0,
             boot_time: None,
             storage: Default::default(),
-            history: History::new(),
+            history: Arc::new(History::new()),
             processes: Default::default(),
             connections: Default::default(),
+            query_cache: Arc::new(Cache::new()),
+            query_limits: QueryLimits::unlimited(),
         };
     }
+    /// Returns a copy of the stats, history and processes are shared
+    ///
+    /// The lock is held only for the time of copying, which is cheap.
+    pub fn snapshot(lock: &RwLock<Stats>) -> Stats {
+        lock.read().expect("stats not poisoned").clone()
+    }
 }
-
@@ -1,25 +1,59 @@
+use std::mem;
 use std::sync::{RwLock, Mutex, Condvar};
+use std::collections::VecDeque;
 use std::fs::{File, rename, remove_file, read_dir};
 use std::os::unix::fs::symlink;
-use std::io::Write;
+use std::io::{Write, BufReader};
 use std::str::FromStr;
 use std::path::Path;
 
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use probor;
 use regex::Regex;
+use serde_cbor;
+use history::{History, VersionInfo, decode_history};
 
 use super::stats::Stats;
 use super::scan::time_ms;
+use super::scan::processes::MinimalProcess;
+use super::scan::connections::Connections;
 use super::deps::{Dependencies, LockedDeps};
+use super::wal;
 
 
+/// Hourly and process snapshots older than this are removed
+const KEEP_HOURS: u64 = 36;
+/// Maximum number of scans waiting to be written to the log, when disk
+/// is too slow older scans are dropped and a snapshot is requested instead
+const MAX_QUEUED_SCANS: usize = 300;
+
 pub struct MetricBuffer {
     pub timestamp: u64,
     pub snapshot: Option<String>,
     pub data: Box<[u8]>,
 }
 
+/// CBOR-encoded `ProcessSnapshot`, compressed by the storage thread
+pub struct ProcessBuffer {
+    pub timestamp: u64,
+    pub data: Box<[u8]>,
+}
+
+/// Process table and sockets at the time of some scan
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProcessSnapshot {
+    pub timestamp: u64,
+    pub boot_time: Option<u64>,
+    pub processes: Vec<MinimalProcess>,
+    pub connections: Option<Connections>,
+}
+
 pub enum Task {
     Metrics(MetricBuffer),
+    Scan(wal::Scan),
+    Processes(ProcessBuffer),
     Peers(Box<[u8]>),
 }
 
@@ -29,11 +63,19 @@ pub struct StorageStats {
     pub timestamp: u64,
     pub duration: u32,
     pub size: usize,
+    /// Bytes written to the write-ahead log since last snapshot
+    pub log_size: usize,
 }
 
 struct Items {
     metrics: Option<MetricBuffer>,
+    /// Scans not written to the log yet, ones older than `metrics` are
+    /// discarded only when the snapshot is stored
+    scans: VecDeque<wal::Scan>,
+    processes: Option<ProcessBuffer>,
     peers: Option<Box<[u8]>>,
+    /// Scanner should store a snapshot of metrics on the next scan
+    snapshot_requested: bool,
 }
 
 pub struct Storage {
@@ -46,7 +88,10 @@ impl Storage {
         Storage {
             value: Mutex::new(Items {
                 metrics: None,
+                scans: VecDeque::new(),
+                processes: None,
                 peers: None,
+                snapshot_requested: false,
             }),
             cond: Condvar::new(),
         }
@@ -56,30 +101,106 @@ impl Storage {
         lock.metrics = Some(value);
         self.cond.notify_all();
     }
+    /// Discards queued scans that are already stored in the snapshot
+    fn discard_scans(&self, timestamp: u64) {
+        let mut lock = self.value.lock().unwrap();
+        lock.scans.retain(|s| s.timestamp.0 > timestamp);
+    }
+    pub fn append_scan(&self, value: wal::Scan) {
+        let mut lock = self.value.lock().unwrap();
+        if lock.scans.len() >= MAX_QUEUED_SCANS {
+            // The snapshot will contain the dropped scans
+            if !lock.snapshot_requested {
+                warn!("Storage is too slow, dropping scans from the log \
+                    and requesting a snapshot");
+            }
+            lock.scans.pop_front();
+            lock.snapshot_requested = true;
+        }
+        lock.scans.push_back(value);
+        self.cond.notify_all();
+    }
+    pub fn store_processes(&self, value: ProcessBuffer) {
+        let mut lock = self.value.lock().unwrap();
+        lock.processes = Some(value);
+        self.cond.notify_all();
+    }
     pub fn store_peers(&self, value: Box<[u8]>) {
         let mut lock = self.value.lock().unwrap();
         lock.peers = Some(value);
         self.cond.notify_all();
     }
+    /// Asks scanner to compact the log into a snapshot on the next scan
+    pub fn request_snapshot(&self) {
+        let mut lock = self.value.lock().unwrap();
+        lock.snapshot_requested = true;
+    }
+    /// Returns true (once) if the snapshot was requested
+    pub fn take_snapshot_request(&self) -> bool {
+        let mut lock = self.value.lock().unwrap();
+        mem::replace(&mut lock.snapshot_requested, false)
+    }
     pub fn get(&self) -> Task {
         let mut lock = self.value.lock().expect("storage lock");
         loop {
             if let Some(val) = lock.peers.take() {
                 return Task::Peers(val);
             }
+            // metrics go first, since all the scans queued are newer
             if let Some(val) = lock.metrics.take() {
                 return Task::Metrics(val);
             }
+            if let Some(val) = lock.scans.pop_front() {
+                return Task::Scan(val);
+            }
+            if let Some(val) = lock.processes.take() {
+                return Task::Processes(val);
+            }
             lock = self.cond.wait(lock).expect("storage lock");
         }
     }
 }
 
-fn store_metrics(path: &Path, buf: MetricBuffer, stats: &RwLock<Stats>) {
+/// Reads history snapshot (`current.cbor` or one of the `hourly-*.cbor`)
+///
+/// Errors are logged
+pub fn read_snapshot(path: &Path) -> Result<History, ()> {
+    let cborcfg = probor::Config {
+        max_len_array: 100000,
+        max_len_bytes: 0x500000,
+        max_len_text: 0x500000,
+        max_size_map: 100000,
+        max_nesting: 16,
+        .. probor::Config::default()
+    };
+    File::open(path)
+        .map_err(|e| error!("Error reading {:?}: {}. Ignoring...", path, e))
+        .map(BufReader::new)
+        .map(|f| probor::Decoder::new(cborcfg, f))
+        .and_then(|mut dec| {
+            let v: VersionInfo = try!(probor::decode(&mut dec)
+                .map_err(|_| error!("Can't decode version info of {:?}. \
+                    Ignoring...", path)));
+            if !v.is_supported() {
+                error!("Unsupported version of history data {:?} in {:?}. \
+                    Ignoring...", v, path);
+                return Err(());
+            }
+            if v != VersionInfo::current() {
+                info!("Migrating history data in {:?} from {:?}", path, v);
+            }
+            decode_history(&v, &mut dec)
+                .map_err(|e| error!(
+                    "Error parsing {:?}: {}. Ignoring...", path, e))
+        })
+}
+
+fn store_metrics(path: &Path, buf: MetricBuffer, cell: &Storage,
+    stats: &RwLock<Stats>, wal: &mut wal::Writer)
+{
     let tmp = path.join("current.tmp");
     let tmplink = path.join("current.tmp.link");
     let current = path.join("current.cbor");
-    let file_re = Regex::new(r#"^hourly-(\d+).cbor$"#).unwrap();
     let start_time = time_ms();
     File::create(&tmp)
     .and_then(|mut f| f.write_all(&buf.data))
@@ -103,11 +224,75 @@ fn store_metrics(path: &Path, buf: MetricBuffer, stats: &RwLock<Stats>) {
             stats.storage.time = time;
             stats.storage.timestamp = buf.timestamp;
             stats.storage.size = buf.data.len();
+            stats.storage.log_size = 0;
         }
+        // snapshot contains all the scans that were not written yet,
+        // if it failed they are appended to the log as usual
+        cell.discard_scans(buf.timestamp);
+        wal.compacted();
     })
     .map_err(|e| error!("Error storing snapshot: {}", e))
     .ok();
-    let cut_off = start_time / 3_600_000 - 36;  // keep 36 hours
+    let cut_off = start_time / 3_600_000 - KEEP_HOURS;
+    for hour in hourly_snapshots(path) {
+        if hour < cut_off {
+            let fpath = path.join(format!("hourly-{}.cbor", hour));
+            remove_file(&fpath)
+            .map_err(|e| error!("Can't remove old file {:?}: {}", fpath, e))
+            .ok();
+        }
+    }
+}
+
+fn store_processes(path: &Path, buf: ProcessBuffer) {
+    let tmp = path.join("processes.tmp");
+    let target = path.join(format!("processes-{}.cbor.gz", buf.timestamp));
+    File::create(&tmp)
+    .and_then(|f| {
+        let mut enc = GzEncoder::new(f, Compression::default());
+        try!(enc.write_all(&buf.data));
+        enc.finish()
+    })
+    .and_then(|_| rename(&tmp, &target))
+    .map_err(|e| error!("Error storing processes: {}", e))
+    .ok();
+    let cut_off = buf.timestamp.saturating_sub(KEEP_HOURS * 3_600_000);
+    for tstamp in process_snapshots(path) {
+        if tstamp < cut_off {
+            let fpath = path.join(format!("processes-{}.cbor.gz", tstamp));
+            remove_file(&fpath)
+            .map_err(|e| error!("Can't remove old file {:?}: {}", fpath, e))
+            .ok();
+        }
+    }
+}
+
+/// Reads a snapshot of processes stored at `timestamp`
+///
+/// Errors are logged
+pub fn read_processes(path: &Path, timestamp: u64)
+    -> Result<ProcessSnapshot, ()>
+{
+    let path = path.join(format!("processes-{}.cbor.gz", timestamp));
+    File::open(&path)
+    .map_err(|e| error!("Error reading {:?}: {}", path, e))
+    .and_then(|f| serde_cbor::from_reader(GzDecoder::new(BufReader::new(f)))
+        .map_err(|e| error!("Error parsing {:?}: {}", path, e)))
+}
+
+/// Returns sorted list of hours for which `hourly-*.cbor` snapshots exist
+pub fn hourly_snapshots(path: &Path) -> Vec<u64> {
+    list_numbered(path, r#"^hourly-(\d+).cbor$"#)
+}
+
+/// Returns sorted list of timestamps of `processes-*.cbor.gz` snapshots
+pub fn process_snapshots(path: &Path) -> Vec<u64> {
+    list_numbered(path, r#"^processes-(\d+).cbor.gz$"#)
+}
+
+fn list_numbered(path: &Path, regex: &str) -> Vec<u64> {
+    let file_re = Regex::new(regex).unwrap();
+    let mut result = Vec::new();
     read_dir(&path).map(|iter| for item in iter {
         item.map(|entry| {
             entry.path().file_name()
@@ -115,16 +300,24 @@ fn store_metrics(path: &Path, buf: MetricBuffer, stats: &RwLock<Stats>) {
             .and_then(|fname| file_re.captures(fname))
             .and_then(|c| c.get(1))
             .and_then(|x| FromStr::from_str(x.as_str()).ok())
-            .map(|x: u64| {
-                if x < cut_off {
-                    remove_file(entry.path())
-                    .map_err(|e| error!("Can't remove old file {:?}: {}",
-                                        entry.path(), e))
-                    .ok();
-                }
-            });
+            .map(|x: u64| result.push(x));
         }).ok();
     }).map_err(|e| error!("Can't read dir: {}", e)).ok();
+    result.sort();
+    return result;
+}
+
+fn append_scan(scan: wal::Scan, stats: &RwLock<Stats>,
+    wal: &mut wal::Writer)
+{
+    wal.append(&scan)
+    .map(|bytes| {
+        if let Ok(mut stats) = stats.write() {
+            stats.storage.log_size += bytes;
+        }
+    })
+    .map_err(|e| error!("Error writing scan to the log: {}", e))
+    .ok();
 }
 
 fn store_peers(path: &Path, buf: Box<[u8]>) {
@@ -139,9 +332,14 @@ fn store_peers(path: &Path, buf: Box<[u8]>) {
 pub fn storage_loop(deps: Dependencies, path: &Path) {
     let cell: &Storage = &*deps.copy();
     let stats: &RwLock<Stats> = &*deps.copy();
+    let mut wal = wal::Writer::new(path);
     loop {
         match cell.get() {
-            Task::Metrics(buf) => store_metrics(path, buf, stats),
+            Task::Metrics(buf) => {
+                store_metrics(path, buf, cell, stats, &mut wal)
+            }
+            Task::Scan(buf) => append_scan(buf, stats, &mut wal),
+            Task::Processes(buf) => store_processes(path, buf),
             Task::Peers(buf) => store_peers(path, buf),
         }
     }
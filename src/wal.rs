@@ -0,0 +1,323 @@
+//! Write-ahead log of scanned values
+//!
+//! Every scan appends a record to the current segment
+//! (`wal-<timestamp>.log` in the storage dir). When history is compacted
+//! into `current.cbor` all segments are removed. On startup the records
+//! newer than the snapshot are replayed on top of it.
+//!
+//! Segment starts with `MAGIC`, then each record is a 4-byte big-endian
+//! length followed by CBOR-encoded `Record`. Keys are numbered in the
+//! order they first appear in the segment, and a record only contains
+//! the keys that are new to the segment and the values that changed
+//! since the previous record. So records can only be decoded in order,
+//! starting from the beginning of the segment.
+//!
+//! Truncated record at the end of a segment (i.e. after a crash) is
+//! ignored. Each record is synced to disk before the next one is written.
+use std::collections::HashMap;
+use std::mem;
+use std::fs::{File, OpenOptions, remove_file, read_dir};
+use std::io::{self, Read, Write, BufReader, Cursor};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use probor::{self, Encoder, Encodable};
+use regex::Regex;
+
+use cantal::Value;
+use history::{History, Key};
+
+
+const MAGIC: &[u8] = b"CNTLWAL2";
+
+/// Values of a single scan
+pub struct Scan {
+    pub timestamp: (u64, u32),
+    pub values: HashMap<Key, Value>,
+}
+
+/// Changes of values since the previous record in the segment
+struct Record {
+    timestamp: (u64, u32),
+    /// Keys that are new to the segment, they are numbered sequentially
+    /// after the keys of the previous records
+    keys: Vec<Key>,
+    /// Values that are new or changed, by the number of the key
+    values: HashMap<u32, Value>,
+    /// Keys that were in the previous record but are absent in this one
+    removed: Vec<u32>,
+}
+
+probor_struct_encoder_decoder!(Record {
+    timestamp => (#0),
+    keys => (#1),
+    values => (#2),
+    removed => (#3),
+});
+
+/// Appends records to the segment files
+///
+/// Used only by the storage thread
+pub struct Writer {
+    dir: PathBuf,
+    file: Option<File>,
+    /// Numbers of the keys written to the current segment
+    keys: HashMap<Key, u32>,
+    /// Values of the last record written to the current segment
+    values: HashMap<u32, Value>,
+}
+
+impl Scan {
+    /// Pushes values to the history: states to the tip and everything
+    /// else to the fine backlog
+    pub fn apply(&self, history: &mut History) {
+        history.tip.push(self.timestamp, self.values.iter()
+            .filter(|&(_, v)| matches!(v, &Value::State(_))));
+        history.fine.push(self.timestamp, self.values.iter()
+            .filter(|&(_, v)| !matches!(v, &Value::State(_))));
+    }
+}
+
+impl Writer {
+    pub fn new(dir: &Path) -> Writer {
+        Writer {
+            dir: dir.to_path_buf(),
+            file: None,
+            keys: HashMap::new(),
+            values: HashMap::new(),
+        }
+    }
+    /// Appends the scan, new segment is started if needed
+    ///
+    /// Returns number of bytes written
+    pub fn append(&mut self, scan: &Scan) -> io::Result<usize> {
+        if self.file.is_none() {
+            let path = self.dir.join(format!("wal-{}.log", scan.timestamp.0));
+            let mut file = try!(OpenOptions::new()
+                .write(true).create_new(true).open(&path));
+            try!(file.write_all(MAGIC));
+            self.file = Some(file);
+            self.keys.clear();
+            self.values.clear();
+        }
+        let buf = try!(self.encode(scan));
+        // Single write, so that crash leaves at most one partial record
+        let result = {
+            let file = self.file.as_mut().unwrap();
+            file.write_all(&buf).and_then(|()| file.sync_data())
+        };
+        if result.is_err() {
+            // Partial record may be written, so we can't append to
+            // this segment any more
+            self.file = None;
+        }
+        return result.map(|()| buf.len());
+    }
+    /// Encodes the record with the length prefix and remembers values
+    /// written
+    fn encode(&mut self, scan: &Scan) -> io::Result<Vec<u8>> {
+        let mut record = Record {
+            timestamp: scan.timestamp,
+            keys: Vec::new(),
+            values: HashMap::new(),
+            removed: Vec::new(),
+        };
+        let mut values = HashMap::with_capacity(scan.values.len());
+        for (key, value) in &scan.values {
+            let num = match self.keys.get(key) {
+                Some(&num) => num,
+                None => {
+                    let num = self.keys.len() as u32;
+                    self.keys.insert(key.clone(), num);
+                    record.keys.push(key.clone());
+                    num
+                }
+            };
+            if self.values.get(&num) != Some(value) {
+                record.values.insert(num, value.clone());
+            }
+            values.insert(num, value.clone());
+        }
+        let old = mem::replace(&mut self.values, values);
+        for num in old.keys() {
+            if !self.values.contains_key(num) {
+                record.removed.push(*num);
+            }
+        }
+        let mut enc = Encoder::new(vec![0u8; 4]);
+        try!(record.encode(&mut enc).map_err(|e| io::Error::new(
+            io::ErrorKind::InvalidData, format!("can't encode scan: {}", e))));
+        let mut buf = enc.into_writer();
+        let len = (buf.len() - 4) as u32;
+        (&mut buf[..4]).write_u32::<BigEndian>(len).unwrap();
+        Ok(buf)
+    }
+    /// Removes all the segments
+    ///
+    /// Must be called when history containing all the records is stored
+    pub fn compacted(&mut self) {
+        self.file = None;
+        for (_, path) in segments(&self.dir) {
+            remove_file(&path)
+            .map_err(|e| error!("Can't remove log {:?}: {}", path, e))
+            .ok();
+        }
+    }
+}
+
+/// Returns segments sorted by time
+fn segments(dir: &Path) -> Vec<(u64, PathBuf)> {
+    let file_re = Regex::new(r#"^wal-(\d+).log$"#).unwrap();
+    let mut result = Vec::new();
+    read_dir(dir).map(|iter| for item in iter {
+        item.map(|entry| {
+            let path = entry.path();
+            path.file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|fname| file_re.captures(fname))
+            .and_then(|c| c.get(1))
+            .and_then(|x| FromStr::from_str(x.as_str()).ok())
+            .map(|x: u64| result.push((x, path.clone())));
+        }).ok();
+    }).map_err(|e| error!("Can't read dir: {}", e)).ok();
+    result.sort();
+    return result;
+}
+
+fn read_segment<F: FnMut(Scan)>(path: &Path, mut f: F) -> io::Result<()> {
+    let cborcfg = probor::Config {
+        max_len_array: 100000,
+        max_len_bytes: 0x500000,
+        max_len_text: 0x500000,
+        max_size_map: 100000,
+        max_nesting: 16,
+        .. probor::Config::default()
+    };
+    let mut file = BufReader::new(try!(File::open(path)));
+    let mut magic = [0u8; 8];
+    try!(file.read_exact(&mut magic));
+    if &magic[..] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "wrong file signature"));
+    }
+    let mut keys = Vec::<Key>::new();
+    let mut values = HashMap::<u32, Value>::new();
+    loop {
+        let len = match file.read_u32::<BigEndian>() {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let mut buf = vec![0u8; len as usize];
+        match file.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                warn!("Truncated record at the end of {:?}", path);
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+        let mut dec = probor::Decoder::new(cborcfg, Cursor::new(&buf[..]));
+        let record: Record = match probor::decode(&mut dec) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Bad record in {:?}: {}. Skipping the rest", path, e);
+                break;
+            }
+        };
+        keys.extend(record.keys);
+        if record.values.keys().any(|&num| num as usize >= keys.len()) {
+            warn!("Bad key number in {:?}. Skipping the rest", path);
+            break;
+        }
+        for num in &record.removed {
+            values.remove(num);
+        }
+        values.extend(record.values);
+        f(Scan {
+            timestamp: record.timestamp,
+            values: values.iter()
+                .map(|(&num, v)| (keys[num as usize].clone(), v.clone()))
+                .collect(),
+        });
+    }
+    Ok(())
+}
+
+/// Applies records that are newer than the history to it
+///
+/// Errors are logged
+pub fn replay(dir: &Path, history: &mut History) {
+    let mut latest = history.fine.timestamps.front().map(|&(ts, _)| ts)
+        .unwrap_or(0)
+        .max(history.tip.latest_timestamp.0);
+    let mut num = 0;
+    for (_, path) in segments(dir) {
+        read_segment(&path, |scan| {
+            if scan.timestamp.0 > latest {
+                scan.apply(history);
+                latest = scan.timestamp.0;
+                num += 1;
+            }
+        }).map_err(|e| error!("Error reading {:?}: {}. Ignoring...", path, e))
+        .ok();
+    }
+    if num > 0 {
+        info!("Replayed {} scans from the write-ahead log", num);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::process;
+
+    use cantal::Value::{Counter, Float};
+    use history::Key;
+    use super::{Writer, Scan, segments, read_segment};
+
+    #[test]
+    fn test_deltas() {
+        let dir = temp_dir().join(format!("cantal-wal-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let mut scans = Vec::new();
+        scans.push(Scan {
+            timestamp: (1000, 10),
+            values: vec![
+                (Key::metric("a"), Counter(1)),
+                (Key::metric("b"), Float(0.5)),
+            ].into_iter().collect(),
+        });
+        scans.push(Scan {
+            timestamp: (2000, 10),
+            values: vec![
+                (Key::metric("a"), Counter(2)),
+                (Key::metric("c"), Float(0.5)),
+            ].into_iter().collect(),
+        });
+        scans.push(Scan {
+            timestamp: (3000, 10),
+            values: vec![
+                (Key::metric("a"), Counter(2)),
+                (Key::metric("c"), Float(0.5)),
+            ].into_iter().collect(),
+        });
+        let mut writer = Writer::new(&dir);
+        let sizes = scans.iter().map(|s| writer.append(s).unwrap())
+            .collect::<Vec<_>>();
+        // unchanged values and known keys are not written
+        assert!(sizes[2] < sizes[1]);
+        let mut read = Vec::new();
+        for (_, path) in segments(&dir) {
+            read_segment(&path, |scan| read.push(scan)).unwrap();
+        }
+        remove_dir_all(&dir).unwrap();
+        assert_eq!(read.len(), 3);
+        for (a, b) in scans.iter().zip(&read) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.values, b.values);
+        }
+    }
+}
@@ -263,6 +263,13 @@ Incompatible.probor_enum_protocol = [new Enum({
     102: "CantSumTimestamps",
     103: "CantSumStates",
     104: "CantDerive",
+    105: "BadPercentile",
+    106: "CantChartValues",
+    107: "Unexpected",
+    108: "BadStep",
+    109: "TooManySeries",
+    110: "TooManyPoints",
+    111: "Timeout",
 })]
 
 let dataset = new Enum({